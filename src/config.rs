@@ -0,0 +1,427 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::num::NonZeroU8;
+
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::db::ConnectionOpts;
+use crate::deepq::model::ReportOrigin;
+use crate::error::{Error, Result};
+use crate::fishnet::model::{AnalysisType, FishnetVersion};
+use crate::http::CorsOpts;
+
+const DEFAULT_CONFIG_PATH: &str = "deepq.toml";
+
+/// Settings read from the TOML file named by `LILA_DEEPQ_CONFIG` (or `deepq.toml`).
+/// Every value has a default or can be supplied by the matching command line
+/// argument / environment variable, so the file itself is optional.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub database: DatabaseConfig,
+    pub webserver: WebserverConfig,
+    pub irwin: IrwinConfig,
+    pub fishnet: FishnetConfig,
+    pub precedence: PrecedenceConfig,
+    pub origin_analysis: OriginAnalysisConfig,
+    pub lichess: LichessConfig,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub mongo_uri: Option<String>,
+    pub mongo_database: Option<String>,
+    /// See `ConnectionOpts::write_concern`; defaults to "majority".
+    pub write_concern: Option<String>,
+}
+
+impl DatabaseConfig {
+    pub fn connection_opts(&self) -> Result<ConnectionOpts> {
+        Ok(ConnectionOpts {
+            mongo_uri: self
+                .mongo_uri
+                .clone()
+                .ok_or(Error::MissingConfig("database.mongo_uri"))?,
+            mongo_database: self
+                .mongo_database
+                .clone()
+                .ok_or(Error::MissingConfig("database.mongo_database"))?,
+            write_concern: self
+                .write_concern
+                .clone()
+                .unwrap_or_else(|| "majority".to_string()),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebserverConfig {
+    pub host: String,
+    pub port: u16,
+    pub channel_size: usize,
+    pub job_max_age_days: i64,
+    /// How often queue depth is sampled for `/fishnet/status/history`.
+    pub queue_sample_secs: u64,
+    pub queue_sample_retention_days: i64,
+    /// Upper bound on requests touching the database at once.
+    pub max_db_concurrency: usize,
+    /// Of those, how many only analysis submissions may use, so completions
+    /// still get through when acquires are being turned away.
+    pub reserved_for_completions: usize,
+    /// Largest request body accepted, before and after gzip inflation.
+    pub max_body_bytes: u64,
+    /// How long a replayed Idempotency-Key gets the original response.
+    pub idempotency_ttl_secs: u64,
+    /// How long workers may keep posting analysis after a shutdown signal.
+    pub shutdown_grace_secs: u64,
+    /// Apply pending `db::migrations` before serving.
+    pub run_migrations: bool,
+    /// Serve HTTPS directly when both are set.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub cors: CorsOpts,
+}
+
+impl Default for WebserverConfig {
+    fn default() -> WebserverConfig {
+        WebserverConfig {
+            host: "127.0.0.1".to_string(),
+            port: 8000,
+            channel_size: 16,
+            job_max_age_days: 7,
+            queue_sample_secs: 60,
+            queue_sample_retention_days: 30,
+            max_db_concurrency: 64,
+            reserved_for_completions: 8,
+            max_body_bytes: 32 * 1024 * 1024,
+            idempotency_ttl_secs: 600,
+            shutdown_grace_secs: 30,
+            run_migrations: false,
+            tls_cert: None,
+            tls_key: None,
+            cors: CorsOpts::default(),
+        }
+    }
+}
+
+impl WebserverConfig {
+    /// The cert and key paths to terminate TLS with, or None for plain HTTP.
+    pub fn tls_paths(&self) -> Result<Option<(&str, &str)>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            (Some(_), None) => Err(Error::MissingConfig("webserver.tls_key")),
+            (None, Some(_)) => Err(Error::MissingConfig("webserver.tls_cert")),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IrwinConfig {
+    pub stream_url: String,
+    pub lichess_api_key: Option<String>,
+    /// Where completed reports are POSTed; nothing is submitted while this is unset.
+    pub submit_url: Option<String>,
+    pub submit_token: Option<String>,
+    /// Reports with more games than this are submitted in several parts.
+    pub submit_chunk_games: Option<usize>,
+    /// A submission that can't connect, or isn't answered, in time counts as failed.
+    pub submit_connect_timeout_secs: u64,
+    pub submit_timeout_secs: u64,
+    /// Submissions stop for `breaker_cooldown_secs` after this many fail in a row.
+    pub breaker_failures: u32,
+    pub breaker_cooldown_secs: i64,
+    /// Games analysed at the same tier this recently aren't analysed again; 0 disables.
+    pub reuse_analysis_hours: i64,
+    /// Reports at least this fraction complete are submitted without their unfinished
+    /// games once they are `partial_submit_after_mins` old. 1.0 never submits partially.
+    pub min_completion: f64,
+    pub partial_submit_after_mins: i64,
+}
+
+impl Default for IrwinConfig {
+    fn default() -> IrwinConfig {
+        IrwinConfig {
+            stream_url: "https://lichess.org/api/stream/irwin".to_string(),
+            lichess_api_key: None,
+            submit_url: None,
+            submit_token: None,
+            submit_chunk_games: Some(500),
+            submit_connect_timeout_secs: 10,
+            submit_timeout_secs: 60,
+            breaker_failures: 5,
+            breaker_cooldown_secs: 300,
+            reuse_analysis_hours: 72,
+            min_completion: 1.0,
+            partial_submit_after_mins: 120,
+        }
+    }
+}
+
+impl IrwinConfig {
+    pub fn partial_submit_after(&self) -> chrono::Duration {
+        chrono::Duration::minutes(self.partial_submit_after_mins)
+    }
+
+    pub fn reuse_analysis_within(&self) -> Option<chrono::Duration> {
+        match self.reuse_analysis_hours {
+            hours if hours > 0 => Some(chrono::Duration::hours(hours)),
+            _ => None,
+        }
+    }
+}
+
+/// What we ask of a worker for a given analysis type.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WorkConfig {
+    pub nnue_nodes: u64,
+    pub classical_nodes: u64,
+    #[serde(default)]
+    pub multipv: Option<NonZeroU8>,
+    #[serde(default)]
+    pub depth: Option<u8>,
+    #[serde(default)]
+    pub skip_positions: Vec<u8>,
+    /// PV lines are cut down to this many moves before being stored; the scores are
+    /// kept either way. None keeps full lines.
+    #[serde(default)]
+    pub max_stored_pv_moves: Option<usize>,
+}
+
+#[serde_as]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct FishnetConfig {
+    /// Workers reporting an older version are turned away with 426 Upgrade Required.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub min_version: Option<FishnetVersion>,
+    /// Jobs reported as failed this many times are quarantined for an operator to look at.
+    pub max_job_failures: i32,
+    /// Keep every submitted report as-is in `deepq_raw_analysis` for auditing.
+    pub store_raw_reports: bool,
+    /// Acquires overlapping one from the same key get no job, for up to this long while
+    /// the first is still running. 0 lets workers acquire in parallel.
+    pub acquire_lock_ms: u64,
+    /// Upper bound on the `wait_ms` an acquire may ask to be held for when there's no
+    /// work. 0 turns long polling off.
+    pub max_acquire_wait_ms: u64,
+    /// A job whose worker hasn't acquired or heartbeated it for this long is handed to
+    /// the next worker that asks.
+    pub lease_secs: i64,
+    /// Completed jobs with a callback URL are POSTed to it up to this many times,
+    /// giving up on each attempt after `callback_timeout_secs`.
+    pub callback_attempts: u32,
+    pub callback_timeout_secs: u64,
+    pub user_analysis: WorkConfig,
+    pub system_analysis: WorkConfig,
+    pub deep: WorkConfig,
+}
+
+impl FishnetConfig {
+    pub fn lease(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.lease_secs)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.callback_attempts == 0 {
+            return Err(Error::InvalidConfig {
+                field: "fishnet.callback_attempts",
+                value: self.callback_attempts.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn work(&self, analysis_type: &AnalysisType) -> &WorkConfig {
+        match analysis_type {
+            AnalysisType::UserAnalysis => &self.user_analysis,
+            AnalysisType::SystemAnalysis => &self.system_analysis,
+            AnalysisType::Deep => &self.deep,
+        }
+    }
+}
+
+impl Default for FishnetConfig {
+    fn default() -> FishnetConfig {
+        // TODO: what is the default right now for lila's fishnet queue?
+        FishnetConfig {
+            min_version: None,
+            max_job_failures: 3,
+            store_raw_reports: false,
+            acquire_lock_ms: 5_000,
+            max_acquire_wait_ms: 30_000,
+            lease_secs: 600,
+            callback_attempts: 3,
+            callback_timeout_secs: 10,
+            user_analysis: WorkConfig {
+                nnue_nodes: 2_250_000_u64,
+                classical_nodes: 4_050_000_u64,
+                multipv: None,
+                depth: None,
+                skip_positions: vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+                max_stored_pv_moves: None,
+            },
+            system_analysis: WorkConfig {
+                nnue_nodes: 2_250_000_u64,
+                classical_nodes: 4_050_000_u64,
+                multipv: None,
+                depth: None,
+                skip_positions: vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+                max_stored_pv_moves: None,
+            },
+            deep: WorkConfig {
+                nnue_nodes: 2_500_000_u64,
+                classical_nodes: 4_500_000_u64,
+                multipv: NonZeroU8::new(5u8),
+                depth: None,
+                skip_positions: Vec::new(),
+                max_stored_pv_moves: None,
+            },
+        }
+    }
+}
+
+/// Job precedence for each report origin; higher values are assigned first.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PrecedenceConfig {
+    pub moderator: i32,
+    pub leaderboard: i32,
+    pub tournament: i32,
+    pub random: i32,
+    /// Games queued directly through /fishnet/enqueue that don't set their own.
+    pub enqueued: i32,
+    /// How much precedence a job loses each time it fails or times out on a worker.
+    pub requeue_decay_percent: u8,
+    /// Requeue decay never takes a job below this.
+    pub requeue_floor: i32,
+    /// Added to a report's precedence for each earlier report on the same user...
+    pub repeat_user_bonus: i32,
+    /// ...up to this much in total. Both default to 0, which skips the lookup.
+    pub repeat_user_bonus_max: i32,
+}
+
+impl Default for PrecedenceConfig {
+    fn default() -> PrecedenceConfig {
+        PrecedenceConfig {
+            moderator: 1_000_000i32,
+            leaderboard: 1000i32,
+            tournament: 100i32,
+            random: 10i32,
+            enqueued: 10i32,
+            requeue_decay_percent: 10,
+            requeue_floor: 0,
+            repeat_user_bonus: 0,
+            repeat_user_bonus_max: 0,
+        }
+    }
+}
+
+/// The kind of analysis irwin reports get for each report origin.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OriginAnalysisConfig {
+    pub moderator: AnalysisType,
+    pub leaderboard: AnalysisType,
+    pub tournament: AnalysisType,
+    pub random: AnalysisType,
+}
+
+impl Default for OriginAnalysisConfig {
+    fn default() -> OriginAnalysisConfig {
+        OriginAnalysisConfig {
+            moderator: AnalysisType::Deep,
+            leaderboard: AnalysisType::Deep,
+            tournament: AnalysisType::Deep,
+            random: AnalysisType::SystemAnalysis,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LichessConfig {
+    pub api_url: String,
+    pub token: Option<String>,
+    /// Jobs from reports with these origins fetch their game from lichess when it
+    /// wasn't stored with the job, instead of the job being dropped.
+    pub export_missing: Vec<ReportOrigin>,
+    /// Exports happen while a worker waits for a job, so they give up quickly.
+    pub export_connect_timeout_secs: u64,
+    pub export_timeout_secs: u64,
+}
+
+impl Default for LichessConfig {
+    fn default() -> LichessConfig {
+        LichessConfig {
+            api_url: "https://lichess.org".to_string(),
+            token: None,
+            export_missing: Vec::new(),
+            export_connect_timeout_secs: 5,
+            export_timeout_secs: 15,
+        }
+    }
+}
+
+impl Config {
+    /// Checks what serde can't. Run it once command line arguments are applied too.
+    pub fn validate(&self) -> Result<()> {
+        self.webserver.cors.validate()?;
+        self.fishnet.validate()
+    }
+}
+
+/// Loads the config file, falling back to defaults when no file exists at the
+/// default path. A path given explicitly via `LILA_DEEPQ_CONFIG` must exist.
+pub fn load() -> Result<Config> {
+    let (path, explicit) = match env::var("LILA_DEEPQ_CONFIG") {
+        Ok(path) => (path, true),
+        Err(_) => (DEFAULT_CONFIG_PATH.to_string(), false),
+    };
+    match fs::read_to_string(&path) {
+        Ok(contents) => Ok(toml::from_str(&contents)?),
+        Err(err) if err.kind() == ErrorKind::NotFound && !explicit => Ok(Config::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn callbacks_need_at_least_one_attempt() {
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+        config.fishnet.callback_attempts = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(Error::InvalidConfig {
+                field: "fishnet.callback_attempts",
+                ..
+            })
+        ));
+    }
+}
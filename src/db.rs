@@ -15,24 +15,163 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
-use mongodb::{Client, Database};
+pub mod migrations;
+
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use mongodb::{
+    bson::Bson,
+    options::{Acknowledgment, ClientOptions, ReadPreference, SelectionCriteria, WriteConcern},
+    Client, Database,
+};
 
 use crate::error::Result;
 
+/// Stores a value as its lowercased `Display` form, which is how our enums (and keys)
+/// end up in queries. Use it from each type's `From<T> for Bson`.
+// NOTE: strum's `Display` derive writes the same variant names its `ToString` derive
+//       did, so the stored strings are unchanged.
+pub trait ToBsonLower {
+    fn to_bson_lower(&self) -> Bson;
+}
+
+impl<T: Display> ToBsonLower for T {
+    fn to_bson_lower(&self) -> Bson {
+        Bson::String(self.to_string().to_lowercase())
+    }
+}
+
 #[derive(Clone)]
 pub struct ConnectionOpts {
     pub mongo_uri: String,
     pub mongo_database: String,
+    /// Acknowledgement every write waits for: "majority", a node count such as "1", or
+    /// a replica set tag.
+    ///
+    /// "majority" means a write we've acknowledged to a worker or to lila survives a
+    /// primary failover; with fewer nodes it can be rolled back, losing analysis or
+    /// reports we already reported as saved. The price is a round trip to the
+    /// secondaries on every write, and writes stall (rather than fail) while a
+    /// majority of the set is unreachable.
+    pub write_concern: String,
+}
+
+fn acknowledgment(w: &str) -> Acknowledgment {
+    match w.parse::<i32>() {
+        Ok(nodes) => Acknowledgment::Nodes(nodes),
+        Err(_) => Acknowledgment::from(w.to_string()),
+    }
+}
+
+/// Where api functions get the current time from, so time dependent behaviour (stale
+/// job cutoffs, expiry) can be driven by hand.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until it is set or advanced.
+pub struct ManualClock {
+    now: Mutex<DateTime<Utc>>,
+}
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> ManualClock {
+        ManualClock {
+            now: Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().expect("clock lock poisoned") = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().expect("clock lock poisoned");
+        *now = *now + by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().expect("clock lock poisoned")
+    }
 }
 
 #[derive(Clone)]
 pub struct DbConn {
     pub client: Client,
     pub database: Database,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl DbConn {
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> DbConn {
+        DbConn { clock, ..self }
+    }
 }
 
 pub async fn connection(opts: &ConnectionOpts) -> Result<DbConn> {
-    let client = Client::with_uri_str(&opts.mongo_uri).await?;
+    let mut options = ClientOptions::parse(&opts.mongo_uri).await?;
+    // NOTE: settings given in the uri (w=, readPreference=) win over ours.
+    let write_concern = options
+        .write_concern
+        .get_or_insert_with(|| WriteConcern::builder().build());
+    if write_concern.w.is_none() {
+        write_concern.w = Some(acknowledgment(&opts.write_concern));
+    }
+    // NOTE: this is the driver's default, but we rely on it: job assignment and report
+    //       completion read what was just written, which a lagging secondary may not have
+    //       yet. (find_one_and_update always goes to the primary regardless.)
+    if options.selection_criteria.is_none() {
+        options.selection_criteria =
+            Some(SelectionCriteria::ReadPreference(ReadPreference::Primary));
+    }
+    let client = Client::with_options(options)?;
     let database = client.database(&opts.mongo_database);
-    Ok(DbConn { client, database })
+    Ok(DbConn {
+        client,
+        database,
+        clock: Arc::new(SystemClock),
+    })
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::env;
+
+    use super::*;
+
+    /// A connection to an empty database of its own for one test, on the server named
+    /// by `LILA_DEEPQ_TEST_MONGO_URI`. Tests that need mongo are `#[ignore]`d, and run
+    /// with `cargo test -- --ignored`; they fail rather than pass when that isn't set.
+    pub async fn test_db(name: &str) -> DbConn {
+        let mongo_uri = env::var("LILA_DEEPQ_TEST_MONGO_URI")
+            .expect("set LILA_DEEPQ_TEST_MONGO_URI to run tests that need mongo");
+        let db = connection(&ConnectionOpts {
+            mongo_uri,
+            mongo_database: format!("deepq_test_{}", name),
+            write_concern: "1".to_string(),
+        })
+        .await
+        .expect("unable to connect to the test database");
+        db.database
+            .drop(None)
+            .await
+            .expect("unable to clear the test database");
+        db
+    }
 }
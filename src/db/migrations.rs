@@ -0,0 +1,221 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use futures::future::{BoxFuture, FutureExt};
+use log::info;
+use mongodb::{
+    bson::{doc, Bson, DateTime as BsonDateTime},
+    options::UpdateModifications,
+    Collection,
+};
+
+use crate::db::DbConn;
+use crate::error::Result;
+use crate::fishnet::model::{Capability, ADMIN_CAPABILITIES};
+use crate::fishnet::{api as fishnet_api, model as fishnet_model};
+
+type MigrationFn = fn(DbConn) -> BoxFuture<'static, Result<()>>;
+
+/// A one-off data change. Versions only ever grow, and a migration's version never
+/// changes once it has shipped.
+struct Migration {
+    version: i32,
+    name: &'static str,
+    run: MigrationFn,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "job owner key to api user id",
+        run: job_owner_ids,
+    },
+    Migration {
+        version: 2,
+        name: "is_admin to capabilities",
+        run: admin_capabilities,
+    },
+    Migration {
+        version: 3,
+        name: "enqueue_games for converted admins",
+        run: admin_enqueue_games,
+    },
+];
+
+fn coll(db: DbConn) -> Collection {
+    db.database.collection("deepq_migrations")
+}
+
+fn job_owner_ids(db: DbConn) -> BoxFuture<'static, Result<()>> {
+    async move {
+        let migrated = fishnet_api::migrate_job_owners(db).await?;
+        info!("job_owner_ids > rewrote {} job owners", migrated);
+        Ok(())
+    }
+    .boxed()
+}
+
+fn capability_names<'a>(capabilities: impl Iterator<Item = &'a Capability>) -> Vec<Bson> {
+    capabilities
+        .map(|capability| Bson::String(capability.to_string()))
+        .collect()
+}
+
+fn admin_capabilities(db: DbConn) -> BoxFuture<'static, Result<()>> {
+    async move {
+        let all = capability_names(ADMIN_CAPABILITIES.iter());
+        let result = fishnet_model::ApiUser::coll(db)
+            .update_many(
+                doc! {"is_admin": true},
+                UpdateModifications::Document(doc! {
+                    "$addToSet": {"capabilities": {"$each": all}},
+                    "$set": {"is_admin": false},
+                }),
+                None,
+            )
+            .await?;
+        info!(
+            "admin_capabilities > converted {} keys",
+            result.modified_count
+        );
+        Ok(())
+    }
+    .boxed()
+}
+
+/// Version 2 converted admins before `EnqueueGames` existed. Keys it converted hold
+/// every other capability, and `is_admin` is cleared by then, so that's how they're found.
+fn admin_enqueue_games(db: DbConn) -> BoxFuture<'static, Result<()>> {
+    async move {
+        let converted = capability_names(
+            ADMIN_CAPABILITIES
+                .iter()
+                .filter(|&capability| *capability != Capability::EnqueueGames),
+        );
+        let enqueue = Bson::String(Capability::EnqueueGames.to_string());
+        let result = fishnet_model::ApiUser::coll(db)
+            .update_many(
+                doc! {"capabilities": {"$all": converted}},
+                UpdateModifications::Document(doc! {
+                    "$addToSet": {"capabilities": enqueue},
+                }),
+                None,
+            )
+            .await?;
+        info!(
+            "admin_enqueue_games > granted {} keys",
+            result.modified_count
+        );
+        Ok(())
+    }
+    .boxed()
+}
+
+async fn is_applied(db: DbConn, migration: &Migration) -> Result<bool> {
+    let done = coll(db)
+        .count_documents(doc! {"_id": migration.version}, None)
+        .await?;
+    Ok(done > 0)
+}
+
+/// Names of the migrations not yet recorded in `deepq_migrations`, in version order.
+pub async fn pending(db: DbConn) -> Result<Vec<&'static str>> {
+    let mut names = Vec::new();
+    for migration in MIGRATIONS {
+        if !is_applied(db.clone(), migration).await? {
+            names.push(migration.name);
+        }
+    }
+    Ok(names)
+}
+
+/// Applies every migration not yet recorded in `deepq_migrations`, in version order,
+/// recording each as it completes. Returns how many were applied.
+pub async fn run_pending(db: DbConn) -> Result<usize> {
+    let mut applied = 0;
+    for migration in MIGRATIONS {
+        if is_applied(db.clone(), migration).await? {
+            continue;
+        }
+        info!(
+            "run_pending > applying {}: {}",
+            migration.version, migration.name
+        );
+        (migration.run)(db.clone()).await?;
+        coll(db.clone())
+            .insert_one(
+                doc! {
+                    "_id": migration.version,
+                    "name": migration.name,
+                    "date_applied": BsonDateTime(db.now()),
+                },
+                None,
+            )
+            .await?;
+        applied += 1;
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing::test_db;
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn admins_from_before_capabilities_can_enqueue_games() {
+        let db = test_db("admins_from_before_capabilities_can_enqueue_games").await;
+        let create = |name: &str, capabilities: Vec<Capability>| fishnet_api::CreateApiUser {
+            user: None,
+            name: name.to_string(),
+            perms: Vec::new(),
+            capabilities,
+            max_concurrent: None,
+            daily_node_budget: None,
+            parallel_acquire: false,
+        };
+        let admin = create("admin", Vec::new());
+        let admin = fishnet_api::create_api_user(db.clone(), admin).await.unwrap();
+        // NOTE: as version 2 left admins it converted before `EnqueueGames` existed.
+        let converted: Vec<Capability> = ADMIN_CAPABILITIES
+            .iter()
+            .filter(|&capability| *capability != Capability::EnqueueGames)
+            .cloned()
+            .collect();
+        let converted = create("converted", converted);
+        let converted = fishnet_api::create_api_user(db.clone(), converted).await.unwrap();
+        fishnet_model::ApiUser::coll(db.clone())
+            .update_one(
+                doc! {"_id": admin._id.clone()},
+                UpdateModifications::Document(doc! {"$set": {"is_admin": true}}),
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(run_pending(db.clone()).await.unwrap(), MIGRATIONS.len());
+        for key in &[admin.key, converted.key] {
+            let api_user = fishnet_api::get_api_user(db.clone(), key.clone()).await.unwrap();
+            let api_user = api_user.unwrap();
+            assert!(!api_user.is_admin);
+            for capability in ADMIN_CAPABILITIES {
+                assert!(api_user.capabilities.contains(capability), "{}", capability);
+            }
+        }
+    }
+}
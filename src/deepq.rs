@@ -16,4 +16,7 @@
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
 pub mod api;
+pub mod handlers;
+pub mod metrics;
 pub mod model;
+pub mod pgn;
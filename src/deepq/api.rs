@@ -15,46 +15,61 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::convert::TryFrom;
+
 use chrono::prelude::*;
-use futures::future::Future;
+use futures::{future::Future, stream::StreamExt};
 use log::debug;
+use serde::Serialize;
 use mongodb::{
-    bson::{doc, from_document, oid::ObjectId, to_document, DateTime as BsonDateTime},
-    options::{UpdateModifications, UpdateOptions},
+    bson::{
+        doc, from_document, oid::ObjectId, to_document, Bson, DateTime as BsonDateTime, Document,
+    },
+    options::{
+        FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument, UpdateModifications,
+        UpdateOptions,
+    },
 };
-use shakmaty::{fen::Fen, uci::Uci};
+use shakmaty::{fen::Fen, uci::Uci, CastlingMode, Chess, Color, FromSetup, Position};
 
+use crate::config::{OriginAnalysisConfig, PrecedenceConfig};
 use crate::db::DbConn;
 use crate::deepq::model as m;
-use crate::error::Result;
-use crate::fishnet::model::JobId;
+use crate::error::{Error, Result};
+use crate::fishnet::model::{AnalysisType, Job, JobId};
 
 #[derive(Debug, Clone)]
 pub struct CreateReport {
     pub user_id: m::UserId,
     pub origin: m::ReportOrigin,
+    pub origin_detail: Option<String>,
     pub report_type: m::ReportType,
     pub games: Vec<m::GameId>,
 }
 
-impl From<CreateReport> for m::Report {
-    fn from(report: CreateReport) -> m::Report {
+impl CreateReport {
+    /// The report as requested at `now`.
+    pub fn into_report(self, now: DateTime<Utc>) -> m::Report {
         m::Report {
             _id: m::ReportId(ObjectId::new()),
-            user_id: report.user_id,
-            origin: report.origin,
-            report_type: report.report_type,
-            games: report.games,
-            date_requested: BsonDateTime(Utc::now()),
+            user_id: self.user_id,
+            origin: self.origin,
+            origin_detail: self.origin_detail,
+            report_type: self.report_type,
+            games: self.games,
+            date_requested: BsonDateTime(now),
             date_completed: None,
             sent_to_irwin: false,
+            resolution: None,
+            submitted_games: Vec::new(),
+            date_checked: None,
         }
     }
 }
 
 pub async fn insert_one_report(db: DbConn, report: CreateReport) -> Result<m::ReportId> {
     let reports_coll = m::Report::coll(db.clone());
-    let report: m::Report = report.into();
+    let report = report.into_report(db.now());
     reports_coll.insert_one(to_document(&report)?, None).await?;
     Ok(report._id)
 }
@@ -71,6 +86,184 @@ pub async fn atomically_update_sent_to_irwin(db: DbConn, id: m::ReportId) -> Res
         .transpose()?)
 }
 
+/// Hands a report back after a failed submission so a later run can pick it up again.
+pub async fn unmark_sent_to_irwin(db: DbConn, id: m::ReportId) -> Result<()> {
+    m::Report::coll(db)
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {"$set": { "sent_to_irwin": false }}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Remembers games irwin has received, so resubmitting the report doesn't send them again.
+pub async fn record_submitted_games(
+    db: DbConn,
+    id: m::ReportId,
+    game_ids: &[m::GameId],
+) -> Result<()> {
+    let game_ids: Vec<Bson> = game_ids.iter().cloned().map(Into::into).collect();
+    m::Report::coll(db)
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {
+                "$addToSet": {"submitted_games": {"$each": game_ids}},
+            }),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn mark_report_complete(db: DbConn, id: m::ReportId) -> Result<()> {
+    m::Report::coll(db.clone())
+        .update_one(
+            doc! {"_id": id.0, "date_completed": null},
+            UpdateModifications::Document(doc! {"$set": { "date_completed": db.now() }}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Records a manual resolution on a report that hasn't been sent to irwin or resolved
+/// already. A force-completed report is also claimed for submission, as
+/// `atomically_update_sent_to_irwin` would. Returns None if there was nothing to resolve.
+pub async fn resolve_report(
+    db: DbConn,
+    id: m::ReportId,
+    action: m::ReportAction,
+    by: String,
+) -> Result<Option<m::Report>> {
+    let sent_to_irwin = action == m::ReportAction::ForceCompleted;
+    let resolution = m::ReportResolution {
+        action,
+        by,
+        date: BsonDateTime(db.now()),
+    };
+    Ok(m::Report::coll(db)
+        .find_one_and_update(
+            doc! {"_id": id.0, "sent_to_irwin": false, "resolution": null},
+            UpdateModifications::Document(doc! {"$set": {
+                "sent_to_irwin": sent_to_irwin,
+                "resolution": to_document(&resolution)?,
+            }}),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
+/// Undoes `resolve_report` after a force-complete failed to submit, so it can be retried.
+pub async fn unresolve_report(db: DbConn, id: m::ReportId) -> Result<()> {
+    m::Report::coll(db)
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {"$set": {
+                "sent_to_irwin": false,
+                "resolution": null,
+            }}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn find_unsent_reports(db: DbConn, since: DateTime<Utc>) -> Result<Vec<m::Report>> {
+    let mut cursor = m::Report::coll(db)
+        .find(
+            doc! {"date_completed": {"$gte": since}, "sent_to_irwin": false},
+            None,
+        )
+        .await?;
+    let mut reports = Vec::new();
+    while let Some(report) = cursor.next().await {
+        reports.push(from_document(report?)?);
+    }
+    Ok(reports)
+}
+
+/// Up to `limit` complete reports that were never sent to irwin or resolved by hand,
+/// e.g. because the irwin breaker was open, the ones looked at least recently first.
+pub async fn find_held_reports(db: DbConn, limit: i64) -> Result<Vec<m::Report>> {
+    let mut cursor = m::Report::coll(db)
+        .find(
+            doc! {
+                "date_completed": {"$ne": null},
+                "sent_to_irwin": false,
+                "resolution": null,
+            },
+            FindOptions::builder()
+                .sort(doc! {"date_checked": 1, "date_completed": 1})
+                .limit(limit)
+                .build(),
+        )
+        .await?;
+    let mut reports = Vec::new();
+    while let Some(report) = cursor.next().await {
+        reports.push(from_document(report?)?);
+    }
+    Ok(reports)
+}
+
+/// Reports that haven't been sent to irwin or resolved by hand.
+pub async fn unsent_report_ids(db: DbConn) -> Result<Vec<m::ReportId>> {
+    Ok(m::Report::coll(db)
+        .distinct("_id", doc! {"sent_to_irwin": false, "resolution": null}, None)
+        .await?
+        .into_iter()
+        .filter_map(|report_id| match report_id {
+            Bson::ObjectId(report_id) => Some(m::ReportId(report_id)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Up to `limit` reports requested before `before` that are still waiting on analysis,
+/// the ones looked at least recently first; see `mark_report_checked`.
+pub async fn find_incomplete_reports(
+    db: DbConn,
+    before: DateTime<Utc>,
+    limit: i64,
+) -> Result<Vec<m::Report>> {
+    let mut cursor = m::Report::coll(db)
+        .find(
+            doc! {
+                "date_requested": {"$lte": before},
+                "date_completed": null,
+                "sent_to_irwin": false,
+                "resolution": null,
+            },
+            FindOptions::builder()
+                .sort(doc! {"date_checked": 1, "date_requested": 1})
+                .limit(limit)
+                .build(),
+        )
+        .await?;
+    let mut reports = Vec::new();
+    while let Some(report) = cursor.next().await {
+        reports.push(from_document(report?)?);
+    }
+    Ok(reports)
+}
+
+/// Sends the report to the back of the line for `find_incomplete_reports`.
+pub async fn mark_report_checked(db: DbConn, id: m::ReportId) -> Result<()> {
+    m::Report::coll(db.clone())
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {"$set": {"date_checked": db.now()}}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
 pub async fn find_report(db: DbConn, id: m::ReportId) -> Result<Option<m::Report>> {
     let reports_coll = m::Report::coll(db.clone());
     Ok(reports_coll
@@ -80,20 +273,139 @@ pub async fn find_report(db: DbConn, id: m::ReportId) -> Result<Option<m::Report
         .transpose()?)
 }
 
-pub fn precedence_for_origin(origin: m::ReportOrigin) -> i32 {
+/// Every report including the game, newest first.
+pub async fn reports_for_game(db: DbConn, game_id: m::GameId) -> Result<Vec<m::Report>> {
+    let mut cursor = m::Report::coll(db)
+        .find(
+            doc! {"games": game_id.0},
+            FindOptions::builder().sort(doc! {"date_requested": -1}).build(),
+        )
+        .await?;
+    let mut reports = Vec::new();
+    while let Some(report) = cursor.next().await {
+        reports.push(from_document(report?)?);
+    }
+    Ok(reports)
+}
+
+/// The multikey index backing `reports_for_game`.
+pub async fn ensure_report_game_index(db: DbConn) -> Result<()> {
+    db.database
+        .run_command(
+            doc! {
+                "createIndexes": "deepq_reports",
+                "indexes": [{
+                    "key": {"games": 1, "date_requested": -1},
+                    "name": "games_requested",
+                }],
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Every game from any of the user's reports, sorted by id so pages of it are stable.
+pub async fn game_ids_for_user(db: DbConn, user_id: m::UserId) -> Result<Vec<m::GameId>> {
+    let mut game_ids: Vec<m::GameId> = m::Report::coll(db)
+        .distinct("games", doc! {"user_id": user_id.0}, None)
+        .await?
+        .into_iter()
+        .filter_map(|game_id| match game_id {
+            Bson::String(game_id) => Some(m::GameId(game_id)),
+            _ => None,
+        })
+        .collect();
+    game_ids.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(game_ids)
+}
+
+/// The index backing `count_reports_for_user` and `game_ids_for_user`.
+pub async fn ensure_report_user_index(db: DbConn) -> Result<()> {
+    db.database
+        .run_command(
+            doc! {
+                "createIndexes": "deepq_reports",
+                "indexes": [{"key": {"user_id": 1}, "name": "user_id"}],
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+pub fn precedence_for_origin(origin: m::ReportOrigin, precedence: &PrecedenceConfig) -> i32 {
     match origin {
-        m::ReportOrigin::Moderator => 1_000_000i32,
-        m::ReportOrigin::Leaderboard => 1000i32,
-        m::ReportOrigin::Tournament => 100i32,
-        m::ReportOrigin::Random => 10i32,
+        m::ReportOrigin::Moderator => precedence.moderator,
+        m::ReportOrigin::Leaderboard => precedence.leaderboard,
+        m::ReportOrigin::Tournament => precedence.tournament,
+        m::ReportOrigin::Random => precedence.random,
     }
 }
 
-pub fn starting_position(_game: m::Game) -> Fen {
-    // TODO: this will eventually need to be smarter, but not for v1
-    "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
-        .parse()
-        .expect("this cannot fail")
+/// The extra precedence for a user already reported `prior_reports` times.
+pub fn repeat_user_bonus(prior_reports: u64, precedence: &PrecedenceConfig) -> i32 {
+    let prior = i32::try_from(prior_reports).unwrap_or(i32::MAX);
+    precedence
+        .repeat_user_bonus
+        .saturating_mul(prior)
+        .min(precedence.repeat_user_bonus_max)
+        .max(0)
+}
+
+pub async fn count_reports_for_user(db: DbConn, user_id: m::UserId) -> Result<u64> {
+    Ok(m::Report::coll(db)
+        .count_documents(doc! {"user_id": user_id.0}, None)
+        .await?)
+}
+
+pub fn analysis_type_for_origin(
+    origin: m::ReportOrigin,
+    analysis: &OriginAnalysisConfig,
+) -> AnalysisType {
+    match origin {
+        m::ReportOrigin::Moderator => analysis.moderator.clone(),
+        m::ReportOrigin::Leaderboard => analysis.leaderboard.clone(),
+        m::ReportOrigin::Tournament => analysis.tournament.clone(),
+        m::ReportOrigin::Random => analysis.random.clone(),
+    }
+}
+
+pub fn starting_position(game: m::Game) -> Fen {
+    // NOTE: initial fens were validated by `initial_position` when the game was created.
+    game.initial_fen
+        .and_then(|fen| fen.parse().ok())
+        .unwrap_or_else(|| {
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1"
+                .parse()
+                .expect("this cannot fail")
+        })
+}
+
+/// The position a game starts from; `field` names where `fen` came from if it doesn't
+/// describe a legal position. Games without one start from the standard position.
+pub fn initial_position(
+    game_id: &m::GameId,
+    field: &'static str,
+    fen: Option<&str>,
+) -> Result<Chess> {
+    let fen = match fen {
+        Some(fen) => fen,
+        None => return Ok(Chess::default()),
+    };
+    let invalid = || Error::InvalidFen {
+        game_id: game_id.0.clone(),
+        field,
+        fen: fen.to_string(),
+    };
+    let setup: Fen = fen.parse().map_err(|_| invalid())?;
+    Chess::from_setup(&setup, CastlingMode::Standard).map_err(|_| invalid())
+}
+
+/// The side that moved first in the game, black for games from a position with black
+/// to move.
+pub fn first_to_move(game: &m::Game) -> Result<Color> {
+    Ok(initial_position(&game._id, "initial_fen", game.initial_fen.as_deref())?.turn())
 }
 
 #[derive(Debug, Clone)]
@@ -105,25 +417,51 @@ pub struct CreateGame {
     pub pgn: Vec<Uci>,
     pub black: Option<m::UserId>,
     pub white: Option<m::UserId>,
+    pub variant: Option<String>,
+    pub clock: Option<m::GameClock>,
+    /// Checked with `initial_position` before the game is created.
+    pub initial_fen: Option<String>,
 }
 
 impl From<CreateGame> for m::Game {
     fn from(g: CreateGame) -> m::Game {
-        m::Game {
+        let mut game = m::Game {
             _id: g.game_id,
             emts: g.emts,
             pgn: g.pgn,
             black: g.black,
             white: g.white,
-        }
+            content_hash: None,
+            variant: g.variant,
+            clock: g.clock,
+            initial_fen: g.initial_fen,
+        };
+        game.content_hash = Some(game.content_hash());
+        game
     }
 }
 
 pub async fn insert_one_game(db: DbConn, game: CreateGame) -> Result<m::GameId> {
+    Ok(upsert_game(db, game).await?.0)
+}
+
+/// Stores the game, returning its id and whether anything was written; a game already
+/// stored exactly as given is left alone.
+pub async fn upsert_game(db: DbConn, game: CreateGame) -> Result<(m::GameId, bool)> {
     // NOTE: because games are unique on their game id, we have to do an upsert
     let game: m::Game = game.into();
     debug!("Insert One Game: {:?}", game);
     let games_coll = m::Game::coll(db.clone());
+    let unchanged = games_coll
+        .count_documents(
+            doc! { "_id": game._id.clone(), "content_hash": game.content_hash() },
+            None,
+        )
+        .await?;
+    if unchanged > 0 {
+        debug!("Game {} is unchanged, skipping write", game._id);
+        return Ok((game._id, false));
+    }
     let result = games_coll
         .update_one(
             doc! { "_id": game._id.clone() },
@@ -132,7 +470,7 @@ pub async fn insert_one_game(db: DbConn, game: CreateGame) -> Result<m::GameId>
         )
         .await?;
     debug!("Result: {:?}", result);
-    Ok(game._id)
+    Ok((game._id, true))
 }
 
 pub fn insert_many_games<T>(
@@ -146,6 +484,20 @@ where
     games.map(move |game| insert_one_game(db.clone(), game))
 }
 
+/// Which of the given games are stored already.
+pub async fn existing_game_ids(db: DbConn, game_ids: &[m::GameId]) -> Result<Vec<m::GameId>> {
+    let game_ids: Vec<Bson> = game_ids.iter().cloned().map(Into::into).collect();
+    Ok(m::Game::coll(db)
+        .distinct("_id", doc! {"_id": {"$in": game_ids}}, None)
+        .await?
+        .into_iter()
+        .filter_map(|game_id| match game_id {
+            Bson::String(game_id) => Some(m::GameId(game_id)),
+            _ => None,
+        })
+        .collect())
+}
+
 pub async fn find_game(db: DbConn, game_id: m::GameId) -> Result<Option<m::Game>> {
     let games_coll = m::Game::coll(db.clone());
     Ok(games_coll
@@ -164,6 +516,8 @@ pub struct UpdateGameAnalysis {
     pub requested_pvs: Option<i32>,
     pub requested_depth: Option<i32>,
     pub requested_nodes: m::Nodes,
+    pub engine: Option<m::EngineIdentity>,
+    pub flavor: Option<m::StockfishFlavor>,
 }
 
 impl From<UpdateGameAnalysis> for m::GameAnalysis {
@@ -173,10 +527,15 @@ impl From<UpdateGameAnalysis> for m::GameAnalysis {
             job_id: g.job_id,
             game_id: g.game_id,
             source_id: g.source_id,
+            actual_pvs: m::GameAnalysis::actual_pvs(&g.analysis),
+            stats: m::AnalysisStats::from_analysis(&g.analysis),
             analysis: g.analysis.clone(),
             requested_pvs: g.requested_pvs,
             requested_depth: g.requested_depth,
             requested_nodes: g.requested_nodes,
+            engine: g.engine,
+            date_analysed: None,
+            flavor: g.flavor,
         }
     }
 }
@@ -186,7 +545,8 @@ pub async fn upsert_one_game_analysis(
     analysis: UpdateGameAnalysis,
 ) -> Result<ObjectId> {
     let analysis_coll = m::GameAnalysis::coll(db.clone());
-    let analysis: m::GameAnalysis = analysis.into();
+    let mut analysis: m::GameAnalysis = analysis.into();
+    analysis.date_analysed = Some(BsonDateTime(db.now()));
     let result = analysis_coll
         .update_one(
             doc! { "_id": analysis._id.clone() },
@@ -198,11 +558,194 @@ pub async fn upsert_one_game_analysis(
     Ok(analysis._id)
 }
 
+pub async fn find_analysis_for_game(
+    db: DbConn,
+    game_id: m::GameId,
+) -> Result<Option<m::GameAnalysis>> {
+    let analysis_coll = m::GameAnalysis::coll(db.clone());
+    let options = FindOneOptions::builder().sort(doc! {"_id": -1}).build();
+    Ok(analysis_coll
+        .find_one(doc! {"game_id": game_id}, options)
+        .await?
+        .map(from_document)
+        .transpose()?)
+}
+
 pub async fn find_analysis_for_job(db: DbConn, job_id: JobId) -> Result<Option<m::GameAnalysis>> {
     let analysis_coll = m::GameAnalysis::coll(db.clone());
+    let options = FindOneOptions::builder().sort(doc! {"_id": -1}).build();
     Ok(analysis_coll
-        .find_one(doc! {"job_id": job_id.0}, None)
+        .find_one(doc! {"job_id": job_id.0}, options)
         .await?
         .map(from_document)
         .transpose()?)
 }
+
+pub async fn insert_raw_analysis(
+    db: DbConn,
+    job_id: JobId,
+    game_id: m::GameId,
+    api_user_id: ObjectId,
+    report: Document,
+) -> Result<ObjectId> {
+    let raw = m::RawAnalysis {
+        _id: ObjectId::new(),
+        job_id,
+        game_id,
+        api_user_id,
+        date_received: BsonDateTime(db.now()),
+        report,
+    };
+    m::RawAnalysis::coll(db)
+        .insert_one(to_document(&raw)?, None)
+        .await?;
+    Ok(raw._id)
+}
+
+/// Every raw report submitted for the game, oldest first.
+pub async fn find_raw_analysis_for_game(
+    db: DbConn,
+    game_id: m::GameId,
+) -> Result<Vec<m::RawAnalysis>> {
+    let options = FindOptions::builder().sort(doc! {"_id": 1}).build();
+    let mut cursor = m::RawAnalysis::coll(db)
+        .find(doc! {"game_id": game_id}, options)
+        .await?;
+    let mut raw = Vec::new();
+    while let Some(doc) = cursor.next().await {
+        raw.push(from_document(doc?)?);
+    }
+    Ok(raw)
+}
+
+/// What `purge_game` removed.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PurgedGame {
+    pub games: i64,
+    pub analyses: i64,
+    pub jobs: i64,
+    pub reports: i64,
+}
+
+/// Removes a game along with its analysis and jobs, and takes it out of any reports
+/// that asked for it. Anything already gone is simply counted as zero, so it is safe
+/// to run again after a partial failure.
+pub async fn purge_game(db: DbConn, game_id: m::GameId) -> Result<PurgedGame> {
+    let analyses = m::GameAnalysis::coll(db.clone())
+        .delete_many(doc! {"game_id": game_id.clone()}, None)
+        .await?
+        .deleted_count
+        + m::RawAnalysis::coll(db.clone())
+            .delete_many(doc! {"game_id": game_id.clone()}, None)
+            .await?
+            .deleted_count;
+    let jobs = Job::coll(db.clone())
+        .delete_many(doc! {"game_id": game_id.clone()}, None)
+        .await?
+        .deleted_count;
+    let reports = m::Report::coll(db.clone())
+        .update_many(
+            doc! {"games": game_id.clone()},
+            UpdateModifications::Document(doc! {"$pull": {"games": game_id.clone()}}),
+            None,
+        )
+        .await?
+        .modified_count;
+    // NOTE: the game goes last so a failure part way through can be retried by id.
+    let games = m::Game::coll(db)
+        .delete_one(doc! {"_id": game_id}, None)
+        .await?
+        .deleted_count;
+    Ok(PurgedGame {
+        games,
+        analyses,
+        jobs,
+        reports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::db::{testing::test_db, ManualClock};
+
+    fn create_report() -> CreateReport {
+        CreateReport {
+            user_id: m::UserId("someone".to_string()),
+            origin: m::ReportOrigin::Moderator,
+            origin_detail: None,
+            report_type: m::ReportType::Irwin,
+            games: vec![m::GameId("abcdefgh".to_string())],
+        }
+    }
+
+    #[test]
+    fn origins_get_their_configured_analysis_type() {
+        let config = OriginAnalysisConfig::default();
+        let analysis = |origin| analysis_type_for_origin(origin, &config);
+        assert_eq!(analysis(m::ReportOrigin::Moderator), AnalysisType::Deep);
+        assert_eq!(analysis(m::ReportOrigin::Leaderboard), AnalysisType::Deep);
+        assert_eq!(analysis(m::ReportOrigin::Tournament), AnalysisType::Deep);
+        assert_eq!(analysis(m::ReportOrigin::Random), AnalysisType::SystemAnalysis);
+
+        let config = OriginAnalysisConfig {
+            random: AnalysisType::UserAnalysis,
+            ..OriginAnalysisConfig::default()
+        };
+        let analysis = analysis_type_for_origin(m::ReportOrigin::Random, &config);
+        assert_eq!(analysis, AnalysisType::UserAnalysis);
+    }
+
+    #[test]
+    fn repeat_user_bonus_grows_up_to_its_cap() {
+        let precedence = PrecedenceConfig {
+            repeat_user_bonus: 10,
+            repeat_user_bonus_max: 25,
+            ..PrecedenceConfig::default()
+        };
+        assert_eq!(repeat_user_bonus(0, &precedence), 0);
+        assert_eq!(repeat_user_bonus(2, &precedence), 20);
+        assert_eq!(repeat_user_bonus(3, &precedence), 25);
+        assert_eq!(repeat_user_bonus(u64::MAX, &precedence), 25);
+    }
+
+    #[test]
+    fn repeat_user_bonus_is_off_by_default() {
+        assert_eq!(repeat_user_bonus(5, &PrecedenceConfig::default()), 0);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn reports_are_incomplete_once_the_clock_passes_their_cutoff() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let clock = Arc::new(ManualClock::new(start));
+        let db = test_db("reports_incomplete_after_cutoff").await.with_clock(clock.clone());
+        let id = insert_one_report(db.clone(), create_report()).await.unwrap();
+        let report = find_report(db.clone(), id.clone()).await.unwrap().unwrap();
+        assert_eq!(report.date_requested.0, start);
+
+        clock.advance(chrono::Duration::minutes(30));
+        let before = db.now() - chrono::Duration::hours(1);
+        assert!(find_incomplete_reports(db.clone(), before, 10).await.unwrap().is_empty());
+
+        clock.advance(chrono::Duration::hours(1));
+        let before = db.now() - chrono::Duration::hours(1);
+        let incomplete = find_incomplete_reports(db.clone(), before, 10).await.unwrap();
+        let ids: Vec<ObjectId> = incomplete.into_iter().map(|r| r._id.0).collect();
+        assert_eq!(ids, vec![id.0]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn reads_of_missing_records_are_none() {
+        let db = test_db("deepq_reads_of_missing_records").await;
+        let report_id = m::ReportId(ObjectId::new());
+        assert!(find_report(db.clone(), report_id).await.unwrap().is_none());
+        let game_id = m::GameId("abcdefgh".to_string());
+        assert!(find_game(db.clone(), game_id).await.unwrap().is_none());
+        let job_id = JobId(ObjectId::new());
+        assert!(find_analysis_for_job(db.clone(), job_id).await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use warp::{
+    filters::{method, BoxedFilter},
+    path,
+    reply::{self, Reply},
+    Filter,
+};
+
+use crate::config::{Config, IrwinConfig};
+use crate::db::DbConn;
+use crate::deepq::api::{self, PurgedGame};
+use crate::deepq::metrics::{GameAccuracy, PlayerAccuracy};
+use crate::deepq::model::{Game, GameId, RawAnalysis, Report, ReportAction, ReportId, UserId};
+use crate::error::{Error, HttpError, Result};
+use crate::fishnet::{api as fishnet_api, filters as f, model as fm};
+use crate::http::{handle, recover, with};
+use crate::irwin::api as irwin_api;
+
+async fn purge_game(
+    db: DbConn,
+    api_user: f::Authorized<fm::ApiUser>,
+    game_id: GameId,
+) -> Result<PurgedGame> {
+    info!("purge_game > {} > {}", api_user.val().name, game_id);
+    api::purge_game(db, game_id).await
+}
+
+async fn get_raw_analysis(
+    db: DbConn,
+    api_user: f::Authorized<fm::ApiUser>,
+    game_id: GameId,
+) -> Result<Vec<RawAnalysis>> {
+    info!("get_raw_analysis > {} > {}", api_user.val().name, game_id);
+    api::find_raw_analysis_for_game(db, game_id).await
+}
+
+async fn get_game_reports(
+    db: DbConn,
+    api_user: f::Authorized<fm::ApiUser>,
+    game_id: GameId,
+) -> Result<Vec<Report>> {
+    info!("get_game_reports > {} > {}", api_user.val().name, game_id);
+    api::reports_for_game(db, game_id).await
+}
+
+const DEFAULT_SUMMARY_GAMES: usize = 100;
+const MAX_SUMMARY_GAMES: usize = 500;
+
+#[derive(Deserialize, Debug)]
+struct SummaryQuery {
+    #[serde(default)]
+    skip: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Debug)]
+struct UserSummary {
+    user_id: UserId,
+    reports: u64,
+    /// Distinct games across all of the user's reports, not just this page.
+    games: usize,
+    skip: usize,
+    /// Games on this page with analysis to grade the user's moves from.
+    games_analyzed: usize,
+    accuracy: PlayerAccuracy,
+    blunder_rate: Option<f64>,
+}
+
+fn is_user(player: &Option<UserId>, user_id: &UserId) -> bool {
+    player
+        .as_ref()
+        .map_or(false, |player| player.0.eq_ignore_ascii_case(&user_id.0))
+}
+
+/// The user's side of the game's accuracy, if they played in it.
+fn user_accuracy(game: &Game, accuracy: GameAccuracy, user_id: &UserId) -> Option<PlayerAccuracy> {
+    if is_user(&game.white, user_id) {
+        Some(accuracy.white)
+    } else if is_user(&game.black, user_id) {
+        Some(accuracy.black)
+    } else {
+        None
+    }
+}
+
+/// How the user played across the games of all their reports, graded a page of games
+/// at a time. Every game on the page takes two lookups, so a full page of
+/// `MAX_SUMMARY_GAMES` can take a few seconds.
+async fn user_summary(
+    db: DbConn,
+    api_user: f::Authorized<fm::ApiUser>,
+    user_id: UserId,
+    query: SummaryQuery,
+) -> Result<UserSummary> {
+    info!("user_summary > {} > {} > {:?}", api_user.val().name, user_id, query);
+    let limit = query.limit.unwrap_or(DEFAULT_SUMMARY_GAMES).min(MAX_SUMMARY_GAMES);
+    let reports = api::count_reports_for_user(db.clone(), user_id.clone()).await?;
+    let game_ids = api::game_ids_for_user(db.clone(), user_id.clone()).await?;
+    let mut accuracy = PlayerAccuracy::default();
+    let mut games_analyzed = 0;
+    for game_id in game_ids.iter().skip(query.skip).take(limit) {
+        let game = match api::find_game(db.clone(), game_id.clone()).await? {
+            Some(game) => game,
+            None => continue,
+        };
+        let analysis = match api::find_analysis_for_game(db.clone(), game_id.clone()).await? {
+            Some(analysis) => analysis,
+            None => continue,
+        };
+        let game_accuracy = GameAccuracy::from_analysis(&game, &analysis)?;
+        if let Some(player) = user_accuracy(&game, game_accuracy, &user_id) {
+            accuracy.add(&player);
+            games_analyzed += 1;
+        }
+    }
+    Ok(UserSummary {
+        user_id,
+        reports,
+        games: game_ids.len(),
+        skip: query.skip,
+        games_analyzed,
+        blunder_rate: accuracy.blunder_rate(),
+        accuracy,
+    })
+}
+
+#[derive(Serialize, Debug)]
+struct ResolvedReport {
+    report_id: String,
+    action: ReportAction,
+    by: String,
+    games_submitted: usize,
+    games_missing: Vec<GameId>,
+    jobs_cancelled: i64,
+}
+
+/// Claims the report for the given action, or explains why it can't be.
+async fn claim_report(
+    db: DbConn,
+    report_id: ReportId,
+    action: ReportAction,
+    by: String,
+) -> Result<Report> {
+    match api::resolve_report(db.clone(), report_id.clone(), action, by).await? {
+        Some(report) => Ok(report),
+        None => match api::find_report(db, report_id).await? {
+            Some(_) => Err(HttpError::ReportResolved.into()),
+            None => Err(Error::NotFoundError),
+        },
+    }
+}
+
+async fn force_complete_report(
+    db: DbConn,
+    config: IrwinConfig,
+    client: reqwest::Client,
+    api_user: f::Authorized<fm::ApiUser>,
+    report_id: ReportId,
+) -> Result<ResolvedReport> {
+    let by = api_user.val().name.clone();
+    info!("force_complete_report > {} > {}", by, report_id);
+    let report = claim_report(
+        db.clone(),
+        report_id.clone(),
+        ReportAction::ForceCompleted,
+        by.clone(),
+    )
+    .await?;
+    let submitted = irwin_api::submit_report(db.clone(), &config, &client, &report, true).await;
+    let submitted = match submitted {
+        Ok(submitted) => submitted,
+        Err(err) => {
+            error!(
+                "force_complete_report > {} > unable to submit: {:?}",
+                report_id, err
+            );
+            api::unresolve_report(db, report_id).await?;
+            return Err(err);
+        }
+    };
+    api::mark_report_complete(db, report_id.clone()).await?;
+    let games_missing = report
+        .games
+        .iter()
+        .filter(|game_id| !submitted.iter().any(|id| id.0 == game_id.0))
+        .cloned()
+        .collect();
+    Ok(ResolvedReport {
+        report_id: report_id.to_string(),
+        action: ReportAction::ForceCompleted,
+        by,
+        games_submitted: submitted.len(),
+        games_missing,
+        jobs_cancelled: 0,
+    })
+}
+
+async fn cancel_report(
+    db: DbConn,
+    api_user: f::Authorized<fm::ApiUser>,
+    report_id: ReportId,
+) -> Result<ResolvedReport> {
+    let by = api_user.val().name.clone();
+    info!("cancel_report > {} > {}", by, report_id);
+    claim_report(
+        db.clone(),
+        report_id.clone(),
+        ReportAction::Cancelled,
+        by.clone(),
+    )
+    .await?;
+    let jobs_cancelled = fishnet_api::cancel_jobs_for_report(db, report_id.clone()).await?;
+    Ok(ResolvedReport {
+        report_id: report_id.to_string(),
+        action: ReportAction::Cancelled,
+        by,
+        games_submitted: 0,
+        games_missing: Vec::new(),
+        jobs_cancelled,
+    })
+}
+
+pub fn mount(
+    db: DbConn,
+    config: &Config,
+    irwin_client: reqwest::Client,
+) -> BoxedFilter<(impl Reply,)> {
+    let capability_required = |capability| f::capability_required(db.clone(), capability);
+
+    let purge_game = path("game")
+        .and(method::delete())
+        .and(with(db.clone()))
+        .and(capability_required(fm::Capability::PurgeGames))
+        .and(path::param())
+        .and(path::end())
+        .and_then(|db, api_user, game_id| handle(purge_game(db, api_user, game_id)))
+        .map(|purged| reply::json(&purged));
+
+    let raw_analysis = path("game")
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(capability_required(fm::Capability::Audit))
+        .and(path::param())
+        .and(path("raw-analysis"))
+        .and(path::end())
+        .and_then(|db, api_user, game_id| handle(get_raw_analysis(db, api_user, game_id)))
+        .map(|raw| reply::json(&raw));
+
+    let game_reports = path("game")
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(capability_required(fm::Capability::Audit))
+        .and(path::param())
+        .and(path("reports"))
+        .and(path::end())
+        .and_then(|db, api_user, game_id| handle(get_game_reports(db, api_user, game_id)))
+        .map(|reports| reply::json(&reports));
+
+    let summary = path("user")
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(capability_required(fm::Capability::Audit))
+        .and(path::param())
+        .and(path("summary"))
+        .and(path::end())
+        .and(warp::query::<SummaryQuery>())
+        .and_then(|db, api_user, user_id, query| {
+            handle(user_summary(db, api_user, user_id, query))
+        })
+        .map(|summary| reply::json(&summary));
+
+    let force_complete = path("reports")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(with(config.irwin.clone()))
+        .and(with(irwin_client))
+        .and(capability_required(fm::Capability::ManageQueue))
+        .and(path::param())
+        .and(path("force-complete"))
+        .and(path::end())
+        .and_then(|db, config, client, api_user, report_id| {
+            handle(force_complete_report(db, config, client, api_user, report_id))
+        })
+        .map(|resolved| reply::json(&resolved));
+
+    let cancel = path("reports")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(capability_required(fm::Capability::ManageQueue))
+        .and(path::param())
+        .and(path("cancel"))
+        .and(path::end())
+        .and_then(|db, api_user, report_id| handle(cancel_report(db, api_user, report_id)))
+        .map(|resolved| reply::json(&resolved));
+
+    purge_game
+        .or(raw_analysis)
+        .or(game_reports)
+        .or(summary)
+        .or(force_complete)
+        .or(cancel)
+        .recover(recover)
+        .boxed()
+}
@@ -0,0 +1,196 @@
+// Copyright 2020 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Per-player accuracy derived from a stored analysis: average centipawn loss and
+//! inaccuracy/mistake/blunder counts.
+
+use serde::Serialize;
+use shakmaty::Color;
+
+use crate::deepq::api::first_to_move;
+use crate::deepq::model::{Game, GameAnalysis, PlyAnalysis, Score};
+use crate::error::Result;
+
+/// Evaluations are clamped to this many centipawns; mates count as the limit.
+const CP_CEILING: i64 = 1000;
+const INACCURACY: i64 = 50;
+const MISTAKE: i64 = 100;
+const BLUNDER: i64 = 300;
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PlayerAccuracy {
+    /// None when none of the player's moves had a usable evaluation on both sides.
+    pub average_centipawn_loss: Option<f64>,
+    pub analyzed_moves: u32,
+    pub inaccuracies: u32,
+    pub mistakes: u32,
+    pub blunders: u32,
+    #[serde(skip)]
+    total_loss: i64,
+}
+
+impl PlayerAccuracy {
+    fn record(&mut self, loss: i64) {
+        self.analyzed_moves += 1;
+        self.total_loss += loss;
+        if loss >= BLUNDER {
+            self.blunders += 1;
+        } else if loss >= MISTAKE {
+            self.mistakes += 1;
+        } else if loss >= INACCURACY {
+            self.inaccuracies += 1;
+        }
+    }
+
+    fn finish(&mut self) {
+        if self.analyzed_moves > 0 {
+            self.average_centipawn_loss =
+                Some(self.total_loss as f64 / f64::from(self.analyzed_moves));
+        }
+    }
+
+    /// Folds in another game's accuracy, e.g. to grade a player across several games.
+    pub fn add(&mut self, other: &PlayerAccuracy) {
+        self.analyzed_moves += other.analyzed_moves;
+        self.inaccuracies += other.inaccuracies;
+        self.mistakes += other.mistakes;
+        self.blunders += other.blunders;
+        self.total_loss += other.total_loss;
+        self.finish();
+    }
+
+    /// The share of graded moves that were blunders.
+    pub fn blunder_rate(&self) -> Option<f64> {
+        match self.analyzed_moves {
+            0 => None,
+            moves => Some(f64::from(self.blunders) / f64::from(moves)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GameAccuracy {
+    pub white: PlayerAccuracy,
+    pub black: PlayerAccuracy,
+}
+
+/// The score in centipawns from the side to move's point of view, clamped to
+/// `CP_CEILING`. Terminal positions have no move to grade, so they give None.
+fn centipawns(score: &Score) -> Option<i64> {
+    match score {
+        Score::Cp(cp) => Some((*cp).clamp(-CP_CEILING, CP_CEILING)),
+        Score::Mate(mate) if *mate > 0 => Some(CP_CEILING),
+        Score::Mate(_) => Some(-CP_CEILING),
+        Score::Terminal => None,
+    }
+}
+
+fn ply_centipawns(ply: &Option<PlyAnalysis>) -> Option<i64> {
+    ply.as_ref().and_then(PlyAnalysis::score).and_then(centipawns)
+}
+
+impl GameAccuracy {
+    /// Grades every move of the game. Moves whose position before or after wasn't
+    /// analyzed (skipped or missing plies) are left out of the averages.
+    pub fn from_analysis(game: &Game, analysis: &GameAnalysis) -> Result<GameAccuracy> {
+        let first = first_to_move(game)?;
+        let mut accuracy = GameAccuracy::default();
+        for ply in 0..game.pgn.len() {
+            let before = analysis.analysis.get(ply).and_then(ply_centipawns);
+            let after = analysis.analysis.get(ply + 1).and_then(ply_centipawns);
+            if let (Some(before), Some(after)) = (before, after) {
+                // NOTE: `after` is from the opponent's point of view.
+                let loss = (before + after).max(0);
+                let player = match (ply % 2 == 0) == (first == Color::White) {
+                    true => &mut accuracy.white,
+                    false => &mut accuracy.black,
+                };
+                player.record(loss);
+            }
+        }
+        accuracy.white.finish();
+        accuracy.black.finish();
+        Ok(accuracy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+    use serde_json::json;
+
+    use super::*;
+    use crate::deepq::model::{GameId, Nodes, UserId};
+    use crate::fishnet::model::JobId;
+
+    fn game(moves: &str, initial_fen: Option<&str>) -> Game {
+        serde_json::from_value(json!({
+            "_id": "abcdefgh",
+            "emts": [],
+            "pgn": moves,
+            "black": "bob",
+            "white": "alice",
+            "initial_fen": initial_fen,
+        }))
+        .unwrap()
+    }
+
+    /// An analysis scoring every position `cps` from the side to move's point of view.
+    fn analysis(cps: &[i32]) -> GameAnalysis {
+        let ply = |cp: &i32| -> PlyAnalysis {
+            serde_json::from_value(json!({"depth": 20, "score": {"cp": cp}})).unwrap()
+        };
+        GameAnalysis {
+            _id: ObjectId::new(),
+            job_id: JobId(ObjectId::new()),
+            game_id: GameId("abcdefgh".to_string()),
+            source_id: UserId("deepq".to_string()),
+            analysis: cps.iter().map(|cp| Some(ply(cp))).collect(),
+            requested_pvs: None,
+            requested_depth: None,
+            requested_nodes: Nodes {
+                nnue: 2_250_000,
+                classical: 4_050_000,
+            },
+            actual_pvs: None,
+            stats: None,
+            engine: None,
+            date_analysed: None,
+            flavor: None,
+        }
+    }
+
+    #[test]
+    fn the_first_move_is_whites_from_the_standard_position() {
+        // NOTE: white's move loses 400 centipawns, black's loses nothing.
+        let game = game("e2e4 e7e5", None);
+        let accuracy = GameAccuracy::from_analysis(&game, &analysis(&[0, 400, -400])).unwrap();
+        assert_eq!(accuracy.white.analyzed_moves, 1);
+        assert_eq!(accuracy.white.blunders, 1);
+        assert_eq!(accuracy.black.blunders, 0);
+    }
+
+    #[test]
+    fn the_first_move_is_blacks_from_a_black_to_move_position() {
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let game = game("e7e5 g1f3", Some(fen));
+        let accuracy = GameAccuracy::from_analysis(&game, &analysis(&[0, 400, -400])).unwrap();
+        assert_eq!(accuracy.black.analyzed_moves, 1);
+        assert_eq!(accuracy.black.blunders, 1);
+        assert_eq!(accuracy.white.blunders, 0);
+    }
+}
@@ -14,22 +14,46 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+use std::convert::TryFrom;
+use std::fmt;
 use std::str::FromStr;
 
 use derive_more::{Display, From};
-use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime};
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
 use mongodb::Collection;
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, DisplayFromStr, SpaceSeparator, StringWithSeparator};
+use serde_with::{
+    serde_as, skip_serializing_none, DisplayFromStr, SpaceSeparator, StringWithSeparator,
+};
+use sha2::{Digest, Sha256};
 use shakmaty::uci::Uci;
 
-use crate::db::DbConn;
+use crate::db::{DbConn, ToBsonLower};
 use crate::error::{Error, Result};
 use crate::fishnet::model::JobId;
 
 #[derive(Serialize, Deserialize, Debug, Clone, From, Display)]
 pub struct UserId(pub String);
 
+/// Lichess usernames: 2 to 30 ascii letters, digits, `_` or `-`.
+impl FromStr for UserId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let valid_len = (2..=30).contains(&s.len());
+        let valid_chars = s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid_len || !valid_chars {
+            return Err(Error::InvalidId {
+                kind: "user id",
+                id: s.to_string(),
+            });
+        }
+        Ok(UserId(s.to_string()))
+    }
+}
+
 // TODO: this should be easy enough to make into a macro
 impl From<UserId> for Bson {
     fn from(ui: UserId) -> Bson {
@@ -46,7 +70,22 @@ impl From<GameId> for Bson {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, From, Display)]
+/// Lichess game ids are exactly 8 ascii letters or digits.
+impl FromStr for GameId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 8 || !s.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::InvalidId {
+                kind: "game id",
+                id: s.to_string(),
+            });
+        }
+        Ok(GameId(s.to_string()))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, From, Display)]
 #[serde(rename_all = "lowercase")]
 pub enum ReportOrigin {
     Moderator,
@@ -57,11 +96,11 @@ pub enum ReportOrigin {
 
 impl From<ReportOrigin> for Bson {
     fn from(ro: ReportOrigin) -> Bson {
-        Bson::String(ro.to_string().to_lowercase())
+        ro.to_bson_lower()
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, strum_macros::ToString)]
+#[derive(Serialize, Deserialize, Debug, Clone, strum_macros::Display)]
 #[serde(rename_all = "lowercase")]
 pub enum ReportType {
     Irwin,
@@ -71,7 +110,7 @@ pub enum ReportType {
 
 impl From<ReportType> for Bson {
     fn from(rt: ReportType) -> Bson {
-        Bson::String(rt.to_string().to_lowercase())
+        rt.to_bson_lower()
     }
 }
 
@@ -99,15 +138,47 @@ pub struct Report {
     pub date_requested: DateTime,
     pub date_completed: Option<DateTime>,
     pub origin: ReportOrigin,
+    /// What triggered the report, e.g. a tournament id or a moderator's username.
+    #[serde(default)]
+    pub origin_detail: Option<String>,
     pub report_type: ReportType,
     pub games: Vec<GameId>,
     pub sent_to_irwin: bool,
+    #[serde(default)]
+    pub resolution: Option<ReportResolution>,
+    /// Games irwin already has from an earlier submission that failed part way through.
+    #[serde(default)]
+    pub submitted_games: Vec<GameId>,
+    /// When `submit_stale_reports` or `resubmit_held_reports` last looked at it.
+    #[serde(default)]
+    pub date_checked: Option<DateTime>,
 }
 
 impl Report {
     pub fn coll(db: DbConn) -> Collection {
         db.database.collection("deepq_reports")
     }
+
+    pub fn was_submitted(&self, game_id: &GameId) -> bool {
+        self.submitted_games.iter().any(|id| id.0 == game_id.0)
+    }
+}
+
+/// How a report that couldn't finish on its own was closed out by hand.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, strum_macros::Display)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum ReportAction {
+    ForceCompleted, // Submitted to irwin with whatever analysis we had.
+    Cancelled,      // Abandoned; never submitted.
+    Abandoned,      // Given up on automatically: none of its jobs could still complete.
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReportResolution {
+    pub action: ReportAction,
+    pub by: String,
+    pub date: DateTime,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -116,12 +187,58 @@ pub struct Blurs {
     pub bits: String, // TODO: why string?!
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// An engine evaluation. Workers send `{"cp": .., "mate": ..}` with either field
+/// possibly null; when both are null the position had nothing to search
+/// (checkmate or stalemate on the board), which we keep as `Terminal` rather
+/// than dropping the ply.
+///
+/// This is the only score type: fishnet reports, stored analysis and irwin requests
+/// all use it, so they share `RawScore` as their one serialized form and never need
+/// converting. Both fields are always written, so `Terminal` is two nulls rather than
+/// an empty object. A score with both fields set is refused.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "RawScore", into = "RawScore")]
 pub enum Score {
-    #[serde(rename = "cp")]
     Cp(i64),
-    #[serde(rename = "mate")]
     Mate(i64),
+    Terminal,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct RawScore {
+    #[serde(default)]
+    cp: Option<i64>,
+    #[serde(default)]
+    mate: Option<i64>,
+}
+
+impl TryFrom<RawScore> for Score {
+    type Error = String;
+
+    fn try_from(raw: RawScore) -> std::result::Result<Score, String> {
+        match (raw.cp, raw.mate) {
+            (Some(cp), Some(mate)) => Err(format!("score has both cp {} and mate {}", cp, mate)),
+            (None, Some(mate)) => Ok(Score::Mate(mate)),
+            (Some(cp), None) => Ok(Score::Cp(cp)),
+            (None, None) => Ok(Score::Terminal),
+        }
+    }
+}
+
+impl From<Score> for RawScore {
+    fn from(score: Score) -> RawScore {
+        match score {
+            Score::Cp(cp) => RawScore {
+                cp: Some(cp),
+                mate: None,
+            },
+            Score::Mate(mate) => RawScore {
+                cp: None,
+                mate: Some(mate),
+            },
+            Score::Terminal => RawScore::default(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -135,11 +252,40 @@ pub struct EmptyAnalysis {
     score: Score,
 }
 
+/// A principal variation as space separated UCI moves. Workers may send an empty PV
+/// (e.g. for terminal positions), which parses to no moves.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pv(pub Vec<Uci>);
+
+impl Pv {
+    pub fn moves(&self) -> &[Uci] {
+        &self.0
+    }
+}
+
+impl FromStr for Pv {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Pv(s
+            .split_whitespace()
+            .map(Uci::from_str)
+            .collect::<std::result::Result<Vec<Uci>, _>>()?))
+    }
+}
+
+impl fmt::Display for Pv {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let moves: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        write!(f, "{}", moves.join(" "))
+    }
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BestMove {
-    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
-    pv: Vec<Uci>,
+    #[serde_as(as = "DisplayFromStr")]
+    pv: Pv,
     depth: i32,
     score: Score,
     time: i64,
@@ -150,8 +296,8 @@ pub struct BestMove {
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MatrixAnalysis {
-    #[serde_as(as = "Vec<Vec<Option<Vec<DisplayFromStr>>>>")]
-    pub pv: Vec<Vec<Option<Vec<Uci>>>>,
+    #[serde_as(as = "Vec<Vec<Option<DisplayFromStr>>>")]
+    pub pv: Vec<Vec<Option<Pv>>>,
     pub score: Vec<Vec<Option<Score>>>,
     pub depth: i32,
     pub nodes: i64,
@@ -160,6 +306,17 @@ pub struct MatrixAnalysis {
     pub nps: Option<i64>,
 }
 
+impl MatrixAnalysis {
+    /// Number of PVs that actually arrived, which may be fewer than requested
+    /// (e.g. when a position has fewer legal moves than the multipv setting).
+    pub fn pv_count(&self) -> usize {
+        self.pv
+            .iter()
+            .filter(|line| line.iter().any(Option::is_some))
+            .count()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum PlyAnalysis {
@@ -169,6 +326,115 @@ pub enum PlyAnalysis {
     Empty(EmptyAnalysis),
 }
 
+impl PlyAnalysis {
+    pub fn skipped() -> PlyAnalysis {
+        PlyAnalysis::Skipped(SkippedAnalysis { skipped: true })
+    }
+
+    /// (nodes, time, nps) as reported by the worker for this ply, if it was analyzed.
+    fn search_stats(&self) -> Option<(i64, i64, Option<i64>)> {
+        match self {
+            PlyAnalysis::Matrix(matrix) => Some((matrix.nodes, matrix.time, matrix.nps)),
+            PlyAnalysis::Best(best) => Some((best.nodes, best.time, best.nps)),
+            PlyAnalysis::Skipped(_) | PlyAnalysis::Empty(_) => None,
+        }
+    }
+
+    /// The evaluation of the position: the deepest score of the first PV for multipv analysis.
+    pub fn score(&self) -> Option<&Score> {
+        match self {
+            PlyAnalysis::Matrix(matrix) => matrix
+                .score
+                .first()
+                .and_then(|line| line.iter().rev().flatten().next()),
+            PlyAnalysis::Best(best) => Some(&best.score),
+            PlyAnalysis::Empty(empty) => Some(&empty.score),
+            PlyAnalysis::Skipped(_) => None,
+        }
+    }
+
+    /// The principal variation behind `score`: the deepest PV of the first line for
+    /// multipv analysis.
+    pub fn pv(&self) -> Option<&Pv> {
+        match self {
+            PlyAnalysis::Matrix(matrix) => matrix
+                .pv
+                .first()
+                .and_then(|line| line.iter().rev().flatten().next()),
+            PlyAnalysis::Best(best) => Some(&best.pv),
+            PlyAnalysis::Skipped(_) | PlyAnalysis::Empty(_) => None,
+        }
+    }
+
+    /// Cuts every PV down to at most `max_moves` moves, leaving the scores alone.
+    pub fn truncate_pvs(&mut self, max_moves: usize) {
+        match self {
+            PlyAnalysis::Matrix(matrix) => matrix
+                .pv
+                .iter_mut()
+                .flatten()
+                .flatten()
+                .for_each(|pv| pv.0.truncate(max_moves)),
+            PlyAnalysis::Best(best) => best.pv.0.truncate(max_moves),
+            PlyAnalysis::Skipped(_) | PlyAnalysis::Empty(_) => {}
+        }
+    }
+
+    /// Checks the ply for values that deserialize fine but make no sense, pushing a
+    /// message prefixed with `path` (e.g. "analysis[3]") for each problem found.
+    pub fn validate(&self, path: &str, issues: &mut Vec<String>) {
+        fn non_negative(issues: &mut Vec<String>, path: &str, field: &str, value: i64) {
+            if value < 0 {
+                issues.push(format!("{}.{}: must not be negative", path, field));
+            }
+        }
+        match self {
+            PlyAnalysis::Matrix(matrix) => {
+                non_negative(issues, path, "depth", matrix.depth.into());
+                non_negative(issues, path, "nodes", matrix.nodes);
+                non_negative(issues, path, "time", matrix.time);
+                if matrix.pv.len() != matrix.score.len() {
+                    issues.push(format!(
+                        "{}: {} pv lines but {} score lines",
+                        path,
+                        matrix.pv.len(),
+                        matrix.score.len()
+                    ));
+                }
+                for (i, (pvs, scores)) in matrix.pv.iter().zip(matrix.score.iter()).enumerate() {
+                    for (depth, (pv, score)) in pvs.iter().zip(scores.iter()).enumerate() {
+                        let has_moves = pv.as_ref().map_or(false, |pv| !pv.moves().is_empty());
+                        if has_moves && score == &Some(Score::Terminal) {
+                            issues.push(format!(
+                                "{}.score[{}][{}]: missing both cp and mate",
+                                path, i, depth
+                            ));
+                        }
+                    }
+                }
+            }
+            PlyAnalysis::Best(best) => {
+                non_negative(issues, path, "depth", best.depth.into());
+                non_negative(issues, path, "nodes", best.nodes);
+                non_negative(issues, path, "time", best.time);
+                if !best.pv.moves().is_empty() && best.score == Score::Terminal {
+                    issues.push(format!("{}.score: missing both cp and mate", path));
+                }
+            }
+            PlyAnalysis::Empty(empty) => non_negative(issues, path, "depth", empty.depth.into()),
+            PlyAnalysis::Skipped(_) => {}
+        }
+    }
+
+    pub fn pv_count(&self) -> usize {
+        match self {
+            PlyAnalysis::Matrix(matrix) => matrix.pv_count(),
+            PlyAnalysis::Best(_) => 1,
+            PlyAnalysis::Skipped(_) | PlyAnalysis::Empty(_) => 0,
+        }
+    }
+}
+
 // TODO: this should come directly from the lila db, why store this more than once?
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -179,12 +445,60 @@ pub struct Game {
     pub pgn: Vec<Uci>,
     pub black: Option<UserId>,
     pub white: Option<UserId>,
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    /// The lichess variant key; games stored before we recorded it are standard.
+    #[serde(default)]
+    pub variant: Option<String>,
+    #[serde(default)]
+    pub clock: Option<GameClock>,
+    /// None for games from the standard starting position.
+    #[serde(default)]
+    pub initial_fen: Option<String>,
+}
+
+/// The game's time control, in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GameClock {
+    pub initial: i32,
+    pub increment: i32,
 }
 
 impl Game {
     pub fn coll(db: DbConn) -> Collection {
         db.database.collection("deepq_games")
     }
+
+    /// Hash of everything we store about the game: the normalized UCI moves, players,
+    /// move times, clock, variant and starting position. Used to skip rewriting
+    /// identical games.
+    pub fn content_hash(&self) -> String {
+        let moves: Vec<String> = self.pgn.iter().map(ToString::to_string).collect();
+        let emts: Vec<String> = self.emts.iter().map(ToString::to_string).collect();
+        let player = |p: &Option<UserId>| p.as_ref().map_or(String::new(), |u| u.0.to_lowercase());
+        let clock = self.clock.as_ref().map_or(String::new(), |c| {
+            format!("{}+{}", c.initial, c.increment)
+        });
+        let mut hasher = Sha256::new();
+        hasher.update(moves.join(" "));
+        hasher.update("\n");
+        hasher.update(player(&self.white));
+        hasher.update("\n");
+        hasher.update(player(&self.black));
+        hasher.update("\n");
+        hasher.update(emts.join(" "));
+        hasher.update("\n");
+        hasher.update(clock);
+        hasher.update("\n");
+        hasher.update(self.variant.as_deref().unwrap_or("standard"));
+        // NOTE: games stored before emts, clock and variant were hashed no longer match,
+        //       so each is rewritten once, the next time it is sent.
+        if let Some(fen) = &self.initial_fen {
+            hasher.update("\n");
+            hasher.update(fen);
+        }
+        format!("{:x}", hasher.finalize())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -193,6 +507,59 @@ pub struct Nodes {
     pub classical: i64,
 }
 
+impl Nodes {
+    /// The nodes per position for a worker running `flavor`; the larger budget when it
+    /// may run either.
+    pub fn for_flavor(&self, flavor: Option<StockfishFlavor>) -> i64 {
+        match flavor {
+            Some(StockfishFlavor::Nnue) => self.nnue,
+            Some(StockfishFlavor::Classical) => self.classical,
+            None => self.nnue.max(self.classical),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StockfishFlavor {
+    Nnue,
+    Classical,
+}
+
+/// Aggregate search statistics for a whole game, used to profile workers and tune node budgets.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnalysisStats {
+    pub total_nodes: i64,
+    pub total_time: i64,
+    pub average_nps: Option<i64>,
+}
+
+impl AnalysisStats {
+    pub fn from_analysis(analysis: &[Option<PlyAnalysis>]) -> Option<AnalysisStats> {
+        let plies: Vec<(i64, i64, Option<i64>)> = analysis
+            .iter()
+            .flatten()
+            .filter_map(PlyAnalysis::search_stats)
+            .collect();
+        if plies.is_empty() {
+            return None;
+        }
+        let total_nodes: i64 = plies.iter().map(|(nodes, _, _)| nodes).sum();
+        let total_time: i64 = plies.iter().map(|(_, time, _)| time).sum();
+        let reported_nps: Vec<i64> = plies.iter().filter_map(|(_, _, nps)| *nps).collect();
+        let average_nps = match reported_nps.len() {
+            0 if total_time > 0 => Some(total_nodes * 1000 / total_time),
+            0 => None,
+            n => Some(reported_nps.iter().sum::<i64>() / n as i64),
+        };
+        Some(AnalysisStats {
+            total_nodes,
+            total_time,
+            average_nps,
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GameAnalysis {
     pub _id: ObjectId,
@@ -203,6 +570,27 @@ pub struct GameAnalysis {
     pub requested_pvs: Option<i32>,
     pub requested_depth: Option<i32>,
     pub requested_nodes: Nodes,
+    #[serde(default)]
+    pub actual_pvs: Option<i32>,
+    #[serde(default)]
+    pub stats: Option<AnalysisStats>,
+    /// None for analysis from workers that don't identify their engine.
+    #[serde(default)]
+    pub engine: Option<EngineIdentity>,
+    #[serde(default)]
+    pub date_analysed: Option<DateTime>,
+    /// The flavor agreed with the worker when it acquired the job, or else the one it
+    /// reported analysing with.
+    #[serde(default)]
+    pub flavor: Option<StockfishFlavor>,
+}
+
+/// The engine a worker says it analysed with.
+#[skip_serializing_none]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EngineIdentity {
+    pub name: Option<String>,
+    pub version: Option<String>,
 }
 
 impl GameAnalysis {
@@ -212,4 +600,118 @@ impl GameAnalysis {
     pub fn is_analysis_complete(&self) -> bool {
         self.analysis.iter().filter(|o| o.is_none()).count() == 0_usize
     }
+
+    /// The largest number of PVs reported for any ply, or None if nothing was analyzed.
+    pub fn actual_pvs(analysis: &[Option<PlyAnalysis>]) -> Option<i32> {
+        analysis
+            .iter()
+            .flatten()
+            .map(PlyAnalysis::pv_count)
+            .filter(|count| *count > 0)
+            .max()
+            .map(|count| count as i32)
+    }
+}
+
+/// An analysis report exactly as a worker submitted it (minus its api key), kept for
+/// settling disputes about the distilled `GameAnalysis`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RawAnalysis {
+    pub _id: ObjectId,
+    pub job_id: JobId,
+    pub game_id: GameId,
+    pub api_user_id: ObjectId,
+    pub date_received: DateTime,
+    pub report: Document,
+}
+
+impl RawAnalysis {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_raw_analysis")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{from_bson, to_bson};
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn scores_round_trip_through_json() {
+        for score in &[Score::Cp(-35), Score::Mate(3), Score::Mate(-1), Score::Terminal] {
+            let json = serde_json::to_value(score).unwrap();
+            assert_eq!(&serde_json::from_value::<Score>(json).unwrap(), score);
+        }
+    }
+
+    #[test]
+    fn scores_round_trip_through_bson() {
+        for score in &[Score::Cp(120), Score::Mate(-4), Score::Terminal] {
+            let bson = to_bson(score).unwrap();
+            assert_eq!(&from_bson::<Score>(bson).unwrap(), score);
+        }
+    }
+
+    #[test]
+    fn scores_serialize_both_fields() {
+        let json = |score| serde_json::to_value(score).unwrap();
+        assert_eq!(json(Score::Cp(15)), json!({"cp": 15, "mate": null}));
+        assert_eq!(json(Score::Mate(2)), json!({"cp": null, "mate": 2}));
+        assert_eq!(json(Score::Terminal), json!({"cp": null, "mate": null}));
+    }
+
+    #[test]
+    fn workers_may_leave_fields_out() {
+        let score = |json| serde_json::from_value::<Score>(json).unwrap();
+        assert_eq!(score(json!({"cp": 15})), Score::Cp(15));
+        assert_eq!(score(json!({"mate": -2})), Score::Mate(-2));
+        assert_eq!(score(json!({})), Score::Terminal);
+        assert_eq!(score(json!({"cp": null, "mate": null})), Score::Terminal);
+    }
+
+    #[test]
+    fn scores_with_both_cp_and_mate_are_refused() {
+        assert!(serde_json::from_value::<Score>(json!({"cp": 15, "mate": 2})).is_err());
+    }
+
+    /// A multipv ply with `lines` of the PV lines filled in.
+    fn matrix(lines: usize) -> PlyAnalysis {
+        let moves = ["e2e4 e7e5", "d2d4 d7d5", "g1f3 g8f6", "c2c4 e7e6", "b1c3 c7c5"];
+        let pv: Vec<_> = moves.iter().take(lines).map(|pv| json!([null, pv])).collect();
+        let score: Vec<_> = (0..lines).map(|i| json!([null, {"cp": 30 - i as i64}])).collect();
+        serde_json::from_value(json!({
+            "pv": pv,
+            "score": score,
+            "depth": 22,
+            "nodes": 2_500_000,
+            "time": 1_800,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn one_of_five_pvs_is_accepted_and_counted() {
+        let ply = matrix(1);
+        let mut issues = Vec::new();
+        ply.validate("analysis[1]", &mut issues);
+        assert!(issues.is_empty(), "{:?}", issues);
+        assert_eq!(ply.pv_count(), 1);
+        assert_eq!(GameAnalysis::actual_pvs(&[None, Some(ply)]), Some(1));
+    }
+
+    #[test]
+    fn five_of_five_pvs_are_counted() {
+        let ply = matrix(5);
+        assert_eq!(ply.pv_count(), 5);
+        assert_eq!(GameAnalysis::actual_pvs(&[None, Some(ply)]), Some(5));
+    }
+
+    #[test]
+    fn actual_pvs_is_the_most_any_ply_reported() {
+        let analysis = [Some(PlyAnalysis::skipped()), Some(matrix(1)), Some(matrix(5))];
+        assert_eq!(GameAnalysis::actual_pvs(&analysis), Some(5));
+        assert_eq!(GameAnalysis::actual_pvs(&[Some(PlyAnalysis::skipped()), None]), None);
+    }
 }
@@ -0,0 +1,187 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+//! PGN export of a stored game, optionally annotated with its analysis as `[%eval]`
+//! comments.
+
+use std::fmt::Write;
+
+use shakmaty::{Color, Position, Setup};
+
+use crate::deepq::api::initial_position;
+use crate::deepq::model::{Game, GameAnalysis, PlyAnalysis, Score, UserId};
+use crate::error::Result;
+use crate::irwin::api::san_from_uci;
+
+/// A `[%eval]` value from white's point of view: pawns to two decimals, or `#N` for
+/// mate in N. `score` is from the side to move's point of view, as workers report it.
+fn eval(score: &Score, turn: Color) -> Option<String> {
+    let sign = match turn {
+        Color::White => 1,
+        Color::Black => -1,
+    };
+    match score {
+        Score::Cp(cp) => Some(format!("{:.2}", (sign * cp) as f64 / 100.0)),
+        Score::Mate(mate) => Some(format!("#{}", sign * mate)),
+        Score::Terminal => None,
+    }
+}
+
+fn tag(pgn: &mut String, name: &str, value: &str) {
+    let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+    let _ = writeln!(pgn, "[{} \"{}\"]", name, value);
+}
+
+/// The game as PGN. With an analysis, every analysed position after a move gets an
+/// eval comment; skipped and terminal positions get none.
+pub fn game_pgn(game: &Game, analysis: Option<&GameAnalysis>) -> Result<String> {
+    let start = initial_position(&game._id, "initial_fen", game.initial_fen.as_deref())?;
+    let player = |p: &Option<UserId>| p.as_ref().map_or_else(|| "?".to_string(), |u| u.0.clone());
+    let mut pgn = String::new();
+    tag(&mut pgn, "Site", &format!("https://lichess.org/{}", game._id));
+    tag(&mut pgn, "White", &player(&game.white));
+    tag(&mut pgn, "Black", &player(&game.black));
+    tag(&mut pgn, "Result", "*");
+    if let Some(fen) = &game.initial_fen {
+        tag(&mut pgn, "SetUp", "1");
+        tag(&mut pgn, "FEN", fen);
+    }
+    pgn.push('\n');
+
+    let mut turn = start.turn();
+    let mut fullmoves = start.fullmoves().get();
+    let mut movetext = Vec::new();
+    // NOTE: black's move needs its number unless it directly follows white's.
+    let mut after_move = false;
+    for (ply, (san, pos)) in san_from_uci(start, &game.pgn)?.into_iter().enumerate() {
+        match turn {
+            Color::White => movetext.push(format!("{}.", fullmoves)),
+            Color::Black if !after_move => movetext.push(format!("{}...", fullmoves)),
+            Color::Black => {}
+        }
+        movetext.push(san);
+        after_move = true;
+        let score = analysis
+            .and_then(|analysis| analysis.analysis.get(ply + 1))
+            .and_then(Option::as_ref)
+            .and_then(PlyAnalysis::score);
+        if let Some(eval) = score.and_then(|score| eval(score, pos.turn())) {
+            movetext.push(format!("{{ [%eval {}] }}", eval));
+            after_move = false;
+        }
+        if turn == Color::Black {
+            fullmoves += 1;
+        }
+        turn = pos.turn();
+    }
+    movetext.push("*".to_string());
+    pgn.push_str(&movetext.join(" "));
+    pgn.push('\n');
+    Ok(pgn)
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::oid::ObjectId;
+    use serde_json::json;
+
+    use super::*;
+    use crate::deepq::model::{GameId, Nodes};
+    use crate::fishnet::model::JobId;
+
+    fn game(moves: &str, initial_fen: Option<&str>) -> Game {
+        serde_json::from_value(json!({
+            "_id": "abcdefgh",
+            "emts": [],
+            "pgn": moves,
+            "black": "bob",
+            "white": "alice",
+            "initial_fen": initial_fen,
+        }))
+        .unwrap()
+    }
+
+    fn analysis(scores: Vec<Option<serde_json::Value>>) -> GameAnalysis {
+        let ply = |score: serde_json::Value| -> PlyAnalysis {
+            serde_json::from_value(json!({"depth": 20, "score": score})).unwrap()
+        };
+        GameAnalysis {
+            _id: ObjectId::new(),
+            job_id: JobId(ObjectId::new()),
+            game_id: GameId("abcdefgh".to_string()),
+            source_id: UserId("deepq".to_string()),
+            analysis: scores.into_iter().map(|score| score.map(ply)).collect(),
+            requested_pvs: None,
+            requested_depth: None,
+            requested_nodes: Nodes {
+                nnue: 2_250_000,
+                classical: 4_050_000,
+            },
+            actual_pvs: None,
+            stats: None,
+            engine: None,
+            date_analysed: None,
+            flavor: None,
+        }
+    }
+
+    fn movetext(pgn: &str) -> &str {
+        pgn.lines().last().unwrap()
+    }
+
+    #[test]
+    fn evals_are_from_whites_point_of_view() {
+        let game = game("e2e4 e7e5 f1c4 b8c6 d1h5 g8f6 h5f7", None);
+        // NOTE: black to move after Qh5 and +0.30 for black; white mates in one after Nf6.
+        let mut scores = vec![None; 8];
+        scores[5] = Some(json!({"cp": 30}));
+        scores[6] = Some(json!({"mate": 1}));
+        let pgn = game_pgn(&game, Some(&analysis(scores))).unwrap();
+        assert_eq!(
+            movetext(&pgn),
+            "1. e4 e5 2. Bc4 Nc6 3. Qh5 { [%eval -0.30] } 3... Nf6 { [%eval #1] } 4. Qxf7# *"
+        );
+    }
+
+    #[test]
+    fn mate_for_black_is_negative() {
+        let game = game("f2f3 e7e5 g2g4", None);
+        let mut scores = vec![None; 4];
+        scores[3] = Some(json!({"mate": 1}));
+        let pgn = game_pgn(&game, Some(&analysis(scores))).unwrap();
+        assert_eq!(movetext(&pgn), "1. f3 e5 2. g4 { [%eval #-1] } *");
+    }
+
+    #[test]
+    fn black_to_move_starts_with_its_move_number() {
+        let fen = "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq - 0 1";
+        let pgn = game_pgn(&game("e7e5 g1f3", Some(fen)), None).unwrap();
+        assert!(pgn.contains("[SetUp \"1\"]\n"));
+        assert!(pgn.contains(&format!("[FEN \"{}\"]\n", fen)));
+        assert_eq!(movetext(&pgn), "1... e5 2. Nf3 *");
+    }
+
+    #[test]
+    fn players_default_to_unknown() {
+        let mut game = game("e2e4", None);
+        game.black = None;
+        let pgn = game_pgn(&game, None).unwrap();
+        assert!(pgn.contains("[White \"alice\"]\n"));
+        assert!(pgn.contains("[Black \"?\"]\n"));
+        assert_eq!(movetext(&pgn), "1. e4 *");
+    }
+}
@@ -16,6 +16,7 @@
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
 use std::env::VarError;
+use std::fmt::{Debug, Display};
 use std::num::TryFromIntError;
 
 use mongodb::bson::{
@@ -25,6 +26,7 @@ use mongodb::bson::{
 use mongodb::error::Error as _MongoDBError;
 //use serde::de::{Error as _SerdeDeError};
 
+use log::{error, warn};
 use warp::reject;
 use tokio::task::JoinError;
 
@@ -36,10 +38,43 @@ pub enum HttpError {
     MalformedHeader,
 
     #[error("Unauthenticated")]
-    Unauthenticated,
+    Unauthenticated, // No key, or a key we don't know about
 
     #[error("Forbidden")]
-    Forbidden, // Insufficient permissions
+    RevokedKey,
+
+    #[error("Forbidden")]
+    InsufficientPermission,
+
+    #[error("Conflict")]
+    JobComplete,
+
+    #[error("Conflict")]
+    ReportResolved,
+
+    #[error("Gone")]
+    JobCancelled,
+
+    #[error("Bad Request")]
+    MalformedBody,
+
+    #[error("Payload Too Large")]
+    PayloadTooLarge,
+
+    #[error("Service Unavailable")]
+    Overloaded,
+
+    #[error("Unprocessable Entity")]
+    InvalidReport(Vec<String>),
+
+    #[error("Unprocessable Entity")]
+    InvalidGames(Vec<String>),
+
+    #[error("Unprocessable Entity")]
+    AnalysisLengthMismatch { expected: usize, actual: usize },
+
+    #[error("Upgrade Required")]
+    UpgradeRequired { version: String, minimum: String },
 }
 
 impl reject::Reject for HttpError {}
@@ -50,11 +85,26 @@ pub enum Error {
     #[error("Invalid command line arguments")]
     InvalidCommandLineArguments,
 
+    #[error("Missing configuration value: {0}")]
+    MissingConfig(&'static str),
+
+    #[error("Invalid configuration value for {field}: {value:?}")]
+    InvalidConfig { field: &'static str, value: String },
+
+    #[error("{0} database migrations are pending; start with --run-migrations")]
+    PendingMigrations(usize),
+
+    #[error("Unable to parse the config file")]
+    TomlError(#[from] toml::de::Error),
+
     // #[error("Serde Deserialization Error")]
     // SerdeDeserializationError(#[from] _SerdeDeError),
     #[error("I am somehow unable to create a record in the database.")]
     CreateError,
 
+    /// A record an operation depends on doesn't exist. Lookups report absence as
+    /// `Ok(None)` instead; this is only for writes and actions that need the record,
+    /// and it answers with a 404.
     #[error("I am somehow unable to find a record in the database.")]
     NotFoundError,
 
@@ -78,9 +128,18 @@ pub enum Error {
     #[error("IrwinStreamError")]
     IrwinStreamError(#[from] reqwest::Error),
 
+    #[error("Irwin didn't answer in time")]
+    IrwinTimeout,
+
+    #[error("Callback host {0} isn't publicly reachable")]
+    PrivateCallbackHost(String),
+
     #[error("serde_json Error")]
     SerdeJsonError(#[from] serde_json::Error),
 
+    #[error("rmp_serde::encode::Error")]
+    MsgpackError(#[from] rmp_serde::encode::Error),
+
     #[error("std::io::Error")]
     IoError(#[from] std::io::Error),
 
@@ -93,9 +152,39 @@ pub enum Error {
     #[error("shakmaty::san::SanError")]
     SanError(#[from] shakmaty::san::SanError),
 
+    #[error("shakmaty::uci::ParseUciError")]
+    UciError(#[from] shakmaty::uci::ParseUciError),
+
     #[error("shakmaty::Chess")]
     PositionError,
 
+    #[error("Game {game_id} has {actual} {field} for {expected} moves")]
+    MisalignedGame {
+        game_id: String,
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+
+    #[error("Report {report_id} has no analysis for game {game_id}")]
+    IncompleteReport { report_id: String, game_id: String },
+
+    #[error("Invalid {kind}: {id:?}")]
+    InvalidId { kind: &'static str, id: String },
+
+    #[error("Unsupported variant: {0}")]
+    UnsupportedVariant(String),
+
+    #[error("Game {game_id} has an invalid {field}: {fen:?}")]
+    InvalidFen {
+        game_id: String,
+        field: &'static str,
+        fen: String,
+    },
+
+    #[error("Illegal move {san} at ply {ply}")]
+    IllegalMove { ply: usize, san: String },
+
     #[error("Unable to deserialize something")]
     DeserializationError,
 
@@ -107,8 +196,51 @@ pub enum Error {
 
     #[error("Unable to join tokio task")]
     JoinError(#[from] JoinError),
+
+    #[error("hyper::Error")]
+    HyperError(#[from] warp::hyper::Error),
 }
 
 impl reject::Reject for Error {}
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// For results nobody further up is waiting on, like in background tasks: log the
+/// error along with what we were doing, and carry on with an `Option`.
+pub trait LogErr<T> {
+    fn log_err(self, context: impl Display) -> Option<T>;
+
+    /// Like `log_err`, for errors that are expected now and then.
+    fn ok_or_warn(self, context: impl Display) -> Option<T>;
+}
+
+impl<T, E: Debug> LogErr<T> for std::result::Result<T, E> {
+    fn log_err(self, context: impl Display) -> Option<T> {
+        self.map_err(|err| error!("{}: {:?}", context, err)).ok()
+    }
+
+    fn ok_or_warn(self, context: impl Display) -> Option<T> {
+        self.map_err(|err| warn!("{}: {:?}", context, err)).ok()
+    }
+}
+
+/// `LogErr` for lookups, where a missing record is worth logging as well.
+pub trait LogMissing<T> {
+    fn log_missing(self, context: impl Display) -> Option<T>;
+}
+
+impl<T, E: Debug> LogMissing<T> for std::result::Result<Option<T>, E> {
+    fn log_missing(self, context: impl Display) -> Option<T> {
+        match self {
+            Ok(Some(t)) => Some(t),
+            Ok(None) => {
+                error!("{}: not found", context);
+                None
+            }
+            Err(err) => {
+                error!("{}: {:?}", context, err);
+                None
+            }
+        }
+    }
+}
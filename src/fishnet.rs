@@ -16,12 +16,20 @@
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
 pub mod api;
+pub mod callback;
 pub mod filters;
 pub mod handlers;
 pub mod model;
 
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
 use crate::fishnet::model::JobId;
 use crate::db::DbConn;
+use crate::config::Config;
+use crate::irwin::breaker::CircuitBreaker;
 
 use tokio::sync::broadcast;
 use warp::{
@@ -29,6 +37,13 @@ use warp::{
     reply::Reply,
 };
 
+/// Job lifecycle events, broadcast in process to the irwin listener.
+///
+/// The listener subscribes before the server accepts any traffic, so while the process
+/// is up every event reaches it. If it falls more than `channel_size` events behind, it
+/// rechecks the reports of recently completed jobs instead. Nothing is persisted, so
+/// events in flight when the process dies are lost; the listener rechecks the same
+/// reports when it starts again.
 #[derive(Debug, Clone)]
 pub enum FishnetMsg {
     JobAcquired(JobId),
@@ -36,20 +51,65 @@ pub enum FishnetMsg {
     JobCompleted(JobId),
 }
 
+/// In-memory queue state shared by every handler. It is not persisted, so a
+/// restart always comes back unpaused.
+#[derive(Debug, Clone, Default)]
+pub struct QueueState {
+    paused: Arc<AtomicBool>,
+    draining: Arc<AtomicBool>,
+}
+
+impl QueueState {
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// Set once shutdown has started. Unlike pausing there is no way back: no new work
+    /// is handed out, but analysis for jobs already out is still accepted.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+}
 
 pub struct Actor {
     pub tx: broadcast::Sender<FishnetMsg>,
+    pub state: QueueState,
 }
 
 impl Actor {
     pub fn new(channel_size: usize) -> Actor {
-        // TODO: make the amount of backlog configurable
         let (tx, _) = broadcast::channel(channel_size);
-        Actor {tx}
+        Actor {
+            tx,
+            state: QueueState::default(),
+        }
     }
 
-    pub fn handlers(&self, db: DbConn) -> BoxedFilter<(impl Reply,)> { 
-        handlers::mount(db.clone(), self.tx.clone())
+    pub fn handlers(
+        &self,
+        db: DbConn,
+        config: &Config,
+        breaker: CircuitBreaker,
+        callbacks: reqwest::Client,
+        exports: reqwest::Client,
+    ) -> BoxedFilter<(impl Reply,)> {
+        handlers::mount(
+            db.clone(),
+            self.tx.clone(),
+            self.state.clone(),
+            config,
+            breaker,
+            callbacks,
+            exports,
+        )
     }
 }
 
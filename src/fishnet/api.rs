@@ -16,21 +16,26 @@
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 //
 //
-use chrono::prelude::*;
-use futures::future::Future;
-use std::convert::TryInto;
+use chrono::{prelude::*, Duration};
+use futures::{future::Future, stream::StreamExt};
+use log::debug;
+use std::convert::{TryFrom, TryInto};
+use std::collections::HashMap;
 use std::iter;
 
 use mongodb::bson::{
-    doc, from_document, oid::ObjectId, to_document, Bson, DateTime as BsonDateTime,
+    doc, from_document, oid::ObjectId, to_document, Bson, DateTime as BsonDateTime, Document,
+};
+use mongodb::options::{
+    FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument, UpdateModifications,
 };
-use mongodb::options::{FindOneAndUpdateOptions, UpdateModifications};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde::Serialize;
 
+use crate::config::PrecedenceConfig;
 use crate::db::DbConn;
-use crate::deepq::model::{GameId, UserId, ReportId};
+use crate::deepq::model::{Game, GameAnalysis, GameId, Report, UserId, ReportId};
 use crate::error::{Error, Result};
 use crate::fishnet::model as m;
 
@@ -39,6 +44,10 @@ pub struct CreateApiUser {
     pub user: Option<UserId>,
     pub name: String,
     pub perms: Vec<m::AnalysisType>,
+    pub capabilities: Vec<m::Capability>,
+    pub max_concurrent: Option<i64>,
+    pub daily_node_budget: Option<i64>,
+    pub parallel_acquire: bool,
 }
 
 impl From<CreateApiUser> for m::ApiUser {
@@ -55,6 +64,17 @@ impl From<CreateApiUser> for m::ApiUser {
             user: job.user,
             name: job.name,
             perms: job.perms,
+            revoked: false,
+            is_admin: false,
+            capabilities: job.capabilities,
+            max_concurrent: job.max_concurrent,
+            abort_reasons: HashMap::new(),
+            last_seen: None,
+            last_version: None,
+            daily_node_budget: job.daily_node_budget,
+            nodes_used: 0,
+            nodes_day: None,
+            parallel_acquire: job.parallel_acquire,
         }
     }
 }
@@ -79,32 +99,128 @@ pub async fn get_api_user(db: DbConn, key: m::Key) -> Result<Option<m::ApiUser>>
         .transpose()?)
 }
 
+/// Records that the key was just used; writes at most once a minute per key.
+pub async fn touch_api_user(db: DbConn, api_user: &m::ApiUser) -> Result<()> {
+    let now = db.now();
+    if let Some(last_seen) = api_user.last_seen {
+        if now - last_seen.0 < Duration::minutes(1) {
+            return Ok(());
+        }
+    }
+    m::ApiUser::coll(db)
+        .update_one(
+            doc! {"_id": api_user._id.clone()},
+            UpdateModifications::Document(doc! {"$set": {"last_seen": now}}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Records the version the key's worker reports, when it differs from the last one.
+pub async fn record_version(
+    db: DbConn,
+    api_user: &m::ApiUser,
+    version: &m::FishnetVersion,
+) -> Result<()> {
+    let version = version.to_string();
+    if api_user.last_version.as_ref() == Some(&version) {
+        return Ok(());
+    }
+    m::ApiUser::coll(db)
+        .update_one(
+            doc! {"_id": api_user._id.clone()},
+            UpdateModifications::Document(doc! {"$set": {"last_version": version}}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Adds `nodes` to what the key was handed today, starting the count over on a new day.
+/// False if nothing was charged, e.g. because the key has since been deleted.
+pub async fn charge_nodes(db: DbConn, api_user: &m::ApiUser, nodes: i64) -> Result<bool> {
+    let today = m::start_of_day(db.now());
+    let coll = m::ApiUser::coll(db);
+    // NOTE: two attempts, in case another request started the day between them.
+    for _ in 0..2 {
+        let counted = coll
+            .update_one(
+                doc! {"_id": api_user._id.clone(), "nodes_day": today},
+                UpdateModifications::Document(doc! {"$inc": {"nodes_used": nodes}}),
+                None,
+            )
+            .await?;
+        if counted.matched_count > 0 {
+            return Ok(true);
+        }
+        let started = coll
+            .update_one(
+                doc! {"_id": api_user._id.clone(), "nodes_day": {"$ne": today}},
+                UpdateModifications::Document(doc! {"$set": {
+                    "nodes_day": today,
+                    "nodes_used": nodes,
+                }}),
+                None,
+            )
+            .await?;
+        if started.matched_count > 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
 #[derive(Debug, Clone)]
 pub struct CreateJob {
     pub game_id: GameId,
     pub report_id: Option<ReportId>,
     pub analysis_type: m::AnalysisType,
     pub precedence: i32,
+    pub target_plies: Option<Vec<u32>>,
+    pub color: Option<m::Color>,
+    /// Reuse this earlier job's analysis; the new job starts out complete.
+    pub analysis_from: Option<m::JobId>,
+    pub label: Option<String>,
+    pub callback_url: Option<String>,
+    /// Set aside from the start; see `m::Job::unsupported`.
+    pub unsupported: Option<String>,
 }
 
-impl From<CreateJob> for m::Job {
-    fn from(job: CreateJob) -> m::Job {
+impl CreateJob {
+    /// The job as queued at `now`.
+    pub fn into_job(self, now: DateTime<Utc>) -> m::Job {
         m::Job {
             _id: m::JobId(ObjectId::new()),
-            game_id: job.game_id,
-            report_id: job.report_id,
-            analysis_type: job.analysis_type,
-            precedence: job.precedence,
+            game_id: self.game_id,
+            report_id: self.report_id,
+            analysis_type: self.analysis_type,
+            precedence: self.precedence,
             owner: None,
-            date_last_updated: BsonDateTime(Utc::now()),
-            is_complete: false
+            date_last_updated: BsonDateTime(now),
+            is_complete: self.analysis_from.is_some(),
+            requested: None,
+            last_abort_reason: None,
+            abort_count: 0,
+            failure_count: 0,
+            last_failure_reason: None,
+            quarantined: false,
+            target_plies: self.target_plies,
+            requeue_count: 0,
+            base_precedence: None,
+            cancelled: false,
+            color: self.color,
+            analysis_from: self.analysis_from,
+            label: self.label,
+            callback_url: self.callback_url,
+            unsupported: self.unsupported,
         }
     }
 }
 
 pub async fn insert_one_job(db: DbConn, job: CreateJob) -> Result<ObjectId> {
-    let job_col = m::Job::coll(db);
-    let job: m::Job = job.into();
+    let job_col = m::Job::coll(db.clone());
+    let job = job.into_job(db.now());
     Ok(job_col
         .insert_one(to_document(&job)?, None)
         .await?
@@ -125,17 +241,26 @@ where
         .map(move |job| insert_one_job(db.clone(), job.clone()))
 }
 
-pub async fn assign_job(db: DbConn, api_user: m::ApiUser) -> Result<Option<m::Job>> {
-    let job_col = m::Job::coll(db);
-    Ok(job_col
-        .find_one_and_update(
+/// The latest whole-game job of `analysis_type` for the game completed since `since`,
+/// whose analysis a new job for the same game could reuse.
+pub async fn find_reusable_job(
+    db: DbConn,
+    game_id: GameId,
+    analysis_type: m::AnalysisType,
+    since: DateTime<Utc>,
+) -> Result<Option<m::Job>> {
+    Ok(m::Job::coll(db)
+        .find_one(
             doc! {
-                "owner": Bson::Null,
-                "analysis_type": doc!{ "$in": Bson::Array(api_user.perms.iter().map(Into::into).collect()) },
+                "game_id": game_id,
+                "analysis_type": analysis_type,
+                "is_complete": true,
+                "target_plies": Bson::Null,
+                "color": Bson::Null,
+                "date_last_updated": {"$gte": since},
             },
-            UpdateModifications::Document(doc! {"$set": {"owner": api_user.key.clone()}}),
-            FindOneAndUpdateOptions::builder()
-                .sort(doc! {"precedence": -1, "date_last_updated": 1})
+            FindOneOptions::builder()
+                .sort(doc! {"date_last_updated": -1})
                 .build(),
         )
         .await?
@@ -143,11 +268,354 @@ pub async fn assign_job(db: DbConn, api_user: m::ApiUser) -> Result<Option<m::Jo
         .transpose()?)
 }
 
-pub async fn unassign_job(db: DbConn, api_user: m::ApiUser, id: m::JobId) -> Result<()> {
+/// Queues a game that already has a job for (re)analysis at `analysis_type`, reusing
+/// the stored game and leaving any previous analysis in place. The new job takes the
+/// highest precedence of the game's existing jobs. If an incomplete job of that type
+/// that's still going to be analysed already exists for the game, its id is returned
+/// instead of queueing another.
+pub async fn upgrade_job(
+    db: DbConn,
+    game_id: GameId,
+    analysis_type: m::AnalysisType,
+) -> Result<m::JobId> {
+    let games = Game::coll(db.clone());
+    if games.count_documents(doc! {"_id": game_id.clone()}, None).await? == 0 {
+        return Err(Error::NotFoundError);
+    }
+
+    let job_coll = m::Job::coll(db.clone());
+    let pending = job_coll
+        .find_one(
+            doc! {
+                "game_id": game_id.clone(),
+                "analysis_type": analysis_type.clone(),
+                "is_complete": false,
+                "quarantined": {"$ne": true},
+                "cancelled": {"$ne": true},
+                "unsupported": Bson::Null,
+            },
+            None,
+        )
+        .await?
+        .map(from_document::<m::Job>)
+        .transpose()?;
+    if let Some(pending) = pending {
+        return Ok(pending._id);
+    }
+
+    let highest = job_coll
+        .find_one(
+            doc! {"game_id": game_id.clone()},
+            FindOneOptions::builder().sort(doc! {"precedence": -1}).build(),
+        )
+        .await?
+        .map(from_document::<m::Job>)
+        .transpose()?;
+    let job = CreateJob {
+        game_id,
+        report_id: None,
+        analysis_type,
+        precedence: highest.map_or(0, |job| job.precedence),
+        target_plies: None,
+        color: None,
+        analysis_from: None,
+        label: None,
+        callback_url: None,
+        unsupported: None,
+    };
+    Ok(m::JobId(insert_one_job(db, job).await?))
+}
+
+/// How many times `assign_job` looks for a job while others keep taking them first.
+const ASSIGN_ATTEMPTS: usize = 3;
+
+/// Assigns the highest precedence job the api user is permitted to analyze.
+/// A non-empty `only` further restricts the analysis types to those listed.
+/// Users already holding their `max_concurrent` incomplete jobs get nothing.
+///
+/// Jobs whose worker hasn't acquired or renewed them within `lease` count as timed
+/// out: they may be taken over, at a decayed precedence.
+pub async fn assign_job(
+    db: DbConn,
+    api_user: m::ApiUser,
+    only: &[m::AnalysisType],
+    lease: Duration,
+    precedence: &PrecedenceConfig,
+) -> Result<Option<m::Job>> {
+    let job_col = m::Job::coll(db.clone());
+    if let Some(max_concurrent) = api_user.max_concurrent {
+        let held = job_col
+            .count_documents(
+                doc! {"owner": api_user._id.clone(), "is_complete": false},
+                None,
+            )
+            .await?;
+        if held >= max_concurrent {
+            return Ok(None);
+        }
+    }
+    let analysis_types: Vec<Bson> = api_user
+        .perms
+        .iter()
+        .filter(|perm| only.is_empty() || only.contains(perm))
+        .map(Into::into)
+        .collect();
+    let now = db.now();
+    let available = doc! {
+        "$or": [
+            {"owner": Bson::Null},
+            {"date_last_updated": {"$lt": now - lease}},
+        ],
+        "is_complete": false,
+        "quarantined": {"$ne": true},
+        "cancelled": {"$ne": true},
+        "unsupported": Bson::Null,
+        "analysis_type": doc!{ "$in": Bson::Array(analysis_types) },
+    };
+    // NOTE: another worker can take the job we were about to match between mongo
+    //       picking it and updating it, which comes back as None even though plenty
+    //       of work is queued. Try again a few times before reporting an empty queue.
+    for attempt in 0..ASSIGN_ATTEMPTS {
+        let assigned = job_col
+            .find_one_and_update(
+                available.clone(),
+                UpdateModifications::Document(doc! {"$set": {
+                    "owner": api_user._id.clone(),
+                    "date_last_updated": now,
+                }}),
+                FindOneAndUpdateOptions::builder()
+                    // NOTE: _id breaks ties so every replica hands out jobs in the same order.
+                    .sort(doc! {"precedence": -1, "date_last_updated": 1, "_id": 1})
+                    .build(),
+            )
+            .await?;
+        if let Some(job) = assigned {
+            // NOTE: the job as it was, so a timed out owner can still be seen.
+            let mut job: m::Job = from_document(job)?;
+            let timed_out = job.owner.is_some();
+            job.owner = Some(api_user._id.clone());
+            job.date_last_updated = BsonDateTime(now);
+            if timed_out {
+                debug!("assign_job > {} > lease expired, reassigning", job._id);
+                record_timeout(db, &mut job, precedence).await?;
+            }
+            return Ok(Some(job));
+        }
+        if job_col.count_documents(available.clone(), None).await? == 0 {
+            break;
+        }
+        debug!("assign_job > lost a race on attempt {}, retrying", attempt + 1);
+        tokio::time::sleep(assign_backoff(attempt)).await;
+    }
+    Ok(None)
+}
+
+/// Base delay before `assign_job` tries again; it doubles with each attempt.
+const ASSIGN_BACKOFF_MS: u64 = 10;
+
+/// How long to wait after losing the race on `attempt`. Jittered, so workers that just
+/// lost to each other don't all come back at once and collide again.
+fn assign_backoff(attempt: usize) -> std::time::Duration {
+    let base = ASSIGN_BACKOFF_MS << attempt.min(8);
+    std::time::Duration::from_millis(base + thread_rng().gen_range(0..base))
+}
+
+/// Hands back a job the api user holds. False if it wasn't holding it.
+pub async fn unassign_job(db: DbConn, api_user: m::ApiUser, id: m::JobId) -> Result<bool> {
+    let result = m::Job::coll(db)
+        .update_one(
+            doc! { "_id": id.0, "owner": api_user._id, "is_complete": false },
+            UpdateModifications::Document(doc! {"$set": {"owner": Bson::Null}}),
+            None,
+        )
+        .await?;
+    Ok(result.modified_count == 1)
+}
+
+/// Bumps `date_last_updated` on a job the api user holds, to show it is still being
+/// worked on. That renews its lease, so `assign_job` won't hand it to anyone else.
+pub async fn renew_job(db: DbConn, api_user: &m::ApiUser, id: m::JobId) -> Result<()> {
+    let result = m::Job::coll(db.clone())
+        .update_one(
+            doc! {"_id": id.0, "owner": api_user._id.clone(), "is_complete": false},
+            UpdateModifications::Document(doc! {"$set": {"date_last_updated": db.now()}}),
+            None,
+        )
+        .await?;
+    match result.matched_count {
+        0 => Err(Error::NotFoundError),
+        _ => Ok(()),
+    }
+}
+
+/// Sets a job aside that can't be analysed, releasing it and recording why. It stays
+/// incomplete, but is never handed out again.
+pub async fn mark_unsupported(db: DbConn, id: m::JobId, reason: String) -> Result<()> {
     m::Job::coll(db)
         .update_one(
-            doc! { "_id": id.0, "owner": api_user.key.clone() },
-            UpdateModifications::Document(doc! {"owner": Bson::Null}),
+            doc! {"_id": id.0},
+            UpdateModifications::Document(doc! {"$set": {
+                "unsupported": reason,
+                "owner": Bson::Null,
+            }}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Counts a lapsed lease against a job that's just been taken over, like a failure.
+async fn record_timeout(
+    db: DbConn,
+    job: &mut m::Job,
+    precedence: &PrecedenceConfig,
+) -> Result<()> {
+    m::Job::coll(db.clone())
+        .update_one(
+            doc! {"_id": job._id.0.clone()},
+            UpdateModifications::Document(doc! {"$inc": {"requeue_count": 1}}),
+            None,
+        )
+        .await?;
+    job.requeue_count += 1;
+    decay_precedence(db, job, precedence).await
+}
+
+/// Lowers the precedence of a job that just failed or timed out, per its `requeue_count`,
+/// so a job that keeps coming back can't hold the front of the queue forever.
+async fn decay_precedence(
+    db: DbConn,
+    job: &mut m::Job,
+    precedence: &PrecedenceConfig,
+) -> Result<()> {
+    let base = job.base_precedence.unwrap_or(job.precedence);
+    job.precedence = m::Job::effective_precedence(base, job.requeue_count, precedence);
+    job.base_precedence = Some(base);
+    m::Job::coll(db)
+        .update_one(
+            doc! {"_id": job._id.0.clone()},
+            UpdateModifications::Document(doc! {"$set": {
+                "precedence": job.precedence,
+                "base_precedence": base,
+            }}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Abort reasons become document keys, so keep them to something mongo accepts there.
+fn abort_reason_key(reason: &str) -> String {
+    reason
+        .trim()
+        .to_lowercase()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Notes why a worker gave up on a job, both on the job and in a per-key tally. Only
+/// counts for incomplete jobs the api user holds.
+pub async fn record_abort(
+    db: DbConn,
+    api_user: &m::ApiUser,
+    id: m::JobId,
+    reason: &str,
+) -> Result<()> {
+    let reason = abort_reason_key(reason);
+    let result = m::Job::coll(db.clone())
+        .update_one(
+            doc! {"_id": id.0, "owner": api_user._id.clone(), "is_complete": false},
+            UpdateModifications::Document(doc! {
+                "$set": {"last_abort_reason": reason.clone()},
+                "$inc": {"abort_count": 1},
+            }),
+            None,
+        )
+        .await?;
+    if result.modified_count != 1 {
+        return Ok(());
+    }
+    let mut tally = Document::new();
+    tally.insert(format!("abort_reasons.{}", reason), 1);
+    m::ApiUser::coll(db)
+        .update_one(
+            doc! {"_id": api_user._id.clone()},
+            UpdateModifications::Document(doc! {"$inc": tally}),
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Releases a job the worker couldn't analyze and counts the failure against it. Once
+/// it has failed `max_failures` times it is quarantined so it stops being handed out;
+/// until then it goes back on the queue at a decayed precedence. Returns None if the api
+/// user wasn't holding the job.
+pub async fn record_failure(
+    db: DbConn,
+    api_user: &m::ApiUser,
+    id: m::JobId,
+    reason: &str,
+    max_failures: i32,
+    precedence: &PrecedenceConfig,
+) -> Result<Option<m::Job>> {
+    // NOTE: a pipeline, so the quarantine is decided in the same write as the count.
+    //       Every reference in the stage sees the values from before it.
+    let failures = doc! {"$add": [{"$ifNull": ["$failure_count", 0]}, 1]};
+    let job: Option<m::Job> = m::Job::coll(db.clone())
+        .find_one_and_update(
+            doc! {"_id": id.0.clone(), "owner": api_user._id.clone(), "is_complete": false},
+            UpdateModifications::Pipeline(vec![doc! {
+                "$set": {
+                    "owner": Bson::Null,
+                    "last_failure_reason": {"$literal": reason},
+                    "failure_count": failures.clone(),
+                    "requeue_count": {"$add": [{"$ifNull": ["$requeue_count", 0]}, 1]},
+                    "quarantined": {"$gte": [failures, max_failures]},
+                },
+            }]),
+            FindOneAndUpdateOptions::builder()
+                .return_document(ReturnDocument::After)
+                .build(),
+        )
+        .await?
+        .map(from_document)
+        .transpose()?;
+    match job {
+        Some(job) if job.quarantined => Ok(Some(job)),
+        Some(mut job) => {
+            decay_precedence(db, &mut job, precedence).await?;
+            Ok(Some(job))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Releases every incomplete job held by this api user, returning how many were released.
+/// Like `unassign_job`, handing work back isn't held against the jobs.
+pub async fn unassign_all_jobs(db: DbConn, api_user: m::ApiUser) -> Result<i64> {
+    let result = m::Job::coll(db)
+        .update_many(
+            doc! { "owner": api_user._id, "is_complete": false },
+            UpdateModifications::Document(doc! {"$set": {"owner": Bson::Null}}),
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+pub async fn set_requested_work(
+    db: DbConn,
+    id: m::JobId,
+    requested: m::RequestedWork,
+) -> Result<()> {
+    m::Job::coll(db)
+        .update_one(
+            doc! {"_id": id.0},
+            UpdateModifications::Document(
+                doc! {"$set": {"requested": to_document(&requested)?}},
+            ),
             None,
         )
         .await?;
@@ -163,15 +631,64 @@ pub async fn game_id_for_job_id(db: DbConn, id: m::JobId) -> Result<Option<GameI
         .map(|d: m::Job| d.game_id))
 }
 
-pub async fn set_complete(db: DbConn, id: m::JobId) -> Result<()> {
-    m::Job::coll(db)
+/// Marks the job as complete, returning whether this call is what completed it.
+/// Repeated calls for an already complete job are no-ops and return false.
+pub async fn set_complete(db: DbConn, id: m::JobId) -> Result<bool> {
+    let result = m::Job::coll(db.clone())
         .update_one(
-            doc! {"_id": {"$eq": id.0}},
-            UpdateModifications::Document(doc! {"$set": { "is_complete": true }}),
+            doc! {"_id": {"$eq": id.0}, "is_complete": {"$eq": false}},
+            UpdateModifications::Document(doc! {"$set": {
+                "is_complete": true,
+                "date_last_updated": db.now(),
+            }}),
             None,
         )
         .await?;
-    Ok(())
+    Ok(result.modified_count > 0)
+}
+
+/// Reports with a job completed since `since`.
+pub async fn reports_with_completed_jobs(
+    db: DbConn,
+    since: DateTime<Utc>,
+) -> Result<Vec<ReportId>> {
+    let report_ids = m::Job::coll(db)
+        .distinct(
+            "report_id",
+            doc! {
+                "is_complete": true,
+                "date_last_updated": {"$gte": since},
+                "report_id": {"$ne": Bson::Null},
+            },
+            None,
+        )
+        .await?;
+    Ok(report_ids
+        .into_iter()
+        .filter_map(|report_id| match report_id {
+            Bson::ObjectId(report_id) => Some(ReportId(report_id)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Sets a new precedence by hand, which also starts requeue decay over from it.
+pub async fn set_precedence(db: DbConn, id: m::JobId, precedence: i32) -> Result<()> {
+    let result = m::Job::coll(db)
+        .update_one(
+            doc! {"_id": {"$eq": id.0}, "is_complete": {"$eq": false}},
+            UpdateModifications::Document(doc! {"$set": {
+                "precedence": precedence,
+                "base_precedence": Bson::Null,
+                "requeue_count": 0,
+            }}),
+            None,
+        )
+        .await?;
+    match result.matched_count {
+        0 => Err(Error::NotFoundError),
+        _ => Ok(()),
+    }
 }
 
 pub async fn delete_job(db: DbConn, id: m::JobId) -> Result<()> {
@@ -181,9 +698,93 @@ pub async fn delete_job(db: DbConn, id: m::JobId) -> Result<()> {
     Ok(())
 }
 
+/// Cancels the incomplete jobs of a report, returning how many there were. Workers
+/// already holding one keep it, but their analysis will be refused.
+pub async fn cancel_jobs_for_report(db: DbConn, report_id: ReportId) -> Result<i64> {
+    let result = m::Job::coll(db)
+        .update_many(
+            doc! {"report_id": report_id.0, "is_complete": false},
+            UpdateModifications::Document(doc! {"$set": {"cancelled": true}}),
+            None,
+        )
+        .await?;
+    Ok(result.modified_count)
+}
+
+/// Deletes incomplete, unassigned jobs that haven't been touched within `max_age`.
+///
+/// Jobs that belong to a report are only removed once every job in that report is
+/// older than `max_age`. The report itself goes too once none of its jobs are left,
+/// so a report with completed or assigned jobs is kept. Returns the number of jobs
+/// deleted.
+///
+/// Completed jobs are never removed, which also keeps every job that another job's
+/// `analysis_from` points at.
+pub async fn expire_old_jobs(db: DbConn, max_age: Duration) -> Result<u64> {
+    let cutoff = db.now() - max_age;
+    let job_coll = m::Job::coll(db.clone());
+    let stale = doc! {
+        "is_complete": {"$eq": false},
+        "owner": {"$eq": Bson::Null},
+        "date_last_updated": {"$lt": cutoff},
+    };
+
+    let mut without_report = stale.clone();
+    without_report.insert("report_id", Bson::Null);
+    let mut deleted = job_coll.delete_many(without_report, None).await?.deleted_count;
+
+    for report_id in job_coll.distinct("report_id", stale, None).await? {
+        let report_id = match report_id {
+            Bson::ObjectId(report_id) => report_id,
+            _ => continue,
+        };
+        let recent = job_coll
+            .count_documents(
+                doc! {"report_id": report_id.clone(), "date_last_updated": {"$gte": cutoff}},
+                None,
+            )
+            .await?;
+        if recent > 0 {
+            continue;
+        }
+        let mut report_stale = stale.clone();
+        report_stale.insert("report_id", report_id.clone());
+        deleted += job_coll.delete_many(report_stale, None).await?.deleted_count;
+        let remaining = job_coll
+            .count_documents(doc! {"report_id": report_id.clone()}, None)
+            .await?;
+        if remaining == 0 {
+            Report::coll(db.clone())
+                .delete_one(doc! {"_id": report_id}, None)
+                .await?;
+        }
+    }
+    Ok(deleted.try_into()?)
+}
+
+/// Jobs used to record the raw api key as their owner. Rewrites any such jobs to
+/// reference the ApiUser's _id instead, returning how many were updated. Safe to
+/// run repeatedly.
+pub async fn migrate_job_owners(db: DbConn) -> Result<i64> {
+    let mut migrated = 0;
+    let mut api_users = m::ApiUser::coll(db.clone()).find(doc! {}, None).await?;
+    while let Some(api_user) = api_users.next().await {
+        let api_user: m::ApiUser = from_document(api_user?)?;
+        migrated += m::Job::coll(db.clone())
+            .update_many(
+                doc! {"owner": api_user.key},
+                UpdateModifications::Document(doc! {"$set": {"owner": api_user._id}}),
+                None,
+            )
+            .await?
+            .modified_count;
+    }
+    Ok(migrated)
+}
+
 pub async fn get_user_job(db: DbConn, id: m::JobId, user: m::ApiUser) -> Result<Option<m::Job>> {
     Ok(m::Job::coll(db)
-        .find_one(doc! {"_id": id.0, "owner": user.key}, None)
+        .find_one(doc! {"_id": id.0, "owner": user._id}, None)
         .await?
         .map(from_document)
         .transpose()?)
@@ -197,11 +798,64 @@ pub async fn get_job(db: DbConn, id: m::JobId) -> Result<Option<m::Job>> {
         .transpose()?)
 }
 
+/// Where a game is in the queue, ordered so the most active state compares greatest.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum GameJobState {
+    None,
+    Cancelled,
+    Unsupported,
+    Quarantined,
+    Complete,
+    Queued,
+    Assigned,
+}
+
+impl From<&m::Job> for GameJobState {
+    fn from(job: &m::Job) -> GameJobState {
+        match (job.is_complete, &job.owner) {
+            (true, _) => GameJobState::Complete,
+            // NOTE: these will never be analysed, whatever their owner says.
+            (false, _) if job.cancelled => GameJobState::Cancelled,
+            (false, _) if job.quarantined => GameJobState::Quarantined,
+            (false, _) if job.unsupported.is_some() => GameJobState::Unsupported,
+            (false, Some(_)) => GameJobState::Assigned,
+            (false, None) => GameJobState::Queued,
+        }
+    }
+}
+
+/// The job state of each game, keyed by game id. A game with several jobs reports
+/// its most active one; games without jobs are `None`.
+pub async fn job_states_for_games(
+    db: DbConn,
+    game_ids: &[GameId],
+) -> Result<HashMap<String, GameJobState>> {
+    let mut states: HashMap<String, GameJobState> = game_ids
+        .iter()
+        .map(|game_id| (game_id.0.clone(), GameJobState::None))
+        .collect();
+    let game_ids: Vec<Bson> = game_ids.iter().cloned().map(Into::into).collect();
+    let mut jobs = m::Job::coll(db)
+        .find(doc! {"game_id": {"$in": game_ids}}, None)
+        .await?;
+    while let Some(job) = jobs.next().await {
+        let job: m::Job = from_document(job?)?;
+        let state = GameJobState::from(&job);
+        let entry = states.entry(job.game_id.0).or_insert(GameJobState::None);
+        *entry = (*entry).max(state);
+    }
+    Ok(states)
+}
+
 #[derive(Serialize)]
 pub struct QStatus {
     acquired: u64,
     queued: u64,
     oldest: u64,
+    quarantined: u64,
+    /// Set aside because they can't be analysed, e.g. for their variant.
+    unsupported: u64,
 }
 
 pub async fn q_status(db: DbConn, analysis_type: m::AnalysisType) -> Result<QStatus> {
@@ -213,16 +867,351 @@ pub async fn q_status(db: DbConn, analysis_type: m::AnalysisType) -> Result<QSta
         .try_into()?;
     let oldest = m::Job::oldest_job(db.clone(), analysis_type.clone())
         .await?
-        .map(|job| job.seconds_since_created())
+        .map(|job| job.seconds_since_created(db.now()))
         .unwrap_or(0_i64)
         .try_into()?;
+    let quarantined = m::Job::quarantined_jobs(db.clone(), analysis_type.clone())
+        .await?
+        .try_into()?;
+    let unsupported = m::Job::unsupported_jobs(db.clone(), analysis_type.clone())
+        .await?
+        .try_into()?;
     Ok(QStatus {
         acquired,
         queued,
         oldest,
+        quarantined,
+        unsupported,
+    })
+}
+
+/// Records the current `q_status` of every analysis type in `deepq_queue_samples`.
+pub async fn record_queue_samples(db: DbConn) -> Result<()> {
+    let date = BsonDateTime(db.now());
+    for analysis_type in [
+        m::AnalysisType::UserAnalysis,
+        m::AnalysisType::SystemAnalysis,
+        m::AnalysisType::Deep,
+    ]
+    .iter()
+    {
+        let status = q_status(db.clone(), analysis_type.clone()).await?;
+        let sample = m::QueueSample {
+            _id: ObjectId::new(),
+            date,
+            analysis_type: analysis_type.clone(),
+            acquired: status.acquired.try_into()?,
+            queued: status.queued.try_into()?,
+            oldest: status.oldest.try_into()?,
+            quarantined: status.quarantined.try_into()?,
+        };
+        m::QueueSample::coll(db.clone())
+            .insert_one(to_document(&sample)?, None)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Has mongo drop queue samples once they are older than `retention`. An existing index
+/// with a different retention is left alone and reported as an error.
+pub async fn ensure_queue_sample_ttl(db: DbConn, retention: Duration) -> Result<()> {
+    db.database
+        .run_command(
+            doc! {
+                "createIndexes": "deepq_queue_samples",
+                "indexes": [{
+                    "key": {"date": 1},
+                    "name": "date_ttl",
+                    "expireAfterSeconds": retention.num_seconds(),
+                }],
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+/// Samples of one analysis type taken since `since`, oldest first.
+pub async fn queue_samples(
+    db: DbConn,
+    analysis_type: m::AnalysisType,
+    since: DateTime<Utc>,
+) -> Result<Vec<m::QueueSample>> {
+    let mut cursor = m::QueueSample::coll(db)
+        .find(
+            doc! {"analysis_type": analysis_type, "date": {"$gte": since}},
+            FindOptions::builder().sort(doc! {"date": 1}).build(),
+        )
+        .await?;
+    let mut samples = Vec::new();
+    while let Some(sample) = cursor.next().await {
+        samples.push(from_document(sample?)?);
+    }
+    Ok(samples)
+}
+
+/// Narrows `query_jobs`; fields left unset match every job.
+#[derive(Debug, Clone, Default)]
+pub struct JobFilter {
+    pub analysis_type: Option<m::AnalysisType>,
+    pub owner: Option<ObjectId>,
+    pub complete: Option<bool>,
+    pub label: Option<String>,
+    /// Only jobs last updated at least this long ago.
+    pub older_than: Option<Duration>,
+    /// Only jobs last updated within this long.
+    pub newer_than: Option<Duration>,
+}
+
+impl JobFilter {
+    fn to_document(&self, now: DateTime<Utc>) -> Document {
+        let mut filter = Document::new();
+        if let Some(analysis_type) = &self.analysis_type {
+            filter.insert("analysis_type", analysis_type.clone());
+        }
+        if let Some(owner) = &self.owner {
+            filter.insert("owner", owner.clone());
+        }
+        if let Some(complete) = self.complete {
+            filter.insert("is_complete", complete);
+        }
+        if let Some(label) = &self.label {
+            filter.insert("label", label.clone());
+        }
+        let mut updated = Document::new();
+        if let Some(older_than) = self.older_than {
+            updated.insert("$lte", now - older_than);
+        }
+        if let Some(newer_than) = self.newer_than {
+            updated.insert("$gte", now - newer_than);
+        }
+        if !updated.is_empty() {
+            filter.insert("date_last_updated", updated);
+        }
+        filter
+    }
+}
+
+#[derive(Serialize)]
+pub struct JobPage {
+    /// Jobs matching the filter, not just those on this page.
+    pub total: u64,
+    pub jobs: Vec<m::Job>,
+}
+
+/// A page of the jobs matching `filter`, most recently updated first.
+pub async fn query_jobs(db: DbConn, filter: JobFilter, skip: u64, limit: u64) -> Result<JobPage> {
+    let filter = filter.to_document(db.now());
+    let job_coll = m::Job::coll(db);
+    let total = job_coll
+        .count_documents(filter.clone(), None)
+        .await?
+        .try_into()?;
+    let options = FindOptions::builder()
+        .sort(doc! {"date_last_updated": -1})
+        .skip(i64::try_from(skip)?)
+        .limit(i64::try_from(limit)?)
+        .build();
+    let mut cursor = job_coll.find(filter, options).await?;
+    let mut jobs = Vec::new();
+    while let Some(job) = cursor.next().await {
+        jobs.push(from_document(job?)?);
+    }
+    Ok(JobPage { total, jobs })
+}
+
+/// Indexes backing `query_jobs`: each filter field leads one, followed by the sort key,
+/// so any single filter is answered in order without an in-memory sort.
+pub async fn ensure_job_search_indexes(db: DbConn) -> Result<()> {
+    db.database
+        .run_command(
+            doc! {
+                "createIndexes": "deepq_fishnetjobs",
+                "indexes": [
+                    {"key": {"date_last_updated": -1}, "name": "search_updated"},
+                    {
+                        "key": {"analysis_type": 1, "date_last_updated": -1},
+                        "name": "search_type_updated",
+                    },
+                    {"key": {"owner": 1, "date_last_updated": -1}, "name": "search_owner_updated"},
+                    {
+                        "key": {"is_complete": 1, "date_last_updated": -1},
+                        "name": "search_complete_updated",
+                    },
+                    {
+                        "key": {"label": 1, "date_last_updated": -1},
+                        "name": "search_label_updated",
+                        "sparse": true,
+                    },
+                ],
+            },
+            None,
+        )
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+pub struct WorkerShare {
+    pub key_id: String,
+    pub name: Option<String>,
+    pub acquired: i64,
+    pub completed: i64,
+    /// Fraction of all jobs acquired in the window that went to this key.
+    pub share: f64,
+}
+
+/// How the jobs updated since `since` were spread across keys, busiest first. Keys
+/// seen since then without any jobs are listed with zero.
+pub async fn worker_fairness(db: DbConn, since: DateTime<Utc>) -> Result<Vec<WorkerShare>> {
+    let mut names = HashMap::new();
+    let mut shares: HashMap<ObjectId, (i64, i64)> = HashMap::new();
+    let mut seen = m::ApiUser::coll(db.clone())
+        .find(doc! {"last_seen": {"$gte": since}}, None)
+        .await?;
+    while let Some(api_user) = seen.next().await {
+        let api_user: m::ApiUser = from_document(api_user?)?;
+        shares.insert(api_user._id.clone(), (0, 0));
+        names.insert(api_user._id, api_user.name);
+    }
+    let mut cursor = m::Job::coll(db.clone())
+        .aggregate(
+            vec![
+                doc! {"$match": {
+                    "owner": {"$ne": Bson::Null},
+                    "date_last_updated": {"$gte": since},
+                }},
+                doc! {"$group": {
+                    "_id": "$owner",
+                    "acquired": {"$sum": 1},
+                    "completed": {"$sum": {"$cond": ["$is_complete", 1, 0]}},
+                }},
+            ],
+            None,
+        )
+        .await?;
+    let count = |group: &Document, field: &str| match group.get(field) {
+        Some(Bson::Int32(n)) => i64::from(*n),
+        Some(Bson::Int64(n)) => *n,
+        _ => 0,
+    };
+    while let Some(group) = cursor.next().await {
+        let group = group?;
+        let owner = group.get_object_id("_id")?.clone();
+        shares.insert(owner, (count(&group, "acquired"), count(&group, "completed")));
+    }
+    // NOTE: keys that did work but haven't been seen lately still need a name.
+    let missing: Vec<Bson> = shares
+        .keys()
+        .filter(|owner| !names.contains_key(*owner))
+        .cloned()
+        .map(Bson::ObjectId)
+        .collect();
+    let mut unseen = m::ApiUser::coll(db)
+        .find(doc! {"_id": {"$in": missing}}, None)
+        .await?;
+    while let Some(api_user) = unseen.next().await {
+        let api_user: m::ApiUser = from_document(api_user?)?;
+        names.insert(api_user._id, api_user.name);
+    }
+    let total: i64 = shares.values().map(|(acquired, _)| acquired).sum();
+    let mut fairness: Vec<WorkerShare> = shares
+        .into_iter()
+        .map(|(owner, (acquired, completed))| WorkerShare {
+            key_id: owner.to_hex(),
+            name: names.get(&owner).cloned(),
+            acquired,
+            completed,
+            share: if total > 0 {
+                acquired as f64 / total as f64
+            } else {
+                0.0
+            },
+        })
+        .collect();
+    fairness.sort_by(|a, b| b.acquired.cmp(&a.acquired));
+    Ok(fairness)
+}
+
+#[derive(Serialize)]
+pub struct ThroughputStatus {
+    reports_completed_last_hour: u64,
+    reports_completed_last_day: u64,
+    /// Jobs completed per minute, averaged over the last hour.
+    jobs_per_minute: f64,
+}
+
+pub async fn throughput_status(db: DbConn) -> Result<ThroughputStatus> {
+    let hour_ago = db.now() - Duration::hours(1);
+    let day_ago = db.now() - Duration::days(1);
+    let reports = Report::coll(db.clone());
+    let reports_completed_last_hour = reports
+        .count_documents(doc! {"date_completed": {"$gte": hour_ago}}, None)
+        .await?
+        .try_into()?;
+    let reports_completed_last_day = reports
+        .count_documents(doc! {"date_completed": {"$gte": day_ago}}, None)
+        .await?
+        .try_into()?;
+    // NOTE: jobs reusing earlier analysis start out complete, no worker did anything.
+    let jobs_completed_last_hour = m::Job::coll(db)
+        .count_documents(
+            doc! {
+                "is_complete": true,
+                "analysis_from": Bson::Null,
+                "date_last_updated": {"$gte": hour_ago},
+            },
+            None,
+        )
+        .await?;
+    Ok(ThroughputStatus {
+        reports_completed_last_hour,
+        reports_completed_last_day,
+        jobs_per_minute: jobs_completed_last_hour as f64 / 60.0,
     })
 }
 
+#[derive(Serialize)]
+pub struct EngineCount {
+    name: Option<String>,
+    version: Option<String>,
+    analyses: i64,
+}
+
+/// How many analyses each engine version produced over the last day, most used first.
+/// Workers that don't identify their engine are counted with no name or version.
+pub async fn engine_distribution(db: DbConn) -> Result<Vec<EngineCount>> {
+    let day_ago = db.now() - Duration::days(1);
+    let mut cursor = GameAnalysis::coll(db)
+        .aggregate(
+            vec![
+                doc! {"$match": {"date_analysed": {"$gte": day_ago}}},
+                doc! {"$group": {
+                    "_id": {"name": "$engine.name", "version": "$engine.version"},
+                    "analyses": {"$sum": 1},
+                }},
+                doc! {"$sort": {"analyses": -1}},
+            ],
+            None,
+        )
+        .await?;
+    let mut engines = Vec::new();
+    while let Some(group) = cursor.next().await {
+        let group = group?;
+        let engine = group.get_document("_id")?;
+        engines.push(EngineCount {
+            name: engine.get_str("name").ok().map(String::from),
+            version: engine.get_str("version").ok().map(String::from),
+            analyses: match group.get("analyses") {
+                Some(Bson::Int32(n)) => i64::from(*n),
+                Some(Bson::Int64(n)) => *n,
+                _ => 0,
+            },
+        });
+    }
+    Ok(engines)
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum KeyStatus {
@@ -232,6 +1221,284 @@ pub enum KeyStatus {
 }
 
 pub fn key_status(api_user: Option<m::ApiUser>) -> Option<KeyStatus> {
-    // TODO: Add in appropriate tracking for invalidated keys.
-    api_user.map(|_| KeyStatus::Active)
+    api_user.map(|api_user| match api_user.revoked {
+        true => KeyStatus::Inactive,
+        false => KeyStatus::Active,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::db::{testing::test_db, ManualClock};
+
+    fn create_job(game_id: &str) -> CreateJob {
+        CreateJob {
+            game_id: GameId(game_id.to_string()),
+            report_id: None,
+            analysis_type: m::AnalysisType::SystemAnalysis,
+            precedence: 0,
+            target_plies: None,
+            color: None,
+            analysis_from: None,
+            label: None,
+            callback_url: None,
+            unsupported: None,
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn new_jobs_are_stamped_by_the_clock() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let clock = Arc::new(ManualClock::new(start));
+        let db = test_db("new_jobs_are_stamped_by_the_clock").await.with_clock(clock.clone());
+        let id = insert_one_job(db.clone(), create_job("abcdefgh")).await.unwrap();
+        let job = get_job(db.clone(), m::JobId(id)).await.unwrap().unwrap();
+        assert_eq!(job.date_last_updated.0, start);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn jobs_expire_once_the_clock_passes_their_max_age() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let clock = Arc::new(ManualClock::new(start));
+        let db = test_db("jobs_expire_once_the_clock_passes_their_max_age")
+            .await
+            .with_clock(clock.clone());
+        insert_one_job(db.clone(), create_job("abcdefgh")).await.unwrap();
+
+        clock.advance(Duration::hours(1));
+        assert_eq!(expire_old_jobs(db.clone(), Duration::hours(2)).await.unwrap(), 0);
+
+        clock.advance(Duration::hours(2));
+        assert_eq!(expire_old_jobs(db.clone(), Duration::hours(2)).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn reads_of_missing_jobs_are_none() {
+        let db = test_db("fishnet_reads_of_missing_jobs").await;
+        let id = m::JobId(ObjectId::new());
+        assert!(get_job(db.clone(), id.clone()).await.unwrap().is_none());
+        assert!(game_id_for_job_id(db.clone(), id).await.unwrap().is_none());
+    }
+
+    async fn worker(db: DbConn, name: &str) -> m::ApiUser {
+        let create = CreateApiUser {
+            user: None,
+            name: name.to_string(),
+            perms: vec![m::AnalysisType::SystemAnalysis],
+            capabilities: Vec::new(),
+            max_concurrent: None,
+            daily_node_budget: None,
+            parallel_acquire: false,
+        };
+        create_api_user(db, create).await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn heartbeats_keep_a_job_from_being_reassigned() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let clock = Arc::new(ManualClock::new(start));
+        let db = test_db("heartbeats_keep_a_job_from_being_reassigned")
+            .await
+            .with_clock(clock.clone());
+        let (lease, precedence) = (Duration::minutes(10), PrecedenceConfig::default());
+        let first = worker(db.clone(), "first").await;
+        let second = worker(db.clone(), "second").await;
+        insert_one_job(db.clone(), create_job("abcdefgh")).await.unwrap();
+
+        let assign =
+            |user: &m::ApiUser| assign_job(db.clone(), user.clone(), &[], lease, &precedence);
+        let job = assign(&first).await.unwrap().expect("a queued job");
+        clock.advance(Duration::minutes(6));
+        renew_job(db.clone(), &first, job._id.clone()).await.unwrap();
+        clock.advance(Duration::minutes(6));
+        assert!(assign(&second).await.unwrap().is_none());
+
+        clock.advance(Duration::minutes(5));
+        let taken = assign(&second).await.unwrap().expect("the timed out job");
+        assert_eq!(taken._id.0, job._id.0);
+        assert_eq!(taken.owner, Some(second._id.clone()));
+        assert_eq!(taken.requeue_count, 1);
+        assert!(renew_job(db.clone(), &first, job._id.clone()).await.is_err());
+    }
+
+    #[test]
+    fn assign_backoff_doubles_with_jitter() {
+        for attempt in 0..ASSIGN_ATTEMPTS {
+            let base = ASSIGN_BACKOFF_MS << attempt;
+            let backoff = assign_backoff(attempt).as_millis() as u64;
+            assert!(backoff >= base && backoff < 2 * base, "{} on {}", backoff, attempt);
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn concurrent_assignments_never_share_a_job() {
+        let db = test_db("concurrent_assignments_never_share_a_job").await;
+        let precedence = PrecedenceConfig::default();
+        for i in 0..10 {
+            insert_one_job(db.clone(), create_job(&format!("game{:04}", i))).await.unwrap();
+        }
+        let mut workers = Vec::new();
+        for i in 0..20 {
+            workers.push(worker(db.clone(), &format!("worker{}", i)).await);
+        }
+        let assigned = futures::future::join_all(workers.iter().map(|user| {
+            assign_job(db.clone(), user.clone(), &[], Duration::minutes(10), &precedence)
+        }))
+        .await;
+        let mut ids: Vec<ObjectId> = assigned
+            .into_iter()
+            .filter_map(|job| job.unwrap())
+            .map(|job| job._id.0)
+            .collect();
+        assert_eq!(ids.len(), 10);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 10);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn cancelling_a_report_only_cancels_its_incomplete_jobs() {
+        let db = test_db("cancelling_a_report_only_cancels_its_incomplete_jobs").await;
+        let report_id = ReportId(ObjectId::new());
+        let for_report = |game_id| CreateJob {
+            report_id: Some(report_id.clone()),
+            ..create_job(game_id)
+        };
+        let open = insert_one_job(db.clone(), for_report("aaaaaaaa")).await.unwrap();
+        let done = insert_one_job(db.clone(), for_report("bbbbbbbb")).await.unwrap();
+        set_complete(db.clone(), m::JobId(done.clone())).await.unwrap();
+        let other = insert_one_job(db.clone(), create_job("cccccccc")).await.unwrap();
+
+        assert_eq!(cancel_jobs_for_report(db.clone(), report_id).await.unwrap(), 1);
+        let job = |id| get_job(db.clone(), m::JobId(id));
+        assert!(job(open).await.unwrap().unwrap().cancelled);
+        assert!(!job(done).await.unwrap().unwrap().cancelled);
+        assert!(!job(other).await.unwrap().unwrap().cancelled);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn aborts_only_count_for_jobs_the_key_holds() {
+        let db = test_db("aborts_only_count_for_jobs_the_key_holds").await;
+        let precedence = PrecedenceConfig::default();
+        let holder = worker(db.clone(), "holder").await;
+        let other = worker(db.clone(), "other").await;
+        insert_one_job(db.clone(), create_job("abcdefgh")).await.unwrap();
+        let job = assign_job(db.clone(), holder.clone(), &[], Duration::minutes(10), &precedence)
+            .await
+            .unwrap()
+            .expect("a queued job");
+
+        record_abort(db.clone(), &other, job._id.clone(), "shutdown").await.unwrap();
+        assert!(!unassign_job(db.clone(), other.clone(), job._id.clone()).await.unwrap());
+        let stored = get_job(db.clone(), job._id.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.abort_count, 0);
+        let other = get_api_user(db.clone(), other.key).await.unwrap().unwrap();
+        assert!(other.abort_reasons.is_empty());
+
+        record_abort(db.clone(), &holder, job._id.clone(), "shutdown").await.unwrap();
+        assert!(unassign_job(db.clone(), holder.clone(), job._id.clone()).await.unwrap());
+        let stored = get_job(db.clone(), job._id.clone()).await.unwrap().unwrap();
+        assert_eq!(stored.abort_count, 1);
+        let holder = get_api_user(db.clone(), holder.key).await.unwrap().unwrap();
+        assert_eq!(holder.abort_reasons.get("shutdown"), Some(&1));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn nodes_are_not_charged_to_a_deleted_key() {
+        let db = test_db("nodes_are_not_charged_to_a_deleted_key").await;
+        let api_user = worker(db.clone(), "budgeted").await;
+        assert!(charge_nodes(db.clone(), &api_user, 1_000).await.unwrap());
+        m::ApiUser::coll(db.clone())
+            .delete_one(doc! {"_id": api_user._id.clone()}, None)
+            .await
+            .unwrap();
+        assert!(!charge_nodes(db.clone(), &api_user, 1_000).await.unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn reused_jobs_are_neither_queued_nor_worked() {
+        let db = test_db("reused_jobs_are_neither_queued_nor_worked").await;
+        let earlier = insert_one_job(db.clone(), create_job("abcdefgh")).await.unwrap();
+        set_complete(db.clone(), m::JobId(earlier.clone())).await.unwrap();
+        let reused = CreateJob {
+            analysis_from: Some(m::JobId(earlier)),
+            ..create_job("abcdefgh")
+        };
+        insert_one_job(db.clone(), reused).await.unwrap();
+
+        let analysis_type = m::AnalysisType::SystemAnalysis;
+        assert_eq!(m::Job::queued_jobs(db.clone(), analysis_type.clone()).await.unwrap(), 0);
+        assert!(m::Job::oldest_job(db.clone(), analysis_type).await.unwrap().is_none());
+        let throughput = throughput_status(db.clone()).await.unwrap();
+        assert!((throughput.jobs_per_minute - 1.0 / 60.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn jobs_that_will_never_be_analysed_are_not_pending() {
+        let job = || create_job("abcdefgh").into_job(Utc::now());
+        let state = |job: m::Job| GameJobState::from(&job);
+        assert_eq!(state(job()), GameJobState::Queued);
+        let cancelled = m::Job {
+            cancelled: true,
+            ..job()
+        };
+        assert_eq!(state(cancelled), GameJobState::Cancelled);
+        let quarantined = m::Job {
+            quarantined: true,
+            owner: Some(ObjectId::new()),
+            ..job()
+        };
+        assert_eq!(state(quarantined), GameJobState::Quarantined);
+        let unsupported = m::Job {
+            unsupported: Some("unsupported variant: atomic".to_string()),
+            ..job()
+        };
+        assert_eq!(state(unsupported), GameJobState::Unsupported);
+        let complete = m::Job {
+            is_complete: true,
+            cancelled: true,
+            ..job()
+        };
+        assert_eq!(state(complete), GameJobState::Complete);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn upgrades_queue_a_new_job_past_set_aside_ones() {
+        let db = test_db("upgrades_queue_a_new_job_past_set_aside_ones").await;
+        let game_id = GameId("abcdefgh".to_string());
+        let game = crate::deepq::api::CreateGame {
+            game_id: game_id.clone(),
+            emts: Vec::new(),
+            pgn: Vec::new(),
+            black: None,
+            white: None,
+            variant: None,
+            clock: None,
+            initial_fen: None,
+        };
+        crate::deepq::api::insert_one_game(db.clone(), game).await.unwrap();
+        let queued = insert_one_job(db.clone(), create_job("abcdefgh")).await.unwrap();
+        let analysis_type = m::AnalysisType::SystemAnalysis;
+        let upgrade = || upgrade_job(db.clone(), game_id.clone(), analysis_type.clone());
+        assert_eq!(upgrade().await.unwrap().0, queued);
+
+        let reason = "unsupported variant: atomic".to_string();
+        mark_unsupported(db.clone(), m::JobId(queued.clone()), reason).await.unwrap();
+        let upgraded = upgrade().await.unwrap();
+        assert_ne!(upgraded.0, queued);
+        assert!(!get_job(db.clone(), upgraded).await.unwrap().unwrap().is_set_aside());
+    }
 }
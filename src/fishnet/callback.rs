@@ -0,0 +1,225 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Per-job completion callbacks, for integrators that want each game's analysis as
+//! soon as it is done rather than waiting for the whole report.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use log::{debug, error, warn};
+use reqwest::{redirect, Url};
+use serde::Serialize;
+
+use crate::config::FishnetConfig;
+use crate::db::DbConn;
+use crate::deepq::api::find_analysis_for_job;
+use crate::deepq::model::{GameAnalysis, GameId};
+use crate::error::{Error, Result};
+use crate::fishnet::model as m;
+
+/// What a job's `callback_url` is sent once the job is complete.
+#[derive(Serialize, Debug)]
+struct JobResult<'a> {
+    job_id: String,
+    game_id: &'a GameId,
+    analysis_type: &'a m::AnalysisType,
+    label: &'a Option<String>,
+    analysis: &'a GameAnalysis,
+}
+
+/// The client every callback goes through. Build it once and share it. Redirects
+/// aren't followed, since they could point anywhere.
+pub fn client(settings: &FishnetConfig) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_secs(settings.callback_timeout_secs))
+        .redirect(redirect::Policy::none())
+        .build()?)
+}
+
+/// False for loopback, private, link-local and other addresses that aren't
+/// reachable from the internet.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            let unique_local = first & 0xfe00 == 0xfc00;
+            let link_local = first & 0xffc0 == 0xfe80;
+            let mapped = ip.to_ipv4().map_or(true, |ip| is_public(IpAddr::V4(ip)));
+            !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local) && mapped
+        }
+    }
+}
+
+/// True for the URLs we're willing to call back: http(s), to a host that isn't
+/// obviously one of our own. Names are checked again once resolved, in `post_result`.
+pub fn valid_callback_url(url: &str) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    if !matches!(url.scheme(), "http" | "https") {
+        return false;
+    }
+    let host = match url.host_str() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return false,
+    };
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_public(ip),
+        Err(_) => {
+            let domain = host.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+    }
+}
+
+/// Refuses to call a host that resolves to an address `is_public` rejects.
+async fn check_resolved_host(url: &str) -> Result<()> {
+    let url = Url::parse(url).map_err(|_| Error::PrivateCallbackHost(url.to_string()))?;
+    let host = url.host_str().unwrap_or_default();
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut addrs = tokio::net::lookup_host((host.as_str(), port)).await?;
+    if addrs.any(|addr| !is_public(addr.ip())) {
+        return Err(Error::PrivateCallbackHost(host));
+    }
+    Ok(())
+}
+
+async fn post_result(
+    client: &reqwest::Client,
+    url: &str,
+    job: &m::Job,
+    analysis: &GameAnalysis,
+) -> Result<()> {
+    check_resolved_host(url).await?;
+    let body = JobResult {
+        job_id: job._id.to_string(),
+        game_id: &job.game_id,
+        analysis_type: &job.analysis_type,
+        label: &job.label,
+        analysis,
+    };
+    client
+        .post(url)
+        .header("User-Agent", "lila-deepq")
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn notify(
+    db: DbConn,
+    client: reqwest::Client,
+    settings: FishnetConfig,
+    job: m::Job,
+    url: String,
+) -> Result<()> {
+    let analysis = match find_analysis_for_job(db, job.analysis_job_id()).await? {
+        Some(analysis) => analysis,
+        None => {
+            warn!("callback > {} > no analysis to send", job._id);
+            return Ok(());
+        }
+    };
+    for attempt in 1..=settings.callback_attempts {
+        match post_result(&client, &url, &job, &analysis).await {
+            Ok(()) => {
+                debug!("callback > {} > delivered", job._id);
+                return Ok(());
+            }
+            Err(err @ Error::PrivateCallbackHost(_)) => return Err(err),
+            Err(err) if attempt < settings.callback_attempts => {
+                warn!("callback > {} > attempt {} failed: {:?}", job._id, attempt, err);
+                tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Sends a completed job's analysis to its callback URL, if it has one. Runs in the
+/// background, retrying a few times; a callback that keeps failing is only logged.
+pub fn job_completed(
+    db: DbConn,
+    client: &reqwest::Client,
+    settings: &FishnetConfig,
+    job: &m::Job,
+) {
+    let url = match &job.callback_url {
+        Some(url) => url.clone(),
+        None => return,
+    };
+    let (client, settings, job) = (client.clone(), settings.clone(), job.clone());
+    tokio::spawn(async move {
+        let job_id = job._id.clone();
+        if let Err(err) = notify(db, client, settings, job, url).await {
+            error!("callback > {} > giving up: {:?}", job_id, err);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_public_http_urls() {
+        assert!(valid_callback_url("https://example.org/deepq/done"));
+        assert!(valid_callback_url("http://93.184.216.34:8080/hook"));
+        assert!(valid_callback_url("http://[2606:2800:220:1::1]/hook"));
+    }
+
+    #[test]
+    fn rejects_other_schemes() {
+        assert!(!valid_callback_url("ftp://example.org/hook"));
+        assert!(!valid_callback_url("file:///etc/passwd"));
+        assert!(!valid_callback_url("not a url"));
+    }
+
+    #[test]
+    fn rejects_loopback_and_private_hosts() {
+        for url in &[
+            "http://localhost/hook",
+            "http://LOCALHOST./hook",
+            "http://api.localhost/hook",
+            "http://127.0.0.1/hook",
+            "http://10.1.2.3/hook",
+            "http://172.16.0.1/hook",
+            "http://192.168.1.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://0.0.0.0/hook",
+            "http://[::1]/hook",
+            "http://[fd00::1]/hook",
+            "http://[fe80::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+        ] {
+            assert!(!valid_callback_url(url), "{}", url);
+        }
+    }
+}
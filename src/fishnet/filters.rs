@@ -25,7 +25,9 @@ use warp::{Filter, Rejection};
 use super::{api, model as m};
 use crate::db::DbConn;
 use crate::error::{Error, HttpError};
-use crate::http::{forbidden, with};
+use crate::http::{
+    forbidden, json_body, required_or_unauthenticated, revoked, unauthenticated, with,
+};
 
 #[derive(Debug)]
 pub struct HeaderKey(pub m::Key);
@@ -68,9 +70,13 @@ where
     T: Into<m::Key> + Clone,
 {
     pub async fn new(db: DbConn, val: T) -> StdResult<Authorized<T>, Rejection> {
-        let api_user = api::get_api_user(db, val.clone().into())
+        let api_user = api::get_api_user(db.clone(), val.clone().into())
             .await?
-            .ok_or_else(forbidden)?;
+            .ok_or_else(unauthenticated)?;
+        if api_user.revoked {
+            return Err(revoked());
+        }
+        api::touch_api_user(db, &api_user).await?;
         Ok(Authorized::<T> { val, api_user })
     }
 
@@ -137,14 +143,81 @@ pub fn authentication_from_header(
         .unify()
 }
 
+pub fn header_authorization_required(
+    db: DbConn,
+) -> impl Filter<Extract = (Authorized<m::ApiUser>,), Error = Rejection> + Clone {
+    warp::any()
+        .and(with(db.clone()))
+        .and(api_user_from_header(db).and_then(required_or_unauthenticated))
+        .and_then(authorize)
+}
+
+pub async fn require_capability(
+    api_user: Authorized<m::ApiUser>,
+    capability: m::Capability,
+) -> StdResult<Authorized<m::ApiUser>, Rejection> {
+    match api_user.api_user().has_capability(&capability) {
+        true => Ok(api_user),
+        false => Err(forbidden()),
+    }
+}
+
+pub fn capability_required(
+    db: DbConn,
+    capability: m::Capability,
+) -> impl Filter<Extract = (Authorized<m::ApiUser>,), Error = Rejection> + Clone {
+    header_authorization_required(db)
+        .and(with(capability))
+        .and_then(require_capability)
+}
+
+/// Looks the api user up by the Authorization header's key when one was sent, and by the
+/// key in the body otherwise.
+async fn authorize_header_or_body<T>(
+    db: DbConn,
+    header_key: Option<HeaderKey>,
+    body: T,
+) -> StdResult<(Authorized<m::ApiUser>, T), Rejection>
+where
+    T: Into<m::Key> + Clone,
+{
+    let key: m::Key = match header_key {
+        Some(header_key) => header_key.into(),
+        None => body.clone().into(),
+    };
+    let api_user = api_user_from_key(db.clone(), key)
+        .await?
+        .ok_or_else(unauthenticated)?;
+    Ok((authorize(db, api_user).await?, body))
+}
+
+/// Like `authorized_json_body`, but also accepts a key in the Authorization header,
+/// which wins when both are present. A malformed header is still rejected rather than
+/// falling back to the body.
+pub fn header_or_body_authorization<T>(
+    db: DbConn,
+    limit: u64,
+) -> impl Filter<Extract = (Authorized<m::ApiUser>, T), Error = Rejection> + Clone
+where
+    T: Into<m::Key> + Clone + Send + Sync + DeserializeOwned,
+{
+    warp::any()
+        .and(with(db))
+        .and(warp::header::optional::<HeaderKey>("authorization"))
+        .and(json_body::<T>(limit))
+        .and_then(authorize_header_or_body::<T>)
+        .untuple_one()
+}
+
 pub fn authorized_json_body<T>(
     db: DbConn,
+    limit: u64,
 ) -> impl Filter<Extract = (Authorized<T>,), Error = Rejection> + Clone
 where
     T: Into<m::Key> + Clone + Send + Sync + DeserializeOwned,
 {
     warp::any()
         .and(with(db.clone()))
-        .and(warp::body::json::<T>())
+        .and(json_body::<T>(limit))
         .and_then(authorize::<T>)
 }
@@ -15,40 +15,88 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::num::NonZeroU8;
 use std::result::Result as StdResult;
 use std::convert::{TryFrom, TryInto, Into};
+use std::time::Duration;
 
-use log::{debug, info, error};
+use chrono::{DateTime, Utc};
+use futures::future::try_join_all;
+use futures::stream::{self, Stream};
+use mongodb::bson::{oid::ObjectId, to_document};
+use log::{debug, info, error, warn};
 use serde::{Deserialize, Serialize};
 use serde_with::{
     serde_as, skip_serializing_none, DisplayFromStr, SpaceSeparator, StringWithSeparator,
 };
-use shakmaty::{fen::Fen, uci::Uci};
-use tokio::sync::broadcast;
+use shakmaty::{fen::Fen, san::San, uci::Uci};
+use strum::VariantNames;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{timeout, Instant};
 use warp::{
-    filters::{method, BoxedFilter},
-    http, path, reject,
+    filters::{method, sse, BoxedFilter},
+    http::header::HeaderValue,
+    path,
     reply::{self, Reply},
     Filter, Rejection,
 };
 
-use super::{api, filters as f, model as m, FishnetMsg};
+use super::{api, callback, filters as f, model as m, FishnetMsg, QueueState};
+use crate::config::{Config, FishnetConfig, LichessConfig, PrecedenceConfig};
 use crate::db::DbConn;
 use crate::deepq::api::{
-    find_game, starting_position, upsert_one_game_analysis, UpdateGameAnalysis
+    existing_game_ids, find_analysis_for_game, find_analysis_for_job, find_game, find_report,
+    first_to_move, insert_many_games, insert_one_game, insert_raw_analysis, starting_position,
+    upsert_one_game_analysis, CreateGame, UpdateGameAnalysis,
 };
-use crate::deepq::model::{PlyAnalysis, UserId, Nodes as ModelNodes};
-use crate::http::{json_object_or_no_content, recover, required_or_unauthenticated, with};
-use crate::error::{Error, Result};
+use crate::deepq::metrics::GameAccuracy;
+use crate::deepq::model::{
+    EngineIdentity, Game, GameAnalysis, GameClock, GameId, Nodes as ModelNodes, PlyAnalysis,
+    StockfishFlavor, UserId,
+};
+use crate::deepq::pgn::game_pgn;
+use crate::http::{
+    cors, gzip_if_accepted, handle, json_body, json_object_or_no_content,
+    negotiated_object_or_no_content, optional_json_body, recover, with, Backpressure,
+    IdempotencyCache, KeyedLock,
+};
+use crate::error::{Error, HttpError, LogErr, Result};
+use crate::irwin::api::uci_from_san;
+use crate::irwin::breaker::{BreakerStatus, CircuitBreaker};
+use crate::lichess;
 
 // TODO: make this complete for all of the variant types we should support.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+    strum_macros::EnumString,
+    strum_macros::EnumVariantNames,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
 pub enum Variant {
-    #[serde(rename = "standard")]
     Standard,
 }
 
+impl Variant {
+    /// The variant for a stored game; games from before variants were recorded are
+    /// standard. None for variants we can't analyze.
+    fn for_game(game: &Game) -> Option<Variant> {
+        game.variant.as_deref().unwrap_or(DEFAULT_VARIANT).parse().ok()
+    }
+}
+
+/// Variants we can analyze, by their lichess key. Taken from `Variant`, so it grows as
+/// `Variant` does.
+pub const SUPPORTED_VARIANTS: &[&str] = Variant::VARIANTS;
+
+/// The variant assumed for games that don't name one.
+pub const DEFAULT_VARIANT: &str = "standard";
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum WorkType {
     #[serde(rename = "analysis")]
@@ -57,13 +105,33 @@ pub enum WorkType {
     Move,
 }
 
+#[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RequestInfo {
-    version: String,
-    #[serde(rename = "apikey")]
+    /// Bogus versions fail the whole request as malformed.
+    #[serde_as(as = "DisplayFromStr")]
+    version: m::FishnetVersion,
+    // NOTE: optional for workers that authorize with the Authorization header instead.
+    #[serde(rename = "apikey", default)]
     api_key: m::Key,
 }
 
+impl RequestInfo {
+    /// Rejects workers older than `settings.min_version`, if one is configured.
+    fn check_version(&self, settings: &FishnetConfig) -> Result<()> {
+        match &settings.min_version {
+            Some(minimum) if self.version.is_older_than(minimum) => {
+                Err(HttpError::UpgradeRequired {
+                    version: self.version.to_string(),
+                    minimum: minimum.to_string(),
+                }
+                .into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FishnetRequest {
     fishnet: RequestInfo,
@@ -78,9 +146,66 @@ impl From<FishnetRequest> for m::Key {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AcquireRequest {
     fishnet: RequestInfo,
+    /// Restricts the work handed out to these analysis types, empty means any permitted.
+    #[serde(default)]
+    only: Vec<m::AnalysisType>,
+    /// Set by workers that understand the server-sent event stream at /fishnet/stream.
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    capabilities: Option<Capabilities>,
+    /// How long to hold the request open when there's no work, capped by the server.
+    #[serde(default)]
+    wait_ms: Option<u64>,
+}
+
+impl AcquireRequest {
+    fn wait(&self, settings: &FishnetConfig) -> Duration {
+        Duration::from_millis(self.wait_ms.unwrap_or(0).min(settings.max_acquire_wait_ms))
+    }
+
+    fn flavor(&self) -> Option<StockfishFlavor> {
+        self.capabilities.as_ref().and_then(Capabilities::flavor)
+    }
+
+    fn work_filter(&self) -> WorkFilter {
+        WorkFilter {
+            only: self.only.clone(),
+            flavor: self.flavor(),
+        }
+    }
+}
+
+/// The work a worker asked for: only these analysis types (empty means any permitted),
+/// sized for this engine flavor.
+#[derive(Debug, Clone, Default)]
+struct WorkFilter {
+    only: Vec<m::AnalysisType>,
+    flavor: Option<StockfishFlavor>,
+}
+
+/// What a worker says its engine can do.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Capabilities {
+    /// The flavors the worker can run; empty if it didn't say.
+    #[serde(default)]
+    flavors: Vec<StockfishFlavor>,
+    // NOTE: accepted, but budgets are in nodes so the thread count doesn't change them.
+    #[serde(rename = "maxThreads", default)]
+    max_threads: Option<u32>,
+}
+
+impl Capabilities {
+    /// The flavor to budget for, if the worker can only run one.
+    fn flavor(&self) -> Option<StockfishFlavor> {
+        match self.flavors.as_slice() {
+            [flavor] => Some(*flavor),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Nodes {
     nnue: u64,
     classical: u64,
@@ -99,7 +224,7 @@ impl TryFrom<Nodes> for ModelNodes {
 
 
 #[skip_serializing_none]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct WorkInfo {
     #[serde(rename = "type")]
     _type: WorkType,
@@ -110,7 +235,7 @@ pub struct WorkInfo {
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Job {
     work: WorkInfo,
     game_id: String,
@@ -121,19 +246,35 @@ pub struct Job {
     moves: Vec<Uci>,
 
     #[serde(rename = "skipPositions")]
-    skip_positions: Vec<u8>,
-}
+    skip_positions: Vec<u32>,
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(rename_all = "lowercase")]
-pub enum StockfishFlavor {
-    Nnue,
-    Classical,
+    // NOTE: hints for engines that correlate move times; absent when we don't know them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    clock: Option<GameClock>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    emts: Option<Vec<i32>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StockfishType {
     flavor: StockfishFlavor,
+    // NOTE: older workers only send the flavor.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    version: Option<String>,
+}
+
+impl StockfishType {
+    fn engine(&self) -> Option<EngineIdentity> {
+        match (&self.name, &self.version) {
+            (None, None) => None,
+            (name, version) => Some(EngineIdentity {
+                name: name.clone(),
+                version: version.clone(),
+            }),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -149,51 +290,156 @@ impl From<AnalysisReport> for m::Key {
     }
 }
 impl AnalysisReport {
-    pub fn is_complete(&self) -> bool {
-        self.analysis.iter().filter(|o| o.is_none()).count() == 0_usize
+    /// Field-level problems with the report, empty if it's acceptable.
+    pub fn validate(&self) -> Vec<String> {
+        let mut issues = Vec::new();
+        for (i, ply) in self.analysis.iter().enumerate() {
+            if let Some(ply) = ply {
+                ply.validate(&format!("analysis[{}]", i), &mut issues);
+            }
+        }
+        issues
+    }
+
+    /// Whether every position the job needs has been analysed; with a `color`, only
+    /// the positions that side's moves led to are needed.
+    pub fn is_complete(&self, color: Option<m::Color>, first: m::Color) -> bool {
+        let needed = |ply: usize| color.map_or(true, |c| c.moved_into(ply as u32, first));
+        self.analysis
+            .iter()
+            .enumerate()
+            .all(|(ply, o)| o.is_some() || !needed(ply))
+    }
+}
+
+/// Skipped positions are still sent, as nulls, so there is always one entry per
+/// position of a game of `moves` half-moves. Anything else would misalign every ply
+/// after it.
+fn check_analysis_length(
+    moves: usize,
+    analysis: &[Option<PlyAnalysis>],
+) -> StdResult<(), HttpError> {
+    let expected = moves + 1;
+    if analysis.len() != expected {
+        return Err(HttpError::AnalysisLengthMismatch {
+            expected,
+            actual: analysis.len(),
+        });
     }
+    Ok(())
 }
 
-// TODO: get this from config or env? or lila? (probably lila, tbh)
-fn nodes_for_job(job: &m::Job) -> Nodes {
-    match job.analysis_type {
-        // TODO: what is the default right now for lila's fishnet queue?
-        m::AnalysisType::UserAnalysis => Nodes {
-            nnue: 2_250_000_u64,
-            classical: 4_050_000_u64,
+/// The node budgets for a job. A worker that can only run one flavor gets that flavor's
+/// budget for both, so it can't end up searching with the other's.
+fn nodes_for_job(
+    job: &m::Job,
+    settings: &FishnetConfig,
+    flavor: Option<StockfishFlavor>,
+) -> Nodes {
+    let work = settings.work(&job.analysis_type);
+    match flavor {
+        Some(StockfishFlavor::Nnue) => Nodes {
+            nnue: work.nnue_nodes,
+            classical: work.nnue_nodes,
         },
-        m::AnalysisType::SystemAnalysis => Nodes {
-            nnue: 2_250_000_u64,
-            classical: 4_050_000_u64,
+        Some(StockfishFlavor::Classical) => Nodes {
+            nnue: work.classical_nodes,
+            classical: work.classical_nodes,
         },
-        m::AnalysisType::Deep => Nodes {
-            nnue: 2_500_000_u64,
-            classical: 4_500_000_u64,
+        None => Nodes {
+            nnue: work.nnue_nodes,
+            classical: work.classical_nodes,
         },
     }
 }
 
-// TODO: get this from config or env? or lila? (probably lila, tbh)
-fn multipv_for_job(job: &m::Job) -> Option<NonZeroU8> {
-    match job.analysis_type {
-        m::AnalysisType::Deep => NonZeroU8::new(5u8),
-        _ => None,
+/// What handing out `positions` positions of the requested work costs a key's daily
+/// node budget.
+fn nodes_to_charge(requested: &m::RequestedWork, positions: usize) -> Result<i64> {
+    let positions = i64::try_from(positions)?;
+    Ok(requested
+        .nodes
+        .for_flavor(requested.flavor)
+        .saturating_mul(positions))
+}
+
+fn multipv_for_job(job: &m::Job, settings: &FishnetConfig) -> Option<NonZeroU8> {
+    settings.work(&job.analysis_type).multipv
+}
+
+fn depth_for_job(job: &m::Job, settings: &FishnetConfig) -> Option<u8> {
+    settings.work(&job.analysis_type).depth
+}
+
+/// Jobs with target plies skip every other position of the game, otherwise the
+/// configured positions are skipped. Jobs for one color also skip every position but
+/// the ones that color's moves led to, in a game `first` moved first in.
+fn skip_positions_for_job(
+    job: &m::Job,
+    settings: &FishnetConfig,
+    plies: usize,
+    first: m::Color,
+) -> Vec<u32> {
+    let mut skip: Vec<u32> = match &job.target_plies {
+        Some(targets) => (0..=plies as u32)
+            .filter(|ply| !targets.contains(ply))
+            .collect(),
+        None => settings
+            .work(&job.analysis_type)
+            .skip_positions
+            .iter()
+            .map(|&ply| u32::from(ply))
+            .collect(),
+    };
+    if let Some(color) = job.color {
+        skip.extend((0..=plies as u32).filter(|&ply| !color.moved_into(ply, first)));
+        skip.sort_unstable();
+        skip.dedup();
     }
+    skip
 }
 
-fn depth_for_job(_job: &m::Job) -> Option<u8> {
-    // TODO: Currently none of them request a specific depth, I thought they did?
-    None
+/// Drops anything a worker sent for positions a one color job doesn't need, i.e. any
+/// but the ones that color's moves led to, in a game `first` moved first in.
+fn restrict_to_color(color: m::Color, first: m::Color, analysis: &mut [Option<PlyAnalysis>]) {
+    for (ply, ply_analysis) in analysis.iter_mut().enumerate() {
+        if !color.moved_into(ply as u32, first) {
+            *ply_analysis = Some(PlyAnalysis::skipped());
+        }
+    }
 }
 
-// TODO: get this from config or env? or lila? (probably lila, tbh)
-fn skip_positions_for_job(job: &m::Job) -> Vec<u8> {
-    match job.analysis_type {
-        // TODO: what is the default right now for lila's fishnet queue?
-        m::AnalysisType::UserAnalysis => vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
-        m::AnalysisType::SystemAnalysis => vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
-        m::AnalysisType::Deep => Vec::new(),
+/// Overlays the target plies of a partial analysis onto the game's previous analysis,
+/// so analyzing a few plies doesn't throw away the rest.
+fn merge_target_plies(
+    targets: &[u32],
+    previous: Option<GameAnalysis>,
+    analysis: Vec<Option<PlyAnalysis>>,
+) -> Vec<Option<PlyAnalysis>> {
+    let mut merged = match previous {
+        Some(previous) if previous.analysis.len() == analysis.len() => previous.analysis,
+        _ => return analysis,
+    };
+    for (ply, ply_analysis) in analysis.into_iter().enumerate() {
+        if targets.contains(&(ply as u32)) {
+            merged[ply] = ply_analysis;
+        }
     }
+    merged
+}
+
+fn requested_work_for_job(
+    job: &m::Job,
+    settings: &FishnetConfig,
+    filter: &WorkFilter,
+) -> Result<m::RequestedWork> {
+    Ok(m::RequestedWork {
+        pvs: multipv_for_job(job, settings).map(|v| i32::from(v.get())),
+        depth: depth_for_job(job, settings).map(Into::into),
+        nodes: nodes_for_job(job, settings, filter.flavor).try_into()?,
+        flavor: filter.flavor,
+        only: filter.only.clone(),
+    })
 }
 
 fn send(
@@ -207,24 +453,246 @@ fn send(
     }
 }
 
-async fn acquire_job(
+/// Acquires in progress, by api user.
+type AcquireLock = KeyedLock<ObjectId>;
+
+/// Responses to /analysis, by api user and Idempotency-Key.
+type AnalysisIdempotency = IdempotencyCache<(ObjectId, String), Option<Job>>;
+
+/// Everything the job handlers share, built once in `mount`.
+#[derive(Clone)]
+struct JobContext {
     db: DbConn,
     tx: broadcast::Sender<FishnetMsg>,
+    state: QueueState,
+    settings: FishnetConfig,
+    precedence: PrecedenceConfig,
+    lichess: LichessConfig,
+    backpressure: Backpressure,
+    idempotency: AnalysisIdempotency,
+    acquiring: AcquireLock,
+    callbacks: reqwest::Client,
+    exports: reqwest::Client,
+}
+
+/// Set on the 204 a key gets once it has used up its daily node budget.
+const QUOTA_HEADER: &str = "x-deepq-quota";
+
+struct Acquired {
+    job: Option<Job>,
+    quota_exhausted: bool,
+}
+
+impl From<Option<Job>> for Acquired {
+    fn from(job: Option<Job>) -> Acquired {
+        Acquired {
+            job,
+            quota_exhausted: false,
+        }
+    }
+}
+
+async fn acquired_reply(
+    acquired: Acquired,
+    accept: Option<String>,
+) -> StdResult<reply::Response, Rejection> {
+    let mut response = negotiated_object_or_no_content(acquired.job, accept).await?;
+    if acquired.quota_exhausted {
+        response
+            .headers_mut()
+            .insert(QUOTA_HEADER, HeaderValue::from_static("exhausted"));
+    }
+    Ok(response)
+}
+
+async fn acquire_job(
+    ctx: JobContext,
     api_user: f::Authorized<m::ApiUser>,
-) -> StdResult<Option<Job>, Rejection> {
+    request: Option<AcquireRequest>,
+) -> Result<Acquired> {
+    let JobContext {
+        db,
+        tx,
+        state,
+        settings,
+        backpressure,
+        acquiring,
+        ..
+    } = &ctx;
     let api_user = api_user.val();
     info!("acquire_job > {}", api_user.name);
+    if let Some(request) = &request {
+        request.fishnet.check_version(&settings)?;
+        api::record_version(db.clone(), &api_user, &request.fishnet.version).await?;
+    }
+    if api_user.node_budget_exhausted(db.now()) {
+        debug!("acquire_job > {} > out of nodes for today", api_user.name);
+        return Ok(Acquired {
+            job: None,
+            quota_exhausted: true,
+        });
+    }
+    let wait = request.as_ref().map_or(Duration::from_secs(0), |r| r.wait(&settings));
+    // NOTE: a long poll keeps the key locked for as long as it waits.
+    let _lock = if api_user.parallel_acquire {
+        None
+    } else {
+        match acquiring.try_lock_for(api_user._id.clone(), wait) {
+            Some(lock) => Some(lock),
+            None => {
+                debug!("acquire_job > {} > already acquiring", api_user.name);
+                return Ok(Acquired::from(None));
+            }
+        }
+    };
+    let deadline = Instant::now() + wait;
+    let filter = request.map(|r| r.work_filter()).unwrap_or_default();
+    // NOTE: subscribe before looking so an abort in between still wakes us.
+    let mut rx = tx.subscribe();
+    loop {
+        let job = {
+            let _permit = backpressure.try_acquire()?;
+            next_job(&ctx, api_user.clone(), &filter).await?
+        };
+        let now = Instant::now();
+        if job.is_some() || state.is_draining() || now >= deadline {
+            return Ok(Acquired::from(job));
+        }
+        if !wait_for_work(&mut rx, (deadline - now).min(STREAM_POLL_INTERVAL)).await {
+            return Ok(Acquired::from(None));
+        }
+    }
+}
+
+/// Waits up to `max` for a job to be handed back. Newly queued jobs aren't broadcast,
+/// so callers look again once `max` is up either way. False once the channel closed.
+async fn wait_for_work(rx: &mut broadcast::Receiver<FishnetMsg>, max: Duration) -> bool {
+    let until = Instant::now() + max;
+    loop {
+        let left = until.saturating_duration_since(Instant::now());
+        match timeout(left, rx.recv()).await {
+            Ok(Ok(FishnetMsg::JobAborted(_))) | Ok(Err(RecvError::Lagged(_))) | Err(_) => {
+                return true
+            }
+            // NOTE: acquiring or completing a job doesn't make any more work available.
+            Ok(Ok(FishnetMsg::JobAcquired(_))) | Ok(Ok(FishnetMsg::JobCompleted(_))) => {}
+            Ok(Err(RecvError::Closed)) => return false,
+        }
+    }
+}
+
+/// Finds the job's game, fetching it from lichess if it's missing and the job's
+/// report origin is one we export for.
+async fn find_or_export_game(
+    db: DbConn,
+    exports: &reqwest::Client,
+    lichess: &LichessConfig,
+    job: &m::Job,
+) -> Result<Option<Game>> {
+    if let Some(game) = find_game(db.clone(), job.game_id.clone()).await? {
+        return Ok(Some(game));
+    }
+    let report = match job.report_id.clone() {
+        Some(report_id) => find_report(db.clone(), report_id).await?,
+        None => None,
+    };
+    if !report.map_or(false, |r| lichess.export_missing.contains(&r.origin)) {
+        return Ok(None);
+    }
+    info!("find_or_export_game > exporting {}", job.game_id);
+    match lichess::export_game(exports, lichess, &job.game_id).await? {
+        Some(game) => {
+            insert_one_game(db.clone(), game).await?;
+            find_game(db, job.game_id.clone()).await
+        }
+        None => Ok(None),
+    }
+}
+
+/// Sets aside a job whose game we can't analyze, e.g. for its variant, so it isn't
+/// handed out again only to fail.
+async fn skip_unsupported(db: DbConn, job: &m::Job, reason: String) -> Result<()> {
+    warn!("next_job > {} > {}", job._id, reason);
+    api::mark_unsupported(db, job._id.clone(), reason).await
+}
+
+/// Whether finding the job's game failed because the game lichess exported can't be
+/// stored as we need it, which exporting it again won't change.
+fn is_unusable_export(err: &Error) -> bool {
+    match err {
+        Error::UnsupportedVariant(_)
+        | Error::InvalidFen { .. }
+        | Error::IllegalMove { .. }
+        | Error::InvalidId { .. }
+        | Error::MisalignedGame { .. } => true,
+        Error::IrwinStreamError(err) => err.is_decode(),
+        _ => false,
+    }
+}
+
+/// Assigns the next job to the api user and builds the work payload for it.
+async fn next_job(
+    ctx: &JobContext,
+    api_user: m::ApiUser,
+    filter: &WorkFilter,
+) -> Result<Option<Job>> {
+    let JobContext {
+        db,
+        tx,
+        state,
+        settings,
+        precedence,
+        lichess,
+        exports,
+        ..
+    } = ctx;
+    let WorkFilter { only, flavor } = filter;
+    let flavor = *flavor;
+    if state.is_paused() || state.is_draining() {
+        debug!("next_job > paused or draining");
+        return Ok(None);
+    }
+    if api_user.daily_node_budget.is_some() {
+        // NOTE: streams and follow-up jobs hold on to the key as it was, so look again.
+        let current = api::get_api_user(db.clone(), api_user.key.clone()).await?;
+        if current.map_or(false, |u| u.node_budget_exhausted(db.now())) {
+            debug!("next_job > {} > out of nodes for today", api_user.name);
+            return Ok(None);
+        }
+    }
     // TODO: Multiple active jobs are allowed. Instead we should unassign old ones that
     //       are not finished.
     // NOTE: not using .map because of unstable async lambdas
     debug!("start");
-    Ok(match api::assign_job(db.clone(), api_user.clone()).await? {
+    let lease = settings.lease();
+    let assigned = api::assign_job(db.clone(), api_user.clone(), only, lease, precedence).await?;
+    Ok(match assigned {
         Some(job) => {
             debug!("Some(job) = {:?}", job);
-            let game = match find_game(db.clone(), job.game_id.clone()).await {
+            let game = match find_or_export_game(db.clone(), exports, lichess, &job).await {
                 Ok(game) => Ok(game),
+                Err(err) if is_unusable_export(&err) => {
+                    skip_unsupported(db.clone(), &job, err.to_string()).await?;
+                    return Ok(None);
+                }
+                // NOTE: counted as a failure so a game lichess keeps failing to export
+                //       sinks down the queue, and is quarantined in the end.
+                Err(Error::IrwinStreamError(err)) => {
+                    warn!("next_job > {} > lichess export failed: {}", job._id, err);
+                    let reason = format!("lichess export failed: {}", err);
+                    api::record_failure(
+                        db.clone(),
+                        &api_user,
+                        job._id.clone(),
+                        &reason,
+                        settings.max_job_failures,
+                        precedence,
+                    )
+                    .await?;
+                    return Ok(None);
+                }
                 Err(err) => {
-                    api::unassign_job(db.clone(), api_user, job._id.clone()).await?;
+                    api::unassign_job(db.clone(), api_user.clone(), job._id.clone()).await?;
                     Err(err)
                 }
             }?;
@@ -235,23 +703,46 @@ async fn acquire_job(
                     // TODO: I don't yet understand recursion in an async function in Rust.
                     None // acquire_job(db.clone(), api_user.clone())?
                 }
+                Some(game) if Variant::for_game(&game).is_none() => {
+                    let variant = game.variant.unwrap_or_default();
+                    let reason = format!("unsupported variant: {}", variant);
+                    skip_unsupported(db.clone(), &job, reason).await?;
+                    None
+                }
                 Some(game) => {
+                    let requested = requested_work_for_job(&job, settings, filter)?;
+                    let first = m::Color::from(first_to_move(&game)?);
+                    let plies = game.pgn.len();
+                    let skip_positions = skip_positions_for_job(&job, settings, plies, first);
+                    if api_user.daily_node_budget.is_some() {
+                        let positions = (game.pgn.len() + 1).saturating_sub(skip_positions.len());
+                        let nodes = nodes_to_charge(&requested, positions)?;
+                        if !api::charge_nodes(db.clone(), &api_user, nodes).await? {
+                            warn!("next_job > {} > unable to charge {} nodes", job._id, nodes);
+                            api::unassign_job(db.clone(), api_user.clone(), job._id.clone())
+                                .await?;
+                            return Ok(None);
+                        }
+                    }
+                    api::set_requested_work(db.clone(), job._id.clone(), requested).await?;
                     send(
-                        tx,
+                        tx.clone(),
                         FishnetMsg::JobAcquired(job._id.clone())
                     );
                     let job = Job {
                         game_id: job.game_id.to_string(),
                         position: starting_position(game.clone()),
-                        variant: Variant::Standard,
-                        skip_positions: skip_positions_for_job(&job),
+                        variant: Variant::for_game(&game).unwrap_or(Variant::Standard),
+                        skip_positions,
+                        clock: game.clock.clone(),
+                        emts: Some(game.emts.clone()).filter(|emts| !emts.is_empty()),
                         moves: game.pgn,
                         work: WorkInfo {
                             id: job._id.to_string(),
                             _type: WorkType::Analysis,
-                            nodes: nodes_for_job(&job).try_into()?,
-                            multipv: multipv_for_job(&job),
-                            depth: depth_for_job(&job),
+                            nodes: nodes_for_job(&job, settings, flavor).try_into()?,
+                            multipv: multipv_for_job(&job, settings),
+                            depth: depth_for_job(&job, settings),
                         },
                     };
                     Some(job)
@@ -262,61 +753,733 @@ async fn acquire_job(
     })
 }
 
+/// How long a streaming worker with nothing to do waits before we look at the queue again.
+/// New jobs are inserted by the irwin listener, which doesn't share our channel.
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The job currently held by a streaming worker. If the connection drops before the
+/// analysis arrives, the job is released back into the queue.
+struct HeldJob {
+    db: DbConn,
+    api_user: m::ApiUser,
+    job_id: Option<m::JobId>,
+}
+
+impl Drop for HeldJob {
+    fn drop(&mut self) {
+        if let Some(job_id) = self.job_id.take() {
+            let db = self.db.clone();
+            let api_user = self.api_user.clone();
+            tokio::spawn(async move {
+                info!("stream_jobs > releasing {}", job_id);
+                if let Err(err) = api::unassign_job(db, api_user, job_id).await {
+                    error!("Unable to release streamed job: {:?}", err);
+                }
+            });
+        }
+    }
+}
+
+struct JobStream {
+    ctx: JobContext,
+    rx: broadcast::Receiver<FishnetMsg>,
+    filter: WorkFilter,
+    held: HeldJob,
+}
+
+impl JobStream {
+    /// Waits until the held job is completed or aborted. Returns false if the channel closed.
+    async fn wait_for_release(&mut self, job_id: &m::JobId) -> bool {
+        match timeout(STREAM_POLL_INTERVAL, self.rx.recv()).await {
+            Ok(Ok(FishnetMsg::JobCompleted(id))) | Ok(Ok(FishnetMsg::JobAborted(id)))
+                if id.0 == job_id.0 =>
+            {
+                self.held.job_id = None;
+            }
+            Ok(Err(RecvError::Closed)) => return false,
+            // NOTE: We may have missed the message, so go look at the job itself.
+            Ok(Err(RecvError::Lagged(_))) | Err(_) => {
+                match api::get_job(self.held.db.clone(), job_id.clone()).await {
+                    Ok(Some(job))
+                        if job.is_complete || job.owner.as_ref() != Some(&self.held.api_user._id) =>
+                    {
+                        self.held.job_id = None;
+                    }
+                    Ok(None) => self.held.job_id = None,
+                    Ok(Some(_)) => {}
+                    Err(err) => error!("Unable to check streamed job: {:?}", err),
+                }
+            }
+            Ok(Ok(_)) => {}
+        }
+        true
+    }
+}
+
+fn job_events(
+    job_stream: JobStream,
+) -> impl Stream<Item = StdResult<sse::Event, serde_json::Error>> {
+    stream::unfold(job_stream, |mut s| async move {
+        loop {
+            if let Some(job_id) = s.held.job_id.clone() {
+                if !s.wait_for_release(&job_id).await {
+                    return None;
+                }
+                continue;
+            }
+            let job = next_job(&s.ctx, s.held.api_user.clone(), &s.filter).await;
+            match job {
+                Ok(Some(job)) => {
+                    s.held.job_id = job.work.id.parse().ok();
+                    let event = sse::Event::default().event("job").json_data(&job);
+                    return Some((event, s));
+                }
+                // NOTE: end idle streams so they don't hold up a graceful shutdown.
+                Ok(None) if s.ctx.state.is_draining() => return None,
+                Ok(None) => {
+                    let woken = timeout(STREAM_POLL_INTERVAL, s.rx.recv()).await;
+                    if let Ok(Err(RecvError::Closed)) = woken {
+                        return None;
+                    }
+                }
+                Err(err) => {
+                    error!("job_events > {:?}", err);
+                    return None;
+                }
+            }
+        }
+    })
+}
+
+/// Streams job assignments to a worker as server-sent events, one job at a time.
+/// The next job is sent once the previous one is completed (via /analysis) or aborted.
+async fn stream_jobs(
+    ctx: JobContext,
+    api_user: f::Authorized<m::ApiUser>,
+    request: Option<AcquireRequest>,
+) -> Result<impl Reply> {
+    let request = request
+        .filter(|r| r.stream)
+        .ok_or(HttpError::MalformedBody)?;
+    request.fishnet.check_version(&ctx.settings)?;
+    let api_user = api_user.val();
+    info!("stream_jobs > {}", api_user.name);
+    api::record_version(ctx.db.clone(), &api_user, &request.fishnet.version).await?;
+    let rx = ctx.tx.subscribe();
+    let events = job_events(JobStream {
+        rx,
+        filter: request.work_filter(),
+        held: HeldJob {
+            db: ctx.db.clone(),
+            api_user,
+            job_id: None,
+        },
+        ctx,
+    });
+    Ok(sse::reply(sse::keep_alive().stream(events)))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AbortRequest {
+    /// Why the worker gave up, e.g. "timeout", "engine_crash" or "oom".
+    #[serde(default)]
+    reason: Option<String>,
+}
+
 async fn abort_job(
     db: DbConn,
     tx: broadcast::Sender<FishnetMsg>,
     api_user: f::Authorized<m::ApiUser>,
     job_id: m::JobId,
-) -> StdResult<Option<()>, Rejection> {
+    request: Option<AbortRequest>,
+) -> Result<Option<()>> {
     let api_user = api_user.val();
     info!("abort_job > {}", api_user.name);
-    api::unassign_job(db.clone(), api_user, job_id.clone()).await?;
-    send(tx, FishnetMsg::JobAborted(job_id));
+    if let Some(reason) = request.and_then(|r| r.reason) {
+        api::record_abort(db.clone(), &api_user, job_id.clone(), &reason).await?;
+    }
+    // NOTE: giving work back isn't a failure, so the job keeps its precedence.
+    if api::unassign_job(db.clone(), api_user, job_id.clone()).await? {
+        send(tx, FishnetMsg::JobAborted(job_id));
+    }
     Ok(None) // None because we're going to return no-content
 }
 
-/// TODO: Not sure I'm checking to ensure that the job is "done"
-/// TODO: Need to mark job as done if it is done and update report.
-async fn save_job_analysis(
+/// Lets a worker that's still busy with a long analysis say so, by bumping the job's
+/// `date_last_updated`. Only the key holding the job may renew it.
+async fn job_heartbeat(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    job_id: m::JobId,
+) -> Result<Option<()>> {
+    let api_user = api_user.val();
+    debug!("job_heartbeat > {} > {:?}", api_user.name, job_id);
+    let job = api::get_job(db.clone(), job_id.clone())
+        .await?
+        .ok_or(Error::NotFoundError)?;
+    if job.owner.as_ref() != Some(&api_user._id) {
+        return Err(HttpError::InsufficientPermission.into());
+    }
+    if job.is_complete {
+        return Err(HttpError::JobComplete.into());
+    }
+    api::renew_job(db, &api_user, job_id).await?;
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FailureReport {
+    /// What went wrong, e.g. "engine_error".
+    reason: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JobFailed {
+    failure_count: i32,
+    quarantined: bool,
+}
+
+async fn report_job_failure(
     db: DbConn,
     tx: broadcast::Sender<FishnetMsg>,
+    settings: FishnetConfig,
+    precedence: PrecedenceConfig,
+    job_id: m::JobId,
     api_user: f::Authorized<m::ApiUser>,
+    report: FailureReport,
+) -> Result<JobFailed> {
+    let api_user = api_user.val();
+    info!(
+        "report_job_failure > {} > {:?} > {}",
+        api_user.name, job_id, report.reason
+    );
+    let job = api::record_failure(
+        db,
+        &api_user,
+        job_id.clone(),
+        &report.reason,
+        settings.max_job_failures,
+        &precedence,
+    )
+    .await?
+    .ok_or(Error::NotFoundError)?;
+    if job.quarantined {
+        warn!("report_job_failure > {:?} quarantined", job_id);
+    }
+    send(tx, FishnetMsg::JobAborted(job_id));
+    Ok(JobFailed {
+        failure_count: job.failure_count,
+        quarantined: job.quarantined,
+    })
+}
+
+#[derive(Serialize, Debug)]
+pub struct AbortAll {
+    released: i64,
+}
+
+async fn abort_all_jobs(db: DbConn, api_user: f::Authorized<m::ApiUser>) -> Result<AbortAll> {
+    let api_user = api_user.val();
+    info!("abort_all_jobs > {}", api_user.name);
+    let released = api::unassign_all_jobs(db, api_user).await?;
+    Ok(AbortAll { released })
+}
+
+/// TODO: Not sure I'm checking to ensure that the job is "done"
+/// TODO: Need to mark job as done if it is done and update report.
+async fn save_job_analysis(
+    ctx: JobContext,
+    idempotency_key: Option<String>,
     job_id: m::JobId,
+    api_user: f::Authorized<m::ApiUser>,
     report: AnalysisReport,
-) -> StdResult<Option<Job>, Rejection> {
+) -> Result<Option<Job>> {
+    let JobContext {
+        db,
+        tx,
+        settings,
+        backpressure,
+        idempotency,
+        acquiring,
+        callbacks,
+        ..
+    } = &ctx;
     let api_user = api_user.val();
     info!("save_job_analysis > {:?} > {:?}", api_user.name, job_id);
+    let idempotency_key = idempotency_key.map(|key| (api_user._id.clone(), key));
+    if let Some(result) = idempotency_key.as_ref().and_then(|key| idempotency.get(key)) {
+        debug!("save_job_analysis > replaying {:?}", idempotency_key);
+        return Ok(result);
+    }
+    report.fishnet.check_version(&settings)?;
+    api::record_version(db.clone(), &api_user, &report.fishnet.version).await?;
+    let issues = report.validate();
+    if !issues.is_empty() {
+        return Err(HttpError::InvalidReport(issues).into());
+    }
+    let _permit = backpressure.acquire().await;
 
     let job = api::get_user_job(db.clone(), job_id.clone().into(), api_user.clone())
         .await?
-        .ok_or(reject::not_found())?;
+        .ok_or(Error::NotFoundError)?;
     debug!("save_job_analysis > get_user_job > success");
+    if !api_user.perms.contains(&job.analysis_type) {
+        return Err(HttpError::InsufficientPermission.into());
+    }
+    if job.cancelled {
+        info!("save_job_analysis > {:?} > cancelled, discarding", job_id);
+        return Err(HttpError::JobCancelled.into());
+    }
+    let game = find_game(db.clone(), job.game_id.clone()).await?;
+    if let Some(game) = &game {
+        check_analysis_length(game.pgn.len(), &report.analysis)?;
+    }
+    // NOTE: a game that's gone can't tell us, but games start with white to move unless
+    //       they're from a position.
+    let first = match &game {
+        Some(game) => m::Color::from(first_to_move(game)?),
+        None => m::Color::White,
+    };
 
+    // NOTE: jobs acquired before we started recording this fall back to the current settings.
+    let requested = match job.requested.clone() {
+        Some(requested) => requested,
+        None => requested_work_for_job(&job, &settings, &WorkFilter::default())?,
+    };
+    let flavor = requested.flavor;
+    // NOTE: the follow-up job is for the same worker, so it asks for the same work.
+    let filter = WorkFilter {
+        only: requested.only.clone(),
+        flavor,
+    };
+    let mut stored_analysis = match &job.target_plies {
+        Some(targets) => {
+            let previous = find_analysis_for_game(db.clone(), job.game_id.clone()).await?;
+            merge_target_plies(targets, previous, report.analysis.clone())
+        }
+        None => report.analysis.clone(),
+    };
+    if let Some(color) = job.color {
+        restrict_to_color(color, first, &mut stored_analysis);
+    }
+    if let Some(max_moves) = settings.work(&job.analysis_type).max_stored_pv_moves {
+        stored_analysis
+            .iter_mut()
+            .flatten()
+            .for_each(|ply| ply.truncate_pvs(max_moves));
+    }
     let analysis = UpdateGameAnalysis {
         job_id: job_id.into(),
         game_id: job.clone().game_id.into(),
-        analysis: report.analysis.clone(),
+        analysis: stored_analysis,
         source_id: UserId(api_user._id.to_string()),
-        requested_pvs: multipv_for_job(&job).map(|v| i32::from(v.get())),
-        requested_depth: depth_for_job(&job).map(Into::into),
-        requested_nodes: nodes_for_job(&job).try_into()?,
+        requested_pvs: requested.pvs,
+        requested_depth: requested.depth,
+        requested_nodes: requested.nodes,
+        engine: report.stockfish.engine(),
+        flavor: flavor.or(Some(report.stockfish.flavor)),
     };
     debug!("save_job_analysis > created UpdateGameAnalysis");
     upsert_one_game_analysis(db.clone(), analysis).await?;
     debug!("save_job_analysis > upsert_one_game_analysis > success");
-    if report.is_complete() {
-        debug!("save_job_analysis > JobCompleted");
-        api::set_complete(db, job._id.clone()).await?;
-        send(tx, FishnetMsg::JobCompleted(job._id.clone()));
+    if settings.store_raw_reports {
+        let mut raw = report.clone();
+        raw.fishnet.api_key = m::Key::default();
+        // NOTE: raw reports are only kept for debugging, so losing one mustn't lose the
+        //       analysis that was already stored.
+        let stored = match to_document(&raw) {
+            Ok(raw) => {
+                let (job_id, game_id) = (job._id.clone(), job.game_id.clone());
+                insert_raw_analysis(db.clone(), job_id, game_id, api_user._id.clone(), raw).await
+            }
+            Err(err) => Err(err.into()),
+        };
+        stored.log_err(format!("save_job_analysis > {:?} > unable to store raw report", job._id));
     }
-    Ok(None)
+    let result = if report.is_complete(job.color, first) {
+        if api::set_complete(db.clone(), job._id.clone()).await? {
+            debug!("save_job_analysis > JobCompleted");
+            send(tx.clone(), FishnetMsg::JobCompleted(job._id.clone()));
+            callback::job_completed(db.clone(), callbacks, &settings, &job);
+        }
+        // NOTE: the job is complete by now, so it can't be handed straight back. Like an
+        //       acquire, the follow-up takes the key's acquire lock; while an acquire holds
+        //       it, there's no follow-up, so the key doesn't end up with two jobs.
+        let lock = match api_user.parallel_acquire {
+            true => None,
+            false => acquiring.try_lock(api_user._id.clone()),
+        };
+        if api_user.parallel_acquire || lock.is_some() {
+            next_job(&ctx, api_user, &filter).await?
+        } else {
+            debug!("save_job_analysis > {} > acquiring elsewhere", api_user.name);
+            None
+        }
+    } else {
+        None
+    };
+    if let Some(key) = idempotency_key {
+        idempotency.insert(key, result.clone());
+    }
+    Ok(result)
+}
+
+async fn get_job_analysis(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    job_id: m::JobId,
+) -> Result<GameAnalysis> {
+    info!("get_job_analysis > {} > {:?}", api_user.val().name, job_id);
+    let job = api::get_job(db.clone(), job_id)
+        .await?
+        .ok_or(Error::NotFoundError)?;
+    find_analysis_for_job(db, job.analysis_job_id())
+        .await?
+        .ok_or(Error::NotFoundError)
+}
+
+async fn get_game(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    game_id: GameId,
+) -> Result<Game> {
+    info!("get_game > {} > {}", api_user.val().name, game_id);
+    find_game(db, game_id).await?.ok_or(Error::NotFoundError)
+}
+
+async fn get_game_accuracy(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    game_id: GameId,
+) -> Result<GameAccuracy> {
+    info!("get_game_accuracy > {} > {}", api_user.val().name, game_id);
+    let game = find_game(db.clone(), game_id.clone())
+        .await?
+        .ok_or(Error::NotFoundError)?;
+    let analysis = find_analysis_for_game(db, game_id)
+        .await?
+        .ok_or(Error::NotFoundError)?;
+    GameAccuracy::from_analysis(&game, &analysis)
+}
+
+#[derive(Deserialize, Debug)]
+struct PgnQuery {
+    #[serde(default)]
+    annotated: bool,
+}
+
+async fn get_game_pgn(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    game_id: GameId,
+    query: PgnQuery,
+) -> Result<String> {
+    info!("get_game_pgn > {} > {}", api_user.val().name, game_id);
+    let game = find_game(db.clone(), game_id.clone())
+        .await?
+        .ok_or(Error::NotFoundError)?;
+    let analysis = if query.annotated {
+        let analysis = find_analysis_for_game(db, game_id).await?;
+        Some(analysis.ok_or(Error::NotFoundError)?)
+    } else {
+        None
+    };
+    game_pgn(&game, analysis.as_ref())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Reanalyze {
+    analysis_type: m::AnalysisType,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Reanalyzed {
+    job_id: String,
+}
+
+async fn reanalyze_game(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    game_id: GameId,
+    body: Reanalyze,
+) -> Result<Reanalyzed> {
+    info!(
+        "reanalyze_game > {} > {} > {:?}",
+        api_user.val().name,
+        game_id,
+        body.analysis_type
+    );
+    let job_id = api::upgrade_job(db, game_id, body.analysis_type).await?;
+    Ok(Reanalyzed {
+        job_id: job_id.to_string(),
+    })
+}
+
+const MAX_ENQUEUE_GAMES: usize = 256;
+
+/// A game to analyse outside of any report.
+#[serde_as]
+#[derive(Deserialize, Debug, Clone)]
+pub struct EnqueueGame {
+    game_id: GameId,
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, San>")]
+    pgn: Vec<San>,
+    analysis_type: m::AnalysisType,
+    /// Defaults to the configured `enqueued` precedence.
+    #[serde(default)]
+    precedence: Option<i32>,
+    /// Tags the job so a batch can be found again with `jobs/search?label=`.
+    #[serde(default)]
+    label: Option<String>,
+    /// Sent the game's analysis once its job is complete.
+    #[serde(default)]
+    callback_url: Option<String>,
+}
+
+impl TryFrom<&EnqueueGame> for CreateGame {
+    type Error = Error;
+
+    fn try_from(g: &EnqueueGame) -> Result<CreateGame> {
+        g.game_id.0.parse::<GameId>()?;
+        Ok(CreateGame {
+            game_id: g.game_id.clone(),
+            emts: Vec::new(),
+            pgn: uci_from_san(&g.pgn)?,
+            black: None,
+            white: None,
+            variant: Some(DEFAULT_VARIANT.to_string()),
+            clock: None,
+            initial_fen: None,
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Enqueued {
+    job_ids: Vec<String>,
+}
+
+/// Stores the games and queues a job for each, with no report. Nothing is queued
+/// unless every game is valid and new; games already stored are queued again with
+/// /game/{id}/reanalyze instead, which doesn't overwrite them.
+async fn enqueue_games(
+    db: DbConn,
+    precedence: PrecedenceConfig,
+    api_user: f::Authorized<m::ApiUser>,
+    games: Vec<EnqueueGame>,
+) -> Result<Enqueued> {
+    info!("enqueue_games > {} > {} games", api_user.val().name, games.len());
+    if games.is_empty() || games.len() > MAX_ENQUEUE_GAMES {
+        return Err(HttpError::MalformedBody.into());
+    }
+    let mut issues = Vec::new();
+    let mut create_games = Vec::new();
+    for (i, game) in games.iter().enumerate() {
+        match CreateGame::try_from(game) {
+            Ok(create_game) => create_games.push(create_game),
+            Err(err) => issues.push(format!("games[{}]: {}", i, err)),
+        }
+        if !game.callback_url.as_deref().map_or(true, callback::valid_callback_url) {
+            issues.push(format!("games[{}]: invalid callback_url", i));
+        }
+        if games[..i].iter().any(|other| other.game_id.0 == game.game_id.0) {
+            issues.push(format!("games[{}]: duplicate game_id", i));
+        }
+    }
+    let game_ids: Vec<GameId> = games.iter().map(|game| game.game_id.clone()).collect();
+    for existing in existing_game_ids(db.clone(), &game_ids).await? {
+        for (i, _) in games.iter().enumerate().filter(|(_, g)| g.game_id.0 == existing.0) {
+            issues.push(format!("games[{}]: {} already exists", i, existing));
+        }
+    }
+    if !issues.is_empty() {
+        return Err(HttpError::InvalidGames(issues).into());
+    }
+    try_join_all(insert_many_games(db.clone(), create_games.into_iter())).await?;
+    let jobs: Vec<api::CreateJob> = games
+        .into_iter()
+        .map(|game| api::CreateJob {
+            game_id: game.game_id,
+            report_id: None,
+            analysis_type: game.analysis_type,
+            precedence: game.precedence.unwrap_or(precedence.enqueued),
+            target_plies: None,
+            color: None,
+            analysis_from: None,
+            label: game.label,
+            callback_url: game.callback_url,
+            unsupported: None,
+        })
+        .collect();
+    let job_ids = try_join_all(api::insert_many_jobs(db, jobs.iter().by_ref())).await?;
+    Ok(Enqueued {
+        job_ids: job_ids.iter().map(ObjectId::to_hex).collect(),
+    })
+}
+
+/// The stored job record, for diagnosing queue stalls.
+async fn get_job_record(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    job_id: m::JobId,
+) -> Result<m::Job> {
+    info!("get_job_record > {} > {:?}", api_user.val().name, job_id);
+    api::get_job(db, job_id).await?.ok_or(Error::NotFoundError)
+}
+
+const DEFAULT_SEARCH_JOBS: u64 = 50;
+const MAX_SEARCH_JOBS: u64 = 500;
+/// Ages past this are as good as "ever", and keep date arithmetic from overflowing.
+const MAX_SEARCH_AGE_SECS: i64 = 100 * 365 * 24 * 60 * 60;
+
+/// Ages in a search are seconds back from now, so a negative one is a mistake.
+fn search_age(secs: Option<i64>) -> Result<Option<chrono::Duration>> {
+    match secs {
+        Some(secs) if secs < 0 => Err(HttpError::MalformedBody.into()),
+        Some(secs) => Ok(Some(chrono::Duration::seconds(secs.min(MAX_SEARCH_AGE_SECS)))),
+        None => Ok(None),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct JobSearchQuery {
+    #[serde(rename = "type")]
+    analysis_type: Option<m::AnalysisType>,
+    owner: Option<String>,
+    complete: Option<bool>,
+    label: Option<String>,
+    /// Only jobs last updated at least this many seconds ago.
+    older_than_secs: Option<i64>,
+    /// Only jobs last updated within this many seconds.
+    newer_than_secs: Option<i64>,
+    #[serde(default)]
+    skip: u64,
+    limit: Option<u64>,
 }
 
-async fn check_key_validity(db: DbConn, key: String) -> StdResult<String, Rejection> {
-    api::get_api_user(db, key.into())
+async fn search_jobs(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    query: JobSearchQuery,
+) -> Result<api::JobPage> {
+    info!("search_jobs > {} > {:?}", api_user.val().name, query);
+    let owner = match query.owner {
+        Some(owner) => match ObjectId::with_string(&owner) {
+            Ok(owner) => Some(owner),
+            Err(_) => return Err(HttpError::MalformedBody.into()),
+        },
+        None => None,
+    };
+    let filter = api::JobFilter {
+        analysis_type: query.analysis_type,
+        owner,
+        complete: query.complete,
+        label: query.label,
+        older_than: search_age(query.older_than_secs)?,
+        newer_than: search_age(query.newer_than_secs)?,
+    };
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_JOBS)
+        .min(MAX_SEARCH_JOBS)
+        .max(1);
+    api::query_jobs(db, filter, query.skip, limit).await
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SetPrecedence {
+    precedence: i32,
+}
+
+async fn set_job_precedence(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    job_id: m::JobId,
+    body: SetPrecedence,
+) -> Result<Option<()>> {
+    let api_user = api_user.val();
+    info!(
+        "set_job_precedence > {} > {:?} > {}",
+        api_user.name, job_id, body.precedence
+    );
+    let job = api::get_job(db.clone(), job_id.clone())
         .await?
-        .ok_or_else(reject::not_found)
-        .map(|_| String::new())
+        .ok_or(Error::NotFoundError)?;
+    if job.is_complete {
+        return Err(HttpError::JobComplete.into());
+    }
+    api::set_precedence(db, job_id, body.precedence).await?;
+    Ok(None)
+}
+
+#[derive(Serialize)]
+pub struct KeyValidity {
+    status: api::KeyStatus,
+}
+
+/// 404 for keys we've never issued, otherwise whether the key is usable.
+async fn check_key_validity(db: DbConn, key: String) -> Result<KeyValidity> {
+    let api_user = api::get_api_user(db, key.into()).await?;
+    api::key_status(api_user)
+        .ok_or(Error::NotFoundError)
+        .map(|status| KeyValidity { status })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidateKeys {
+    keys: Vec<m::Key>,
+}
+
+async fn validate_keys(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    body: ValidateKeys,
+) -> Result<HashMap<String, api::KeyStatus>> {
+    info!("validate_keys > {} > {} keys", api_user.val().name, body.keys.len());
+    let mut statuses = HashMap::new();
+    for key in body.keys {
+        let api_user = api::get_api_user(db.clone(), key.clone()).await?;
+        let status = api::key_status(api_user).unwrap_or(api::KeyStatus::Unknown);
+        statuses.insert(key.0, status);
+    }
+    Ok(statuses)
+}
+
+#[derive(Serialize, Debug)]
+pub struct Paused {
+    paused: bool,
+}
+
+async fn set_paused(
+    state: QueueState,
+    api_user: f::Authorized<m::ApiUser>,
+    paused: bool,
+) -> Result<Paused> {
+    info!("set_paused > {} > {}", api_user.val().name, paused);
+    state.set_paused(paused);
+    Ok(Paused { paused })
+}
+
+/// The most games a single /status/games request may ask about.
+const MAX_STATUS_GAMES: usize = 256;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GamesStatus {
+    games: Vec<GameId>,
+}
+
+async fn games_status(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    body: GamesStatus,
+) -> Result<HashMap<String, api::GameJobState>> {
+    info!("games_status > {} > {} games", api_user.val().name, body.games.len());
+    if body.games.len() > MAX_STATUS_GAMES
+        || body.games.iter().any(|g| g.0.parse::<GameId>().is_err())
+    {
+        return Err(HttpError::MalformedBody.into());
+    }
+    api::job_states_for_games(db, &body.games).await
 }
 
 #[derive(Serialize)]
@@ -331,19 +1494,83 @@ struct FishnetAnalysisStatus {
 struct FishnetStatus {
     analysis: FishnetAnalysisStatus,
     key: Option<api::KeyStatus>,
+    /// The requesting key's aborts, by reason.
+    aborts: Option<HashMap<String, i64>>,
+    paused: bool,
+    draining: bool,
+    throughput: api::ThroughputStatus,
+    /// Analyses received in the last day, by engine.
+    engines: Vec<api::EngineCount>,
+    irwin: BreakerStatus,
 }
 
 async fn fishnet_status(
     db: DbConn,
+    state: QueueState,
+    breaker: CircuitBreaker,
     api_user: Option<m::ApiUser>,
-) -> StdResult<FishnetStatus, Rejection> {
+) -> Result<FishnetStatus> {
     info!("status");
     let user = api::q_status(db.clone(), m::AnalysisType::UserAnalysis).await?;
     let system = api::q_status(db.clone(), m::AnalysisType::SystemAnalysis).await?;
     let deep = api::q_status(db.clone(), m::AnalysisType::Deep).await?;
     let key = api::key_status(api_user.clone());
+    let aborts = api_user.map(|api_user| api_user.abort_reasons);
+    let throughput = api::throughput_status(db.clone()).await?;
+    let engines = api::engine_distribution(db.clone()).await?;
     let analysis = FishnetAnalysisStatus { user, system, deep };
-    Ok(FishnetStatus { analysis, key })
+    Ok(FishnetStatus {
+        analysis,
+        key,
+        aborts,
+        paused: state.is_paused(),
+        draining: state.is_draining(),
+        throughput,
+        engines,
+        irwin: breaker.status(db.now()),
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct HistoryQuery {
+    #[serde(rename = "type")]
+    analysis_type: m::AnalysisType,
+    /// Defaults to a day ago.
+    since: Option<DateTime<Utc>>,
+}
+
+async fn status_history(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    query: HistoryQuery,
+) -> Result<Vec<m::QueueSample>> {
+    info!(
+        "status_history > {} > {}",
+        api_user.val().name,
+        query.analysis_type
+    );
+    let since = query
+        .since
+        .unwrap_or_else(|| db.now() - chrono::Duration::days(1));
+    api::queue_samples(db, query.analysis_type, since).await
+}
+
+#[derive(Deserialize, Debug)]
+struct FairnessQuery {
+    /// Defaults to a day ago.
+    since: Option<DateTime<Utc>>,
+}
+
+async fn fairness_status(
+    db: DbConn,
+    api_user: f::Authorized<m::ApiUser>,
+    query: FairnessQuery,
+) -> Result<Vec<api::WorkerShare>> {
+    info!("fairness_status > {}", api_user.val().name);
+    let since = query
+        .since
+        .unwrap_or_else(|| db.now() - chrono::Duration::days(1));
+    api::worker_fairness(db, since).await
 }
 
 fn _log_body() -> impl Filter<Extract = (), Error = Rejection> + Copy {
@@ -354,14 +1581,37 @@ fn _log_body() -> impl Filter<Extract = (), Error = Rejection> + Copy {
         .untuple_one()
 }
 
-pub fn mount(db: DbConn, tx: broadcast::Sender<FishnetMsg>) -> BoxedFilter<(impl Reply,)> {
-    let authenticated = f::api_user_from_header(db.clone());
-    let authentication_required = authenticated.clone().and_then(required_or_unauthenticated);
-
-    let header_authorization_required = warp::any()
-        .and(with(db.clone()))
-        .and(authentication_required.clone())
-        .and_then(f::authorize);
+pub fn mount(
+    db: DbConn,
+    tx: broadcast::Sender<FishnetMsg>,
+    state: QueueState,
+    config: &Config,
+    breaker: CircuitBreaker,
+    callbacks: reqwest::Client,
+    exports: reqwest::Client,
+) -> BoxedFilter<(impl Reply,)> {
+    let backpressure = Backpressure::new(
+        config.webserver.max_db_concurrency,
+        config.webserver.reserved_for_completions,
+    );
+    let body_limit = config.webserver.max_body_bytes;
+    let ctx = JobContext {
+        db: db.clone(),
+        tx: tx.clone(),
+        state: state.clone(),
+        settings: config.fishnet.clone(),
+        precedence: config.precedence.clone(),
+        lichess: config.lichess.clone(),
+        backpressure,
+        idempotency: AnalysisIdempotency::new(Duration::from_secs(
+            config.webserver.idempotency_ttl_secs,
+        )),
+        acquiring: AcquireLock::new(Duration::from_millis(config.fishnet.acquire_lock_ms)),
+        callbacks,
+        exports,
+    };
+    let header_authorization_required = f::header_authorization_required(db.clone());
+    let capability_required = |capability| f::capability_required(db.clone(), capability);
 
     // NOTE: this supports the old fishnet 1.x style of authorization
     //       which I am not going to worry about supporting out of the box.
@@ -373,11 +1623,19 @@ pub fn mount(db: DbConn, tx: broadcast::Sender<FishnetMsg>) -> BoxedFilter<(impl
 
     let acquire = path("acquire")
         .and(method::post())
-        .and(with(db.clone()))
-        .and(with(tx.clone()))
+        .and(with(ctx.clone()))
         .and(header_authorization_required.clone())
-        .and_then(acquire_job)
-        .and_then(json_object_or_no_content::<Job>);
+        .and(optional_json_body(body_limit))
+        .and_then(|ctx, api_user, request| handle(acquire_job(ctx, api_user, request)))
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(acquired_reply);
+
+    let stream = path("stream")
+        .and(method::get())
+        .and(with(ctx.clone()))
+        .and(header_authorization_required.clone())
+        .and(optional_json_body(body_limit))
+        .and_then(|ctx, api_user, request| handle(stream_jobs(ctx, api_user, request)));
 
     let abort = path("abort")
         .and(method::post())
@@ -385,42 +1643,476 @@ pub fn mount(db: DbConn, tx: broadcast::Sender<FishnetMsg>) -> BoxedFilter<(impl
         .and(with(tx.clone()))
         .and(header_authorization_required.clone())
         .and(path::param())
-        .and_then(abort_job)
+        .and(optional_json_body(body_limit))
+        .and_then(|db, tx, api_user, job_id, request| {
+            handle(abort_job(db, tx, api_user, job_id, request))
+        })
+        .and_then(json_object_or_no_content::<()>);
+
+    let heartbeat = path("job")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and(path::param())
+        .and(path("heartbeat"))
+        .and(path::end())
+        .and_then(|db, api_user, job_id| handle(job_heartbeat(db, api_user, job_id)))
         .and_then(json_object_or_no_content::<()>);
 
+    let abort_all = path("abort-all")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and_then(|db, api_user| handle(abort_all_jobs(db, api_user)))
+        .map(|released| reply::json(&released));
+
     let analysis = path("analysis")
+        .and(method::post())
+        .and(with(ctx.clone()))
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(path::param())
+        .and(path::end())
+        .and(f::header_or_body_authorization(db.clone(), body_limit))
+        .and_then(|ctx, idempotency_key, job_id, api_user, report| {
+            handle(save_job_analysis(ctx, idempotency_key, job_id, api_user, report))
+        })
+        .and(warp::header::optional::<String>("accept"))
+        .and_then(negotiated_object_or_no_content::<Job>);
+
+    let failed = path("analysis")
         .and(method::post())
         .and(with(db.clone()))
         .and(with(tx.clone()))
+        .and(with(config.fishnet.clone()))
+        .and(with(config.precedence.clone()))
+        // NOTE: match the path before authorizing, or posts to /analysis/{id} that authorize
+        //       in the body would answer with this route's missing header rejection.
+        .and(path::param())
+        .and(path("failed"))
+        .and(path::end())
+        .and(header_authorization_required.clone())
+        .and(json_body(body_limit))
+        .and_then(|db, tx, settings, precedence, job_id, api_user, report| {
+            handle(report_job_failure(db, tx, settings, precedence, job_id, api_user, report))
+        })
+        .map(|failed| reply::json(&failed));
+
+    let job_analysis = path("analysis")
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and(path::param())
+        .and_then(|db, api_user, job_id| handle(get_job_analysis(db, api_user, job_id)))
+        .map(|analysis| reply::json(&analysis));
+
+    let game = path("game")
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and(path::param())
+        .and(path::end())
+        .and_then(|db, api_user, game_id| handle(get_game(db, api_user, game_id)))
+        .map(|game| reply::json(&game));
+
+    let accuracy = path("game")
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and(path::param())
+        .and(path("accuracy"))
+        .and_then(|db, api_user, game_id| handle(get_game_accuracy(db, api_user, game_id)))
+        .map(|accuracy| reply::json(&accuracy));
+
+    let pgn = path("game")
+        .and(method::get())
+        .and(with(db.clone()))
         .and(header_authorization_required.clone())
         .and(path::param())
-        .and(warp::body::json())
-        .and_then(save_job_analysis)
-        .and_then(json_object_or_no_content::<Job>);
+        .and(path("pgn"))
+        .and(path::end())
+        .and(warp::query::<PgnQuery>())
+        .and_then(|db, api_user, game_id, query| {
+            handle(get_game_pgn(db, api_user, game_id, query))
+        })
+        .map(|pgn| reply::with_header(pgn, "content-type", "application/x-chess-pgn"));
+
+    let reanalyze = path("game")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(capability_required(m::Capability::Reprioritize))
+        .and(path::param())
+        .and(path("reanalyze"))
+        .and(json_body(body_limit))
+        .and_then(|db, api_user, game_id, body| {
+            handle(reanalyze_game(db, api_user, game_id, body))
+        })
+        .map(|reanalyzed| reply::json(&reanalyzed));
+
+    let enqueue = path("enqueue")
+        .and(path::end())
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(with(config.precedence.clone()))
+        .and(capability_required(m::Capability::EnqueueGames))
+        .and(json_body(body_limit))
+        .and_then(|db, precedence, api_user, games| {
+            handle(enqueue_games(db, precedence, api_user, games))
+        })
+        .map(|enqueued| reply::json(&enqueued));
+
+    let job_record = path("job")
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(capability_required(m::Capability::Audit))
+        .and(path::param())
+        .and_then(|db, api_user, job_id| handle(get_job_record(db, api_user, job_id)))
+        .map(|job| reply::json(&job));
+
+    let job_search = path("jobs")
+        .and(path("search"))
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(capability_required(m::Capability::Audit))
+        .and(warp::query::<JobSearchQuery>())
+        .and_then(|db, api_user, query| handle(search_jobs(db, api_user, query)))
+        .map(|page| reply::json(&page));
+
+    let precedence = path("jobs")
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(capability_required(m::Capability::Reprioritize))
+        .and(path::param())
+        .and(path("precedence"))
+        .and(json_body(body_limit))
+        .and_then(|db, api_user, job_id, body| {
+            handle(set_job_precedence(db, api_user, job_id, body))
+        })
+        .and_then(json_object_or_no_content::<()>);
 
     let valid_key = path("key")
         .and(method::get())
         .and(with(db.clone()))
         .and(path::param())
-        .and_then(check_key_validity);
+        .and_then(|db, key| handle(check_key_validity(db, key)))
+        .map(|validity| reply::json(&validity));
+
+    let validate = path("keys")
+        .and(path("validate"))
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(capability_required(m::Capability::ManageKeys))
+        .and(json_body(body_limit))
+        .and_then(|db, api_user, body| handle(validate_keys(db, api_user, body)))
+        .map(|statuses| reply::json(&statuses));
+
+    let pause = path("pause")
+        .and(method::post())
+        .and(with(state.clone()))
+        .and(capability_required(m::Capability::ManageQueue))
+        .and(with(true))
+        .and_then(|state, api_user, paused| handle(set_paused(state, api_user, paused)))
+        .map(|paused| reply::json(&paused));
+
+    let resume = path("resume")
+        .and(method::post())
+        .and(with(state.clone()))
+        .and(capability_required(m::Capability::ManageQueue))
+        .and(with(false))
+        .and_then(|state, api_user, paused| handle(set_paused(state, api_user, paused)))
+        .map(|paused| reply::json(&paused));
+
+    let games_status = path("status")
+        .and(path("games"))
+        .and(method::post())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and(json_body(body_limit))
+        .and_then(|db, api_user, body| handle(games_status(db, api_user, body)))
+        .map(|statuses| reply::json(&statuses));
+
+    let status_history = path("status")
+        .and(path("history"))
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(header_authorization_required.clone())
+        .and(warp::query::<HistoryQuery>())
+        .and_then(|db, api_user, query| handle(status_history(db, api_user, query)))
+        .map(|samples| reply::json(&samples));
+
+    let fairness = path("fairness")
+        .and(path::end())
+        .and(method::get())
+        .and(with(db.clone()))
+        .and(capability_required(m::Capability::Audit))
+        .and(warp::query::<FairnessQuery>())
+        .and_then(|db, api_user, query| handle(fairness_status(db, api_user, query)))
+        .map(|shares| reply::json(&shares));
 
     let status = path("status")
+        .and(path::end())
         .and(method::get())
         .and(with(db.clone()))
+        .and(with(state))
+        .and(with(breaker))
         .and(f::authentication_from_header(db))
-        .and_then(fishnet_status)
-        .map(|status| {
-            Ok(reply::with_status(
-                reply::json(&status),
-                http::StatusCode::OK,
-            ))
-        });
+        .and_then(|db, state, breaker, api_user| {
+            handle(fishnet_status(db, state, breaker, api_user))
+        })
+        .map(|status| reply::json(&status));
 
-    acquire
+    let routes = acquire
+        .or(stream)
         .or(abort)
+        .or(abort_all)
+        .or(heartbeat)
         .or(analysis)
+        .or(failed)
+        .or(job_analysis)
+        .or(game)
+        .or(accuracy)
+        .or(pgn)
+        .or(reanalyze)
+        .or(enqueue)
+        .or(job_record)
+        .or(job_search)
+        .or(precedence)
         .or(valid_key)
+        .or(validate)
+        .or(pause)
+        .or(resume)
+        .or(games_status)
+        .or(status_history)
+        .or(fairness)
         .or(status)
-        .recover(recover)
+        .recover(recover);
+
+    warp::header::optional::<String>("accept-encoding")
+        .and(routes)
+        .and_then(gzip_if_accepted)
+        .with(cors(&config.webserver.cors))
         .boxed()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::testing::test_db;
+
+    fn deep_job(color: Option<m::Color>) -> m::Job {
+        api::CreateJob {
+            game_id: GameId("abcdefgh".to_string()),
+            report_id: None,
+            analysis_type: m::AnalysisType::Deep,
+            precedence: 0,
+            target_plies: None,
+            color,
+            analysis_from: None,
+            label: None,
+            callback_url: None,
+            unsupported: None,
+        }
+        .into_job(Utc::now())
+    }
+
+    /// A report for a game of `plies` half-moves with every position in `analysed`
+    /// analysed and the rest missing.
+    fn report(plies: usize, analysed: &[usize]) -> AnalysisReport {
+        let analysis: Vec<serde_json::Value> = (0..=plies)
+            .map(|ply| match analysed.contains(&ply) {
+                true => serde_json::json!({"depth": 20, "score": {"cp": 15}}),
+                false => serde_json::Value::Null,
+            })
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "fishnet": {"version": "2.2.0", "apikey": "key"},
+            "stockfish": {"flavor": "nnue"},
+            "analysis": analysis,
+        }))
+        .unwrap()
+    }
+
+    fn requested(flavor: Option<StockfishFlavor>) -> m::RequestedWork {
+        m::RequestedWork {
+            pvs: None,
+            depth: None,
+            nodes: ModelNodes {
+                nnue: 1_000,
+                classical: 3_000,
+            },
+            flavor,
+            only: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn nodes_are_charged_for_the_flavor_requested() {
+        let charge = |flavor| nodes_to_charge(&requested(flavor), 10).unwrap();
+        assert_eq!(charge(Some(StockfishFlavor::Nnue)), 10_000);
+        assert_eq!(charge(Some(StockfishFlavor::Classical)), 30_000);
+        assert_eq!(charge(None), 30_000);
+    }
+
+    #[test]
+    fn supported_variants_are_the_ones_we_can_analyze() {
+        let game = |variant: Option<&str>| -> Game {
+            serde_json::from_value(serde_json::json!({
+                "_id": "abcdefgh",
+                "emts": [],
+                "pgn": "e2e4",
+                "black": "bob",
+                "white": "alice",
+                "variant": variant,
+            }))
+            .unwrap()
+        };
+        for key in SUPPORTED_VARIANTS {
+            assert!(Variant::for_game(&game(Some(key))).is_some(), "{}", key);
+        }
+        assert_eq!(Variant::for_game(&game(None)), Some(Variant::Standard));
+        assert_eq!(Variant::for_game(&game(Some("crazyhouse"))), None);
+    }
+
+    #[test]
+    fn analysis_with_one_entry_per_position_is_accepted() {
+        assert!(check_analysis_length(6, &report(6, &[]).analysis).is_ok());
+    }
+
+    #[test]
+    fn analysis_that_is_too_short_is_refused() {
+        let err = check_analysis_length(6, &report(5, &[]).analysis).unwrap_err();
+        assert!(matches!(
+            err,
+            HttpError::AnalysisLengthMismatch {
+                expected: 7,
+                actual: 6
+            }
+        ));
+    }
+
+    #[test]
+    fn analysis_that_is_too_long_is_refused() {
+        let err = check_analysis_length(6, &report(7, &[]).analysis).unwrap_err();
+        assert!(matches!(
+            err,
+            HttpError::AnalysisLengthMismatch {
+                expected: 7,
+                actual: 8
+            }
+        ));
+    }
+
+    #[test]
+    fn white_only_jobs_skip_the_positions_black_moved_into() {
+        let settings = FishnetConfig::default();
+        let job = deep_job(Some(m::Color::White));
+        let skip = skip_positions_for_job(&job, &settings, 6, m::Color::White);
+        assert_eq!(skip, vec![0, 2, 4, 6]);
+    }
+
+    #[test]
+    fn black_only_jobs_skip_the_positions_white_moved_into() {
+        let settings = FishnetConfig::default();
+        let job = deep_job(Some(m::Color::Black));
+        let skip = skip_positions_for_job(&job, &settings, 6, m::Color::White);
+        assert_eq!(skip, vec![0, 1, 3, 5]);
+    }
+
+    #[test]
+    fn white_only_jobs_from_a_black_to_move_position_skip_black_moves() {
+        let settings = FishnetConfig::default();
+        let job = deep_job(Some(m::Color::White));
+        let skip = skip_positions_for_job(&job, &settings, 6, m::Color::Black);
+        assert_eq!(skip, vec![0, 1, 3, 5]);
+    }
+
+    #[test]
+    fn jobs_for_both_colors_skip_nothing_extra() {
+        let settings = FishnetConfig::default();
+        let skip = skip_positions_for_job(&deep_job(None), &settings, 6, m::Color::White);
+        assert!(skip.is_empty());
+    }
+
+    #[test]
+    fn white_only_reports_are_complete_without_black_moves() {
+        let report = report(6, &[1, 3, 5]);
+        assert!(report.is_complete(Some(m::Color::White), m::Color::White));
+        assert!(!report.is_complete(Some(m::Color::Black), m::Color::White));
+        assert!(!report.is_complete(None, m::Color::White));
+    }
+
+    #[test]
+    fn black_moves_lead_to_odd_plies_when_black_moves_first() {
+        let report = report(6, &[1, 3, 5]);
+        assert!(report.is_complete(Some(m::Color::Black), m::Color::Black));
+        assert!(!report.is_complete(Some(m::Color::White), m::Color::Black));
+    }
+
+    #[test]
+    fn restricting_to_white_keeps_only_white_moves() {
+        let mut analysis = report(4, &[0, 1, 2, 3, 4]).analysis;
+        restrict_to_color(m::Color::White, m::Color::White, &mut analysis);
+        let kept: Vec<bool> = analysis
+            .iter()
+            .map(|ply| !matches!(ply, Some(PlyAnalysis::Skipped(_))))
+            .collect();
+        assert_eq!(kept, vec![false, true, false, true, false]);
+    }
+
+    #[test]
+    fn restricting_to_white_when_black_moves_first_keeps_even_plies() {
+        let mut analysis = report(4, &[0, 1, 2, 3, 4]).analysis;
+        restrict_to_color(m::Color::White, m::Color::Black, &mut analysis);
+        let kept: Vec<bool> = analysis
+            .iter()
+            .map(|ply| !matches!(ply, Some(PlyAnalysis::Skipped(_))))
+            .collect();
+        assert_eq!(kept, vec![false, false, true, false, true]);
+    }
+
+    #[test]
+    fn games_lichess_exports_badly_are_unusable() {
+        let illegal = Error::IllegalMove {
+            ply: 3,
+            san: "Ke9".to_string(),
+        };
+        assert!(is_unusable_export(&illegal));
+        assert!(is_unusable_export(&Error::UnsupportedVariant("atomic".to_string())));
+        assert!(!is_unusable_export(&Error::NotFoundError));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn body_authorized_analysis_is_not_refused_for_a_missing_header() {
+        let db = test_db("body_authorized_analysis").await;
+        let create = api::CreateApiUser {
+            user: None,
+            name: "worker".to_string(),
+            perms: vec![m::AnalysisType::SystemAnalysis],
+            capabilities: Vec::new(),
+            max_concurrent: None,
+            daily_node_budget: None,
+            parallel_acquire: false,
+        };
+        let api_user = api::create_api_user(db.clone(), create).await.unwrap();
+        let mut config = Config::default();
+        config.fishnet.min_version = Some("2.0.0".parse().unwrap());
+        let (tx, _rx) = broadcast::channel(16);
+        let breaker = CircuitBreaker::new(5, Duration::from_secs(60));
+        let client = reqwest::Client::new();
+        let state = QueueState::default();
+        let routes = mount(db, tx, state, &config, breaker, client.clone(), client);
+        let response = warp::test::request()
+            .method("POST")
+            .path(&format!("/analysis/{}", ObjectId::new()))
+            .json(&serde_json::json!({
+                "fishnet": {"version": "1.0.0", "apikey": api_user.key.0},
+                "stockfish": {"flavor": "nnue"},
+                "analysis": [],
+            }))
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), warp::http::StatusCode::UPGRADE_REQUIRED);
+    }
+}
@@ -14,6 +14,8 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
 
 use chrono::prelude::*;
@@ -27,16 +29,17 @@ use mongodb::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::db::DbConn;
-use crate::deepq::model::{GameId, Report, UserId, ReportId};
+use crate::config::PrecedenceConfig;
+use crate::db::{DbConn, ToBsonLower};
+use crate::deepq::model::{GameId, Nodes, Report, StockfishFlavor, UserId, ReportId};
 use crate::error::{Error, Result};
 
-#[derive(Serialize, Deserialize, Debug, Clone, From, Display)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, From, Display)]
 pub struct Key(pub String);
 
 impl From<Key> for Bson {
     fn from(k: Key) -> Bson {
-        Bson::String(k.to_string().to_lowercase())
+        k.to_bson_lower()
     }
 }
 
@@ -44,7 +47,7 @@ impl From<Key> for Bson {
 //       I'd like it if Irwin and CR were unified, and user/system
 //       analysis should also be unified. but it  might be easier
 //       to deal with very specific analysis requests.
-#[derive(Serialize, Deserialize, Debug, Clone, strum_macros::ToString)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, strum_macros::Display)]
 #[serde(rename_all = "lowercase")]
 pub enum AnalysisType {
     UserAnalysis,   // User requested analysis, single-pv
@@ -54,10 +57,68 @@ pub enum AnalysisType {
 
 impl From<AnalysisType> for Bson {
     fn from(at: AnalysisType) -> Bson {
-        Bson::String(at.to_string().to_lowercase())
+        at.to_bson_lower()
+    }
+}
+
+/// One side of the board, for jobs that only need one player's moves analysed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Color {
+    White,
+    Black,
+}
+
+impl Color {
+    /// Whether the position after `ply` half-moves is the one this side's move led to,
+    /// in a game `first` moved first in. That position's eval is the eval of the move,
+    /// so it's the one worth keeping.
+    pub fn moved_into(self, ply: u32, first: Color) -> bool {
+        ply > 0 && (ply % 2 == 1) == (self == first)
+    }
+}
+
+impl From<shakmaty::Color> for Color {
+    fn from(color: shakmaty::Color) -> Color {
+        match color {
+            shakmaty::Color::White => Color::White,
+            shakmaty::Color::Black => Color::Black,
+        }
     }
 }
 
+/// Things a key may do besides analysis, which is gated by `ApiUser.perms`.
+#[derive(
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+    strum_macros::Display,
+    strum_macros::EnumString,
+)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Capability {
+    ManageKeys,   // Look up the status of other keys.
+    ManageQueue,  // Pause and resume the queue.
+    Reprioritize, // Change job precedence or queue games for reanalysis.
+    Audit,        // Read job records and raw analysis.
+    PurgeGames,   // Erase games and everything referencing them.
+    EnqueueGames, // Store new games and queue them, outside of any report.
+}
+
+/// Every capability, as `FishnetNewUser --admin` grants them and migrations give to keys
+/// that were admins before capabilities existed.
+pub const ADMIN_CAPABILITIES: &[Capability] = &[
+    Capability::ManageKeys,
+    Capability::ManageQueue,
+    Capability::Reprioritize,
+    Capability::Audit,
+    Capability::PurgeGames,
+    Capability::EnqueueGames,
+];
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ApiUser {
     pub _id: ObjectId,
@@ -65,12 +126,136 @@ pub struct ApiUser {
     pub user: Option<UserId>,
     pub name: String,
     pub perms: Vec<AnalysisType>,
+    #[serde(default)]
+    pub revoked: bool,
+    /// Keys from before capabilities existed; these hold every capability.
+    #[serde(default)]
+    pub is_admin: bool,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// The most incomplete jobs this key may hold at once, None for no limit.
+    #[serde(default)]
+    pub max_concurrent: Option<i64>,
+    /// How often this key has aborted jobs, by reason.
+    #[serde(default)]
+    pub abort_reasons: HashMap<String, i64>,
+    /// When the key last authenticated, to within a minute.
+    #[serde(default)]
+    pub last_seen: Option<DateTime>,
+    /// The normalized version the key's worker last reported.
+    #[serde(default)]
+    pub last_version: Option<String>,
+    /// Most nodes the key may be handed per UTC day, None for no limit.
+    #[serde(default)]
+    pub daily_node_budget: Option<i64>,
+    /// Nodes handed out on `nodes_day`; a new day starts the count over.
+    #[serde(default)]
+    pub nodes_used: i64,
+    #[serde(default)]
+    pub nodes_day: Option<DateTime>,
+    /// Lets the key's workers acquire in parallel instead of one at a time.
+    #[serde(default)]
+    pub parallel_acquire: bool,
 }
 
 impl ApiUser {
     pub fn coll(db: DbConn) -> Collection {
         db.database.collection("deepq_apiuser")
     }
+
+    pub fn has_capability(&self, capability: &Capability) -> bool {
+        self.is_admin || self.capabilities.contains(capability)
+    }
+
+    /// Nodes handed out to the key so far on the UTC day `now` falls on.
+    pub fn nodes_used_on(&self, now: chrono::DateTime<Utc>) -> i64 {
+        match &self.nodes_day {
+            Some(day) if day.0 == start_of_day(now) => self.nodes_used,
+            _ => 0,
+        }
+    }
+
+    pub fn node_budget_exhausted(&self, now: chrono::DateTime<Utc>) -> bool {
+        self.daily_node_budget
+            .map_or(false, |budget| self.nodes_used_on(now) >= budget)
+    }
+}
+
+/// Midnight UTC of the day `now` falls on.
+pub fn start_of_day(now: chrono::DateTime<Utc>) -> chrono::DateTime<Utc> {
+    now.date().and_hms(0, 0, 0)
+}
+
+/// A worker's version: up to three numbers, optionally followed by a pre-release or
+/// build suffix as fishnet nightlies send ("2.2.0-dev", "2.3.0+nightly", "2.3.0rc1").
+/// Missing numbers are taken as 0, and the suffix is kept but never compared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FishnetVersion {
+    numbers: [u64; 3],
+    suffix: Option<String>,
+}
+
+impl FishnetVersion {
+    /// Compares the numbers only, so nightlies pass the same gates as their release.
+    pub fn is_older_than(&self, other: &FishnetVersion) -> bool {
+        self.numbers < other.numbers
+    }
+}
+
+impl FromStr for FishnetVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidId {
+            kind: "version",
+            id: s.to_string(),
+        };
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (numbers, suffix) = s.split_at(split);
+        let parts: Vec<&str> = numbers.split('.').collect();
+        if parts.len() > 3 {
+            return Err(invalid());
+        }
+        let mut version = FishnetVersion {
+            numbers: [0; 3],
+            suffix: None,
+        };
+        for (i, part) in parts.iter().enumerate() {
+            // NOTE: also turns away empty parts, which covers an empty version.
+            if part.is_empty() || part.len() > 9 {
+                return Err(invalid());
+            }
+            version.numbers[i] = part.parse().map_err(|_| invalid())?;
+        }
+        if !suffix.is_empty() {
+            let tag = suffix.trim_start_matches(|c| c == '-' || c == '+');
+            let valid_tag = tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '+');
+            if tag.is_empty() || !valid_tag {
+                return Err(invalid());
+            }
+            version.suffix = Some(match suffix.chars().next() {
+                Some('+') => suffix.to_string(),
+                _ => format!("-{}", tag),
+            });
+        }
+        Ok(version)
+    }
+}
+
+impl fmt::Display for FishnetVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [major, minor, patch] = self.numbers;
+        write!(f, "{}.{}.{}", major, minor, patch)?;
+        if let Some(suffix) = &self.suffix {
+            write!(f, "{}", suffix)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, From, Display)]
@@ -96,10 +281,79 @@ pub struct Job {
     pub game_id: GameId,
     pub analysis_type: AnalysisType,
     pub precedence: i32,
-    pub owner: Option<String>, // TODO: this should be the key from the database
+    pub owner: Option<ObjectId>, // The _id of the ApiUser working on it.
     pub date_last_updated: DateTime,
     pub report_id: Option<ReportId>,
     pub is_complete: bool, // Denormalized cache of completion state.
+    #[serde(default)]
+    pub requested: Option<RequestedWork>, // What we asked of the worker when it was acquired.
+    #[serde(default)]
+    pub last_abort_reason: Option<String>,
+    #[serde(default)]
+    pub abort_count: i32,
+    #[serde(default)]
+    pub failure_count: i32,
+    #[serde(default)]
+    pub last_failure_reason: Option<String>,
+    /// Failed too often to keep serving; left for an operator to review.
+    #[serde(default)]
+    pub quarantined: bool,
+    /// Only these plies need analysis; None means the whole game.
+    #[serde(default)]
+    pub target_plies: Option<Vec<u32>>,
+    /// Times the job failed or timed out on a worker; each one decays `precedence`.
+    #[serde(default)]
+    pub requeue_count: i32,
+    /// What `precedence` was before any requeue decay; None until the first requeue.
+    #[serde(default)]
+    pub base_precedence: Option<i32>,
+    /// Its report was cancelled; it is no longer handed out and analysis for it is refused.
+    #[serde(default)]
+    pub cancelled: bool,
+    /// Only the positions this side's moves led to are analysed; None for both sides.
+    #[serde(default)]
+    pub color: Option<Color>,
+    /// Created complete, using the analysis already stored for this earlier job.
+    #[serde(default)]
+    pub analysis_from: Option<JobId>,
+    /// Free-form tag grouping jobs queued together, e.g. for an experiment. Left out
+    /// of the document when unset, so the sparse label index only covers labelled jobs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Sent the job's analysis once it is complete; see `callback::job_completed`.
+    #[serde(default)]
+    pub callback_url: Option<String>,
+    /// Why the job can't be analysed, e.g. its game's variant; such jobs aren't handed out.
+    #[serde(default)]
+    pub unsupported: Option<String>,
+}
+
+impl Job {
+    /// The job whose stored analysis belongs to this one.
+    pub fn analysis_job_id(&self) -> JobId {
+        self.analysis_from.clone().unwrap_or_else(|| self._id.clone())
+    }
+
+    /// Jobs that will never be analysed, for now at least: quarantined ones wait on an
+    /// operator. Their reports go ahead without them.
+    pub fn is_set_aside(&self) -> bool {
+        self.quarantined || self.unsupported.is_some()
+    }
+}
+
+/// The search settings sent to a worker, recorded on the job so the stored
+/// analysis reflects them even if the configuration changes in the meantime.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestedWork {
+    pub pvs: Option<i32>,
+    pub depth: Option<i32>,
+    pub nodes: Nodes,
+    /// The only flavor the worker said it could run; None if it didn't say.
+    #[serde(default)]
+    pub flavor: Option<StockfishFlavor>,
+    /// The analysis types the worker restricted itself to; empty for any it may do.
+    #[serde(default)]
+    pub only: Vec<AnalysisType>,
 }
 
 impl Job {
@@ -107,8 +361,20 @@ impl Job {
         db.database.collection("deepq_fishnetjobs")
     }
 
-    pub fn seconds_since_created(&self) -> i64 {
-        Utc::now().timestamp() - self.date_last_updated.timestamp()
+    /// The precedence a job started at `base` has after `requeue_count` requeues. It
+    /// loses `requeue_decay_percent` each time, but never drops below `requeue_floor`;
+    /// jobs that already start below the floor keep their precedence.
+    pub fn effective_precedence(base: i32, requeue_count: i32, config: &PrecedenceConfig) -> i32 {
+        if base <= config.requeue_floor {
+            return base;
+        }
+        let kept = 1f64 - f64::from(config.requeue_decay_percent.min(100)) / 100f64;
+        let decayed = (f64::from(base) * kept.powi(requeue_count)).floor() as i32;
+        decayed.max(config.requeue_floor)
+    }
+
+    pub fn seconds_since_created(&self, now: chrono::DateTime<Utc>) -> i64 {
+        now.timestamp() - self.date_last_updated.timestamp()
     }
 
     pub async fn acquired_jobs(db: DbConn, analysis_type: AnalysisType) -> Result<i64> {
@@ -152,6 +418,27 @@ impl Job {
     pub async fn queued_jobs(db: DbConn, analysis_type: AnalysisType) -> Result<i64> {
         let filter = doc! {
             "owner": { "$eq": Bson::Null },
+            "is_complete": { "$eq": false },
+            "analysis_type": { "$eq": analysis_type },
+            "quarantined": { "$ne": true },
+            "cancelled": { "$ne": true },
+            "unsupported": { "$eq": Bson::Null },
+        };
+        Ok(Job::coll(db.clone()).count_documents(filter, None).await?)
+    }
+
+    pub async fn unsupported_jobs(db: DbConn, analysis_type: AnalysisType) -> Result<i64> {
+        let filter = doc! {
+            "unsupported": { "$ne": Bson::Null },
+            "is_complete": { "$eq": false },
+            "analysis_type": { "$eq": analysis_type },
+        };
+        Ok(Job::coll(db.clone()).count_documents(filter, None).await?)
+    }
+
+    pub async fn quarantined_jobs(db: DbConn, analysis_type: AnalysisType) -> Result<i64> {
+        let filter = doc! {
+            "quarantined": { "$eq": true },
             "analysis_type": { "$eq": analysis_type },
         };
         Ok(Job::coll(db.clone()).count_documents(filter, None).await?)
@@ -160,7 +447,11 @@ impl Job {
     pub async fn oldest_job(db: DbConn, analysis_type: AnalysisType) -> Result<Option<Job>> {
         let filter = doc! {
             "owner": { "$eq": Bson::Null },
+            "is_complete": { "$eq": false },
             "analysis_type": { "$eq": analysis_type },
+            "quarantined": { "$ne": true },
+            "cancelled": { "$ne": true },
+            "unsupported": { "$eq": Bson::Null },
         };
         let options = FindOneOptions::builder()
             .sort(doc! { "date_last_updated": -1 })
@@ -172,3 +463,128 @@ impl Job {
             .transpose()?)
     }
 }
+
+/// Queue depth for one analysis type at a point in time, for capacity planning.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueueSample {
+    pub _id: ObjectId,
+    pub date: DateTime,
+    pub analysis_type: AnalysisType,
+    pub acquired: i64,
+    pub queued: i64,
+    /// Seconds the oldest queued job has been waiting.
+    pub oldest: i64,
+    pub quarantined: i64,
+}
+
+impl QueueSample {
+    pub fn coll(db: DbConn) -> Collection {
+        db.database.collection("deepq_queue_samples")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fishnet::api::CreateApiUser;
+
+    fn api_user(capabilities: Vec<Capability>, daily_node_budget: Option<i64>) -> ApiUser {
+        ApiUser::from(CreateApiUser {
+            user: None,
+            name: "worker".to_string(),
+            perms: vec![AnalysisType::SystemAnalysis],
+            capabilities,
+            max_concurrent: None,
+            daily_node_budget,
+            parallel_acquire: false,
+        })
+    }
+
+    #[test]
+    fn keys_only_hold_the_capabilities_they_were_given() {
+        let user = api_user(vec![Capability::ManageQueue], None);
+        assert!(user.has_capability(&Capability::ManageQueue));
+        assert!(!user.has_capability(&Capability::ManageKeys));
+        assert!(!user.has_capability(&Capability::PurgeGames));
+    }
+
+    #[test]
+    fn admin_keys_hold_every_capability() {
+        let mut user = api_user(Vec::new(), None);
+        user.is_admin = true;
+        assert!(user.has_capability(&Capability::ManageKeys));
+        assert!(user.has_capability(&Capability::EnqueueGames));
+    }
+
+    #[test]
+    fn node_budget_runs_out_at_the_budget() {
+        let now = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let mut user = api_user(Vec::new(), Some(1_000));
+        user.nodes_day = Some(DateTime(start_of_day(now)));
+        user.nodes_used = 999;
+        assert!(!user.node_budget_exhausted(now));
+        user.nodes_used = 1_000;
+        assert!(user.node_budget_exhausted(now));
+    }
+
+    #[test]
+    fn node_budget_starts_over_at_midnight_utc() {
+        let last_second = Utc.ymd(2021, 3, 1).and_hms(23, 59, 59);
+        let midnight = Utc.ymd(2021, 3, 2).and_hms(0, 0, 0);
+        assert_eq!(start_of_day(last_second), Utc.ymd(2021, 3, 1).and_hms(0, 0, 0));
+        assert_eq!(start_of_day(midnight), midnight);
+
+        let mut user = api_user(Vec::new(), Some(1_000));
+        user.nodes_day = Some(DateTime(start_of_day(last_second)));
+        user.nodes_used = 5_000;
+        assert!(user.node_budget_exhausted(last_second));
+        assert_eq!(user.nodes_used_on(midnight), 0);
+        assert!(!user.node_budget_exhausted(midnight));
+    }
+
+    #[test]
+    fn keys_without_a_budget_never_run_out() {
+        let now = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let mut user = api_user(Vec::new(), None);
+        user.nodes_day = Some(DateTime(start_of_day(now)));
+        user.nodes_used = i64::MAX;
+        assert!(!user.node_budget_exhausted(now));
+    }
+
+    fn precedence(decay_percent: u8, floor: i32) -> PrecedenceConfig {
+        PrecedenceConfig {
+            requeue_decay_percent: decay_percent,
+            requeue_floor: floor,
+            ..PrecedenceConfig::default()
+        }
+    }
+
+    #[test]
+    fn precedence_decays_with_each_requeue() {
+        let config = precedence(10, 0);
+        let decayed: Vec<i32> = (0..4)
+            .map(|requeues| Job::effective_precedence(1000, requeues, &config))
+            .collect();
+        assert_eq!(decayed, vec![1000, 900, 810, 729]);
+    }
+
+    #[test]
+    fn precedence_decay_stops_at_the_floor() {
+        let config = precedence(50, 100);
+        assert_eq!(Job::effective_precedence(1000, 3, &config), 125);
+        assert_eq!(Job::effective_precedence(1000, 4, &config), 100);
+        assert_eq!(Job::effective_precedence(1000, 40, &config), 100);
+    }
+
+    #[test]
+    fn precedence_below_the_floor_is_left_alone() {
+        let config = precedence(50, 100);
+        assert_eq!(Job::effective_precedence(10, 5, &config), 10);
+    }
+
+    #[test]
+    fn decay_is_capped_at_everything() {
+        let config = precedence(250, 1);
+        assert_eq!(Job::effective_precedence(1000, 1, &config), 1);
+    }
+}
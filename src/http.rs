@@ -15,25 +15,45 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::HashMap;
 use std::convert::Infallible;
+use std::hash::Hash;
+use std::io::{Read, Write};
 use std::marker::Send;
 use std::result::Result as StdResult;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use futures::future::{self, Future};
 use mongodb::bson::oid::ObjectId;
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::sync::{Semaphore, SemaphorePermit};
 use warp::{
-    http, reject,
+    http::{
+        self,
+        header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER},
+    },
+    hyper::{
+        self,
+        body::{Body, Bytes},
+    },
+    reject,
     reply::{self, Json, Reply, WithStatus},
     Filter, Rejection,
 };
 
 use crate::error::{Error, HttpError};
 
-/// Unauthorized rejection
+/// Forbidden rejection for keys that lack the required permissions
 pub fn forbidden() -> Rejection {
-    reject::custom(HttpError::Forbidden)
+    reject::custom(HttpError::InsufficientPermission)
+}
+
+/// Forbidden rejection for keys that have been revoked
+pub fn revoked() -> Rejection {
+    reject::custom(HttpError::RevokedKey)
 }
 
 pub fn unauthenticated() -> Rejection {
@@ -86,6 +106,25 @@ impl From<Id> for ObjectId {
     }
 }
 
+/// Rejection for a domain error. An `HttpError` is unwrapped first so `recover` can
+/// still find it and answer with its status rather than a 500.
+pub fn into_rejection(err: Error) -> Rejection {
+    match err {
+        Error::HttpError(err) => reject::custom(err),
+        Error::NotFoundError => reject::not_found(),
+        err => reject::custom(err),
+    }
+}
+
+/// Awaits a handler written against our own `Result`, turning its error into a
+/// rejection. Use it from `and_then` so handlers don't each convert errors by hand.
+pub async fn handle<T, F>(fut: F) -> StdResult<T, Rejection>
+where
+    F: Future<Output = crate::error::Result<T>>,
+{
+    fut.await.map_err(into_rejection)
+}
+
 pub fn with<T>(t: T) -> impl Filter<Extract = (T,), Error = std::convert::Infallible> + Clone
 where
     T: Clone + Sync + Send,
@@ -105,11 +144,366 @@ pub async fn json_object_or_no_content<T: Serialize>(
     )
 }
 
+/// Whether an `Accept` header value asks for msgpack.
+fn accepts_msgpack(accept: &str) -> bool {
+    accept.split(',').any(|media_type| {
+        let name = media_type.split(';').next().unwrap_or("").trim();
+        name.eq_ignore_ascii_case("application/msgpack")
+    })
+}
+
+/// Like `json_object_or_no_content`, but replies with msgpack instead when the
+/// `Accept` header asks for `application/msgpack`.
+pub async fn negotiated_object_or_no_content<T: Serialize>(
+    value: Option<T>,
+    accept: Option<String>,
+) -> StdResult<reply::Response, Rejection> {
+    let val = match value {
+        Some(val) => val,
+        None => return Ok(json_object_or_no_content::<T>(None).await?.into_response()),
+    };
+    if !accept.as_deref().map_or(false, accepts_msgpack) {
+        return Ok(reply::json(&val).into_response());
+    }
+    // NOTE: named, so structs keep the same map shape they have as JSON.
+    let body = rmp_serde::to_vec_named(&val).map_err(Error::from)?;
+    let mut response = reply::Response::new(Body::from(body));
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/msgpack"));
+    Ok(response)
+}
+
+fn is_gzip(content_encoding: Option<String>) -> bool {
+    content_encoding.map_or(false, |encoding| encoding.trim().eq_ignore_ascii_case("gzip"))
+}
+
+/// Whether an `Accept-Encoding` header value allows a gzipped response.
+fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|encoding| {
+        let mut parts = encoding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        let quality = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .and_then(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && quality > 0.0
+    })
+}
+
+fn decode_json_body<T: DeserializeOwned>(
+    content_encoding: Option<String>,
+    body: Bytes,
+    limit: u64,
+) -> StdResult<T, Rejection> {
+    let parsed = if is_gzip(content_encoding) {
+        let mut inflated = Vec::new();
+        GzDecoder::new(&body[..])
+            .take(limit + 1)
+            .read_to_end(&mut inflated)
+            .map_err(|_| reject::custom(HttpError::MalformedBody))?;
+        if inflated.len() as u64 > limit {
+            return Err(reject::custom(HttpError::PayloadTooLarge));
+        }
+        serde_json::from_slice(&inflated)
+    } else {
+        serde_json::from_slice(&body)
+    };
+    parsed.map_err(|_| reject::custom(HttpError::MalformedBody))
+}
+
+/// Extracts a JSON body, inflating it first when sent with `Content-Encoding: gzip`.
+/// Bodies that fail to inflate or parse are rejected as a 400, and bodies over
+/// `limit` bytes (or without a Content-Length) as a 413 (or 411).
+pub fn json_body<T>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    warp::body::content_length_limit(limit)
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::bytes())
+        .and_then(move |content_encoding: Option<String>, body: Bytes| async move {
+            decode_json_body::<T>(content_encoding, body, limit)
+        })
+}
+
+/// Like `json_body`, but an empty body extracts `None` rather than being rejected.
+/// Since the body is optional, so is the Content-Length; it's only checked if sent.
+pub fn optional_json_body<T>(
+    limit: u64,
+) -> impl Filter<Extract = (Option<T>,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    warp::header::optional::<u64>("content-length")
+        .and_then(move |length: Option<u64>| async move {
+            match length {
+                Some(length) if length > limit => {
+                    Err(reject::custom(HttpError::PayloadTooLarge))
+                }
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::bytes())
+        .and_then(move |content_encoding: Option<String>, body: Bytes| async move {
+            if body.is_empty() {
+                return Ok::<Option<T>, Rejection>(None);
+            }
+            decode_json_body::<T>(content_encoding, body, limit).map(Some)
+        })
+}
+
+/// Gzips the reply when the client's `Accept-Encoding` allows it.
+pub async fn gzip_if_accepted<R: Reply>(
+    accept_encoding: Option<String>,
+    reply: R,
+) -> StdResult<reply::Response, Rejection> {
+    let response = reply.into_response();
+    // NOTE: event streams never end, so they can't be buffered up and compressed.
+    let is_event_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .map_or(false, |v| v.as_bytes().starts_with(b"text/event-stream"));
+    if !accept_encoding.as_deref().map_or(false, accepts_gzip)
+        || response.headers().contains_key(CONTENT_ENCODING)
+        || is_event_stream
+    {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await.map_err(Error::from)?;
+    if body.is_empty() {
+        return Ok(reply::Response::from_parts(parts, Body::from(body)));
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&body).map_err(Error::from)?;
+    let compressed = encoder.finish().map_err(Error::from)?;
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(reply::Response::from_parts(parts, Body::from(compressed)))
+}
+
+/// Cross-origin settings for browser based dashboards. Requests from origins that
+/// aren't listed are rejected without any permissive CORS headers.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsOpts {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+}
+
+impl Default for CorsOpts {
+    fn default() -> CorsOpts {
+        CorsOpts {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+        }
+    }
+}
+
+impl CorsOpts {
+    /// warp panics on an origin or method it can't parse, so check them all up front.
+    pub fn validate(&self) -> crate::error::Result<()> {
+        let invalid = |field, value: &String| Error::InvalidConfig {
+            field,
+            value: value.clone(),
+        };
+        for origin in &self.allowed_origins {
+            let mut parts = origin.splitn(2, "://");
+            let valid = match (parts.next(), parts.next()) {
+                (Some(scheme), Some(authority)) => {
+                    scheme.parse::<http::uri::Scheme>().is_ok()
+                        && authority.parse::<http::uri::Authority>().is_ok()
+                        && HeaderValue::from_str(origin).is_ok()
+                }
+                _ => false,
+            };
+            if !valid {
+                return Err(invalid("webserver.cors.allowed_origins", origin));
+            }
+        }
+        for method in &self.allowed_methods {
+            if method.parse::<http::Method>().is_err() {
+                return Err(invalid("webserver.cors.allowed_methods", method));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Panics on options that haven't passed `CorsOpts::validate`.
+pub fn cors(opts: &CorsOpts) -> warp::cors::Cors {
+    warp::cors()
+        .allow_origins(opts.allowed_origins.iter().map(String::as_str))
+        .allow_methods(opts.allowed_methods.iter().map(String::as_str))
+        .allow_headers(vec!["authorization", "content-type", "content-encoding"])
+        .build()
+}
+
+/// Bounds the number of requests working against the database at once.
+#[derive(Debug, Clone)]
+pub struct Backpressure {
+    permits: Arc<Semaphore>,
+    reserved: usize,
+}
+
+impl Backpressure {
+    pub fn new(limit: usize, reserved: usize) -> Backpressure {
+        Backpressure {
+            permits: Arc::new(Semaphore::new(limit)),
+            reserved: reserved.min(limit),
+        }
+    }
+
+    /// For requests the client can simply retry: fails immediately with
+    /// `HttpError::Overloaded` rather than queueing, and never takes the reserved permits.
+    pub fn try_acquire(&self) -> StdResult<SemaphorePermit, HttpError> {
+        if self.permits.available_permits() <= self.reserved {
+            return Err(HttpError::Overloaded);
+        }
+        self.permits.try_acquire().map_err(|_| HttpError::Overloaded)
+    }
+
+    /// For requests we'd rather not lose: waits for any permit, reserved ones included.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.permits
+            .acquire()
+            .await
+            .expect("the semaphore is never closed")
+    }
+}
+
+/// Remembers responses for a while, so a retried request carrying the same
+/// `Idempotency-Key` gets the original response instead of being processed again.
+#[derive(Debug, Clone)]
+pub struct IdempotencyCache<K, V> {
+    entries: Arc<Mutex<HashMap<K, (Instant, V)>>>,
+    ttl: Duration,
+}
+
+impl<K: Eq + Hash, V: Clone> IdempotencyCache<K, V> {
+    pub fn new(ttl: Duration) -> IdempotencyCache<K, V> {
+        IdempotencyCache {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().expect("idempotency cache poisoned");
+        entries
+            .get(key)
+            .filter(|(at, _)| at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().expect("idempotency cache poisoned");
+        let ttl = self.ttl;
+        entries.retain(|_, (at, _)| at.elapsed() < ttl);
+        entries.insert(key, (Instant::now(), value));
+    }
+}
+
+/// Lets one request per key through at a time. A holder that never finishes only keeps
+/// its key locked for `timeout`, or however long it said it would hold it; a zero
+/// timeout turns the lock off.
+#[derive(Debug, Clone)]
+pub struct KeyedLock<K> {
+    /// When each key was taken, and until when it stays locked.
+    held: Arc<Mutex<HashMap<K, (Instant, Instant)>>>,
+    timeout: Duration,
+}
+
+/// Releases the key when dropped.
+pub struct KeyedLockGuard<K: Eq + Hash> {
+    held: Arc<Mutex<HashMap<K, (Instant, Instant)>>>,
+    key: Option<K>,
+    at: Instant,
+}
+
+impl<K: Eq + Hash + Clone> KeyedLock<K> {
+    pub fn new(timeout: Duration) -> KeyedLock<K> {
+        KeyedLock {
+            held: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+        }
+    }
+
+    /// None while another request holds the key.
+    pub fn try_lock(&self, key: K) -> Option<KeyedLockGuard<K>> {
+        self.try_lock_for(key, Duration::from_secs(0))
+    }
+
+    /// Like `try_lock`, for a holder that means to keep the key for up to `hold`, e.g.
+    /// while long polling. The key stays locked for the longer of `hold` and `timeout`.
+    pub fn try_lock_for(&self, key: K, hold: Duration) -> Option<KeyedLockGuard<K>> {
+        let now = Instant::now();
+        if self.timeout == Duration::from_secs(0) {
+            return Some(KeyedLockGuard {
+                held: self.held.clone(),
+                key: None,
+                at: now,
+            });
+        }
+        let mut held = self.held.lock().expect("keyed lock poisoned");
+        if let Some((_, until)) = held.get(&key) {
+            if now < *until {
+                return None;
+            }
+        }
+        held.insert(key.clone(), (now, now + self.timeout.max(hold)));
+        Some(KeyedLockGuard {
+            held: self.held.clone(),
+            key: Some(key),
+            at: now,
+        })
+    }
+}
+
+impl<K: Eq + Hash> Drop for KeyedLockGuard<K> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let mut held = self.held.lock().expect("keyed lock poisoned");
+            // NOTE: after a timeout the key may belong to a newer request by now.
+            if held.get(&key).map(|(at, _)| at) == Some(&self.at) {
+                held.remove(&key);
+            }
+        }
+    }
+}
+
+/// How long clients should wait before retrying when we're overloaded.
+const RETRY_AFTER_SECONDS: &str = "5";
+
 /// An API error serializable to JSON.
 #[derive(Serialize)]
 pub struct ErrorMessage {
     code: u16,
     message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    issues: Vec<String>,
+}
+
+fn is_authorization_header(name: Option<&str>) -> bool {
+    name.map_or(false, |name| name.eq_ignore_ascii_case("authorization"))
+}
+
+/// Errors about a game a client sent or asked for that can't be analysed as it is.
+fn is_invalid_game(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::UnsupportedVariant(_)
+            | Error::InvalidFen { .. }
+            | Error::IllegalMove { .. }
+            | Error::MisalignedGame { .. }
+    )
 }
 
 // This function receives a `Rejection` and tries to return a custom
@@ -117,16 +511,78 @@ pub struct ErrorMessage {
 pub async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
     let code;
     let message;
+    let mut issues = Vec::new();
 
-    if err.is_not_found() {
+    if err.is_not_found() || matches!(err.find(), Some(Error::NotFoundError)) {
         code = http::StatusCode::NOT_FOUND;
         message = "NOT_FOUND";
     } else if let Some(HttpError::Unauthenticated) = err.find() {
         code = http::StatusCode::UNAUTHORIZED;
-        message = "UNAUTHORIZED";
-    } else if let Some(HttpError::Forbidden) = err.find() {
+        message = "missing_key";
+    } else if is_authorization_header(err.find::<reject::MissingHeader>().map(|h| h.name()))
+        || is_authorization_header(err.find::<reject::InvalidHeader>().map(|h| h.name()))
+    {
+        code = http::StatusCode::UNAUTHORIZED;
+        message = "missing_key";
+    } else if let Some(HttpError::RevokedKey) = err.find() {
         code = http::StatusCode::FORBIDDEN;
-        message = "FORBIDDEN";
+        message = "revoked_key";
+    } else if let Some(HttpError::InsufficientPermission) = err.find() {
+        code = http::StatusCode::FORBIDDEN;
+        message = "insufficient_permission";
+    } else if let Some(HttpError::JobComplete) = err.find() {
+        code = http::StatusCode::CONFLICT;
+        message = "job_complete";
+    } else if let Some(HttpError::ReportResolved) = err.find() {
+        code = http::StatusCode::CONFLICT;
+        message = "report_resolved";
+    } else if let Some(HttpError::JobCancelled) = err.find() {
+        code = http::StatusCode::GONE;
+        message = "job_cancelled";
+    } else if let Some(HttpError::MalformedBody) = err.find() {
+        code = http::StatusCode::BAD_REQUEST;
+        message = "malformed_body";
+    } else if let Some(invalid @ Error::InvalidId { .. }) = err.find() {
+        code = http::StatusCode::BAD_REQUEST;
+        message = "invalid_id";
+        issues = vec![invalid.to_string()];
+    } else if let Some(invalid) = err.find::<Error>().filter(|err| is_invalid_game(err)) {
+        code = http::StatusCode::UNPROCESSABLE_ENTITY;
+        message = "invalid_game";
+        issues = vec![invalid.to_string()];
+    } else if let Some(HttpError::InvalidReport(report_issues)) = err.find() {
+        code = http::StatusCode::UNPROCESSABLE_ENTITY;
+        message = "invalid_report";
+        issues = report_issues.clone();
+    } else if let Some(HttpError::InvalidGames(game_issues)) = err.find() {
+        code = http::StatusCode::UNPROCESSABLE_ENTITY;
+        message = "invalid_games";
+        issues = game_issues.clone();
+    } else if let Some(HttpError::AnalysisLengthMismatch { expected, actual }) = err.find() {
+        code = http::StatusCode::UNPROCESSABLE_ENTITY;
+        message = "analysis_length_mismatch";
+        issues = vec![format!(
+            "expected {} analysis entries, one per position, but got {}",
+            expected, actual
+        )];
+    } else if let Some(HttpError::UpgradeRequired { version, minimum }) = err.find() {
+        code = http::StatusCode::UPGRADE_REQUIRED;
+        message = "upgrade_required";
+        issues = vec![format!(
+            "fishnet {} is no longer supported, please upgrade to {} or later",
+            version, minimum
+        )];
+    } else if let Some(HttpError::Overloaded) = err.find() {
+        code = http::StatusCode::SERVICE_UNAVAILABLE;
+        message = "overloaded";
+    } else if err.find::<reject::PayloadTooLarge>().is_some()
+        || matches!(err.find(), Some(HttpError::PayloadTooLarge))
+    {
+        code = http::StatusCode::PAYLOAD_TOO_LARGE;
+        message = "payload_too_large";
+    } else if err.find::<reject::LengthRequired>().is_some() {
+        code = http::StatusCode::LENGTH_REQUIRED;
+        message = "length_required";
     } else if err.find::<reject::MethodNotAllowed>().is_some() {
         code = http::StatusCode::METHOD_NOT_ALLOWED;
         message = "METHOD_NOT_ALLOWED";
@@ -140,7 +596,119 @@ pub async fn recover(err: Rejection) -> Result<impl Reply, Infallible> {
     let json = warp::reply::json(&ErrorMessage {
         code: code.as_u16(),
         message: message.into(),
+        issues,
     });
 
-    Ok(warp::reply::with_status(json, code))
+    let mut response = warp::reply::with_status(json, code).into_response();
+    if code == http::StatusCode::SERVICE_UNAVAILABLE {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static(RETRY_AFTER_SECONDS));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread::sleep;
+
+    /// The status `recover` answers with for a handler failing with `err`.
+    async fn status_for(err: fn() -> Error) -> http::StatusCode {
+        let filter = warp::any()
+            .and_then(move || handle(async move { Err::<String, _>(err()) }))
+            .recover(recover);
+        warp::test::request().reply(&filter).await.status()
+    }
+
+    #[tokio::test]
+    async fn not_found_errors_answer_404() {
+        assert_eq!(status_for(|| Error::NotFoundError).await, http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn not_found_errors_rejected_directly_answer_404() {
+        let filter = warp::any()
+            .and_then(|| async { Err::<String, _>(reject::custom(Error::NotFoundError)) })
+            .recover(recover);
+        let response = warp::test::request().reply(&filter).await;
+        assert_eq!(response.status(), http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn analysis_length_mismatches_answer_422_with_the_lengths() {
+        let err = || {
+            Error::HttpError(HttpError::AnalysisLengthMismatch {
+                expected: 41,
+                actual: 40,
+            })
+        };
+        let filter = warp::any()
+            .and_then(move || handle(async move { Err::<String, _>(err()) }))
+            .recover(recover);
+        let response = warp::test::request().reply(&filter).await;
+        assert_eq!(response.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["message"], "analysis_length_mismatch");
+        assert_eq!(
+            body["issues"][0],
+            "expected 41 analysis entries, one per position, but got 40"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_answer_400() {
+        let err = || Error::InvalidId {
+            kind: "game",
+            id: "abc".to_string(),
+        };
+        assert_eq!(status_for(err).await, http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn invalid_games_answer_422_with_the_problem() {
+        let err = || Error::IllegalMove {
+            ply: 3,
+            san: "Ke9".to_string(),
+        };
+        let filter = warp::any()
+            .and_then(move || handle(async move { Err::<String, _>(err()) }))
+            .recover(recover);
+        let response = warp::test::request().reply(&filter).await;
+        assert_eq!(response.status(), http::StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["message"], "invalid_game");
+        assert_eq!(body["issues"][0], "Illegal move Ke9 at ply 3");
+        let unsupported = || Error::UnsupportedVariant("atomic".to_string());
+        assert_eq!(status_for(unsupported).await, http::StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn other_errors_answer_500() {
+        assert_eq!(
+            status_for(|| Error::CreateError).await,
+            http::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[test]
+    fn keyed_lock_expires_after_timeout() {
+        let lock = KeyedLock::new(Duration::from_millis(10));
+        let _guard = lock.try_lock(1).expect("free key");
+        assert!(lock.try_lock(1).is_none());
+        assert!(lock.try_lock(2).is_some());
+        sleep(Duration::from_millis(20));
+        assert!(lock.try_lock(1).is_some());
+    }
+
+    #[test]
+    fn keyed_lock_is_held_for_the_requested_time() {
+        let lock = KeyedLock::new(Duration::from_millis(10));
+        let guard = lock.try_lock_for(1, Duration::from_secs(60)).expect("free key");
+        sleep(Duration::from_millis(20));
+        assert!(lock.try_lock(1).is_none());
+        drop(guard);
+        assert!(lock.try_lock(1).is_some());
+    }
 }
@@ -20,23 +20,44 @@
 use std::convert::{TryFrom, TryInto};
 use std::iter::Iterator;
 use std::result::Result as StdResult;
+use std::time::Duration;
 
-use futures::{future::try_join_all, stream::StreamExt};
+use chrono::{DateTime, Utc};
+use futures::{
+    future::try_join_all,
+    stream::{Stream, StreamExt, TryStreamExt},
+};
 use log::{debug, error, info, warn};
+use mongodb::{
+    bson::doc,
+    options::{UpdateModifications, UpdateOptions},
+    Collection,
+};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, SpaceSeparator, StringWithSeparator};
 use shakmaty::{san::San, uci::Uci, CastlingMode, Chess, Position};
 use tokio::sync::broadcast::{self, error::RecvError};
 
+use super::breaker::{BreakerState, CircuitBreaker};
+use crate::config::{IrwinConfig, OriginAnalysisConfig, PrecedenceConfig};
 use crate::db::DbConn;
 use crate::deepq::api::{
-    atomically_update_sent_to_irwin, find_report, insert_many_games, insert_one_report,
-    precedence_for_origin, CreateGame, CreateReport,
+    analysis_type_for_origin, atomically_update_sent_to_irwin, count_reports_for_user,
+    find_analysis_for_job, find_game, find_held_reports, find_incomplete_reports, find_report,
+    initial_position, insert_one_report, mark_report_checked, mark_report_complete,
+    precedence_for_origin, record_submitted_games, repeat_user_bonus, resolve_report,
+    unmark_sent_to_irwin, unsent_report_ids, upsert_game, CreateGame, CreateReport,
+};
+use crate::deepq::model::{
+    GameAnalysis, GameId, Report, ReportAction, ReportId, ReportOrigin, ReportType, Score,
+    UserId,
+};
+use crate::error::{Error, LogErr, LogMissing, Result};
+use crate::fishnet::api::{
+    find_reusable_job, get_job, insert_many_jobs, reports_with_completed_jobs, CreateJob,
 };
-use crate::deepq::model::{GameId, Report, ReportOrigin, ReportType, Score, UserId};
-use crate::error::{Error, Result};
-use crate::fishnet::api::{get_job, insert_many_jobs, CreateJob};
-use crate::fishnet::model::{AnalysisType, Job, JobId};
+use crate::fishnet::handlers::{DEFAULT_VARIANT, SUPPORTED_VARIANTS};
+use crate::fishnet::model::{Color, Job, JobId};
 use crate::fishnet::FishnetMsg;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -58,31 +79,119 @@ pub struct Game {
     #[serde_as(as = "StringWithSeparator::<SpaceSeparator, San>")]
     pub pgn: Vec<San>,
     pub analysis: Option<Vec<Score>>,
+    #[serde(default)]
+    pub variant: Option<String>,
+    /// The plies irwin wants analyzed, when it doesn't need the whole game.
+    #[serde(default)]
+    pub target_plies: Option<Vec<u32>>,
+    /// The side irwin wants analyzed, when it only cares about one player.
+    #[serde(default)]
+    pub color: Option<Color>,
+    /// Only sent for games that don't start from the standard position.
+    #[serde(rename = "initialFen", default)]
+    pub initial_fen: Option<String>,
 }
 
-fn uci_from_san(pgn: &Vec<San>) -> Result<Vec<Uci>> {
-    let mut pos = Chess::default();
+pub fn uci_from_san(pgn: &[San]) -> Result<Vec<Uci>> {
+    uci_from_position(Chess::default(), pgn)
+}
+
+/// Like `uci_from_san`, for games that don't start from the standard position.
+pub fn uci_from_position(mut pos: Chess, pgn: &[San]) -> Result<Vec<Uci>> {
     let mut ret_val = Vec::new();
-    for san in pgn.iter() {
-        let m = san.to_move(&pos)?;
+    for (ply, san) in pgn.iter().enumerate() {
+        let illegal = || Error::IllegalMove {
+            ply,
+            san: san.to_string(),
+        };
+        let m = san.to_move(&pos).map_err(|_| illegal())?;
         // TODO: the castling mode needs to come from the game!!
         ret_val.push(Uci::from_move(&m, CastlingMode::Standard));
-        pos = pos.play(&m).map_err(|_pos| Error::PositionError)?;
+        pos = pos.play(&m).map_err(|_pos| illegal())?;
     }
     Ok(ret_val)
 }
 
+/// The reverse of `uci_from_position`: each move's SAN, with its check or mate suffix,
+/// and the position it leads to.
+pub fn san_from_uci(mut pos: Chess, moves: &[Uci]) -> Result<Vec<(String, Chess)>> {
+    let mut ret_val = Vec::new();
+    for (ply, uci) in moves.iter().enumerate() {
+        let illegal = || Error::IllegalMove {
+            ply,
+            san: uci.to_string(),
+        };
+        let m = uci.to_move(&pos).map_err(|_| illegal())?;
+        let san = San::from_move(&pos, &m);
+        pos = pos.play(&m).map_err(|_pos| illegal())?;
+        let suffix = if pos.is_checkmate() {
+            "#"
+        } else if pos.is_check() {
+            "+"
+        } else {
+            ""
+        };
+        ret_val.push((format!("{}{}", san, suffix), pos.clone()));
+    }
+    Ok(ret_val)
+}
+
+impl Game {
+    /// Whether we can analyze the game's variant; games that don't name one are standard.
+    pub fn is_supported(&self) -> bool {
+        let variant = self.variant.as_deref().unwrap_or(DEFAULT_VARIANT);
+        SUPPORTED_VARIANTS.contains(&variant)
+    }
+
+    /// Ids have to be well formed, and per-move data has to line up with the moves,
+    /// otherwise zipping them together later silently drops whatever doesn't fit.
+    pub fn validate(&self) -> Result<()> {
+        self.id.0.parse::<GameId>()?;
+        self.white.0.parse::<UserId>()?;
+        self.black.0.parse::<UserId>()?;
+        let expected = self.pgn.len();
+        let per_move = [
+            ("emts", self.emts.as_ref().map(Vec::len)),
+            ("analysis", self.analysis.as_ref().map(Vec::len)),
+        ];
+        for &(field, actual) in per_move.iter() {
+            match actual {
+                Some(actual) if actual != expected => {
+                    return Err(Error::MisalignedGame {
+                        game_id: self.id.0.clone(),
+                        field,
+                        expected,
+                        actual,
+                    })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
 impl TryFrom<&Game> for CreateGame {
     type Error = Error;
 
     fn try_from(g: &Game) -> StdResult<CreateGame, Self::Error> {
+        g.validate()?;
         let g = g.clone();
+        let variant = g.variant.as_deref().unwrap_or(DEFAULT_VARIANT);
+        if !SUPPORTED_VARIANTS.contains(&variant) {
+            return Err(Error::UnsupportedVariant(variant.to_string()));
+        }
+        let start = initial_position(&g.id, "initialFen", g.initial_fen.as_deref())?;
         Ok(CreateGame {
+            pgn: uci_from_position(start, &g.pgn)?,
             game_id: g.id,
             emts: g.emts.unwrap_or_else(Vec::new),
-            pgn: uci_from_san(&g.pgn)?,
             black: Some(g.black),
             white: Some(g.white),
+            variant: Some(variant.to_string()),
+            // NOTE: irwin doesn't send the time control.
+            clock: None,
+            initial_fen: g.initial_fen,
         })
     }
 }
@@ -92,6 +201,9 @@ impl TryFrom<&Game> for CreateGame {
 pub struct Request {
     pub t: String,
     pub origin: ReportOrigin,
+    /// What triggered the request: a tournament id, a moderator's username, etc.
+    #[serde(default, rename = "originDetail")]
+    pub origin_detail: Option<String>,
     pub user: User,
     pub games: Vec<Game>,
 }
@@ -101,115 +213,556 @@ impl From<Request> for CreateReport {
         CreateReport {
             user_id: request.user.id,
             origin: request.origin,
+            origin_detail: request.origin_detail,
             report_type: ReportType::Irwin,
             games: request.games.iter().map(|g| g.id.clone()).collect(),
         }
     }
 }
 
-impl From<Request> for Vec<CreateJob> {
-    fn from(request: Request) -> Vec<CreateJob> {
-        request
-            .games
-            .iter()
-            .map(|g| CreateJob {
-                game_id: g.id.clone(),
-                report_id: None,
-                analysis_type: AnalysisType::Deep,
-                precedence: precedence_for_origin(request.clone().origin),
-            })
-            .collect()
+/// What `add_to_queue` did with a request.
+#[derive(Serialize, Debug, Clone)]
+pub struct Queued {
+    pub report_id: ReportId,
+    /// Games stored, whether new or changed since they were last sent.
+    pub games_inserted: usize,
+    /// Games already stored exactly as sent.
+    pub games_skipped: usize,
+    /// Games in variants we can't analyze. They get a job that is set aside from the
+    /// start, and the report goes ahead without them.
+    pub games_unsupported: usize,
+    pub jobs_created: usize,
+    /// Jobs that reuse recent analysis and so start out complete.
+    pub jobs_reused: usize,
+}
+
+impl Queued {
+    /// False when every job started out complete or set aside. Nothing will complete
+    /// then, so the report has to be checked right away; see `check_report`.
+    pub fn needs_analysis(&self) -> bool {
+        self.jobs_reused + self.games_unsupported < self.jobs_created
     }
 }
 
-pub async fn add_to_queue(db: DbConn, request: Request) -> Result<()> {
+/// Queues a job per game of the request. Games already analysed at the same tier
+/// within `reuse_within` get a job that is complete from the start and reuses that
+/// analysis, but stay part of the report.
+pub async fn add_to_queue(
+    db: DbConn,
+    precedence: &PrecedenceConfig,
+    origin_analysis: &OriginAnalysisConfig,
+    reuse_within: Option<chrono::Duration>,
+    mut request: Request,
+) -> Result<Queued> {
+    request.user.id.0.parse::<UserId>()?;
+    let (games, unsupported): (Vec<Game>, Vec<Game>) =
+        request.games.into_iter().partition(Game::is_supported);
+    request.games = games;
+    if !unsupported.is_empty() {
+        warn!(
+            "add_to_queue > {} > setting aside {} games in unsupported variants",
+            request.user.id,
+            unsupported.len()
+        );
+    }
+    // NOTE: a report with no games would never complete, so turn it away as before.
+    if request.games.is_empty() {
+        if let Some(game) = unsupported.first() {
+            let variant = game.variant.clone().unwrap_or_default();
+            return Err(Error::UnsupportedVariant(variant));
+        }
+    }
     let games_with_uci = request
         .games
         .iter()
         .map(TryInto::try_into)
         .collect::<Result<Vec<CreateGame>>>()?;
-    try_join_all(insert_many_games(
-        db.clone(),
-        games_with_uci.iter().cloned(),
-    ))
+    let upserted = try_join_all(
+        games_with_uci
+            .into_iter()
+            .map(|game| upsert_game(db.clone(), game)),
+    )
     .await?;
+    let games_inserted = upserted.iter().filter(|(_, written)| *written).count();
 
+    let bonus = if precedence.repeat_user_bonus > 0 {
+        let prior = count_reports_for_user(db.clone(), request.user.id.clone()).await?;
+        repeat_user_bonus(prior, precedence)
+    } else {
+        0
+    };
     let report_id = insert_one_report(db.clone(), request.clone().into()).await?;
 
-    let fishnet_jobs: Vec<CreateJob> = request.into();
-    let fishnet_jobs: Vec<CreateJob> = fishnet_jobs
+    let precedence =
+        precedence_for_origin(request.origin.clone(), precedence).saturating_add(bonus);
+    let analysis_type = analysis_type_for_origin(request.origin.clone(), origin_analysis);
+    let mut fishnet_jobs = Vec::new();
+    for g in request.games.iter() {
+        let reusable = match reuse_within {
+            Some(within) if g.target_plies.is_none() && g.color.is_none() => {
+                let since = db.now() - within;
+                find_reusable_job(db.clone(), g.id.clone(), analysis_type.clone(), since).await?
+            }
+            _ => None,
+        };
+        fishnet_jobs.push(CreateJob {
+            game_id: g.id.clone(),
+            report_id: Some(report_id.clone()),
+            analysis_type: analysis_type.clone(),
+            precedence,
+            target_plies: g.target_plies.clone(),
+            color: g.color,
+            analysis_from: reusable.map(|job| job.analysis_job_id()),
+            label: None,
+            callback_url: None,
+            unsupported: None,
+        });
+    }
+    let reused = fishnet_jobs
         .iter()
-        .map(|j: &CreateJob| CreateJob {
-            game_id: j.game_id.clone(),
+        .filter(|job| job.analysis_from.is_some())
+        .count();
+    if reused > 0 {
+        info!(
+            "add_to_queue > {} > reusing recent analysis for {} of {} games",
+            report_id,
+            reused,
+            fishnet_jobs.len()
+        );
+    }
+
+    // NOTE: the games themselves aren't stored, their moves can't be read as standard chess.
+    for g in unsupported.iter() {
+        fishnet_jobs.push(CreateJob {
+            game_id: g.id.clone(),
             report_id: Some(report_id.clone()),
-            analysis_type: j.analysis_type.clone(),
-            precedence: j.precedence,
-        })
-        .collect();
+            analysis_type: analysis_type.clone(),
+            precedence,
+            target_plies: None,
+            color: None,
+            analysis_from: None,
+            label: None,
+            callback_url: None,
+            unsupported: Some(format!(
+                "unsupported variant: {}",
+                g.variant.as_deref().unwrap_or_default()
+            )),
+        });
+    }
 
     try_join_all(insert_many_jobs(db.clone(), fishnet_jobs.iter().by_ref())).await?;
+    Ok(Queued {
+        report_id,
+        games_inserted,
+        games_skipped: upserted.len() - games_inserted,
+        games_unsupported: unsupported.len(),
+        jobs_created: fishnet_jobs.len(),
+        jobs_reused: reused,
+    })
+}
+
+/// A completed report in the shape irwin expects it back.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct IrwinJob {
+    pub player_id: UserId,
+    pub origin: ReportOrigin,
+    pub games: Vec<IrwinGame>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug, Clone)]
+pub struct IrwinGame {
+    pub id: GameId,
+    pub white: Option<UserId>,
+    pub black: Option<UserId>,
+    pub emts: Vec<i32>,
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, Uci>")]
+    pub pgn: Vec<Uci>,
+    /// One entry per position, starting from the initial one.
+    pub analysis: Vec<Option<Score>>,
+}
+
+fn irwin_game(game: crate::deepq::model::Game, analysis: GameAnalysis) -> Result<IrwinGame> {
+    let expected = game.pgn.len() + 1;
+    if analysis.analysis.len() != expected {
+        return Err(Error::MisalignedGame {
+            game_id: game._id.0.clone(),
+            field: "analysis",
+            expected,
+            actual: analysis.analysis.len(),
+        });
+    }
+    Ok(IrwinGame {
+        id: game._id,
+        white: game.white,
+        black: game.black,
+        emts: game.emts,
+        pgn: game.pgn,
+        analysis: analysis
+            .analysis
+            .iter()
+            .map(|ply| ply.as_ref().and_then(|ply| ply.score()).cloned())
+            .collect(),
+    })
+}
+
+async fn irwin_game_for_job(
+    db: DbConn,
+    report_id: &ReportId,
+    job: Job,
+    allow_missing: bool,
+) -> Result<Option<IrwinGame>> {
+    let game = find_game(db.clone(), job.game_id.clone()).await?;
+    let analysis = find_analysis_for_job(db, job.analysis_job_id()).await?;
+    // NOTE: jobs still being worked on may have partial analysis stored already.
+    match (game, analysis) {
+        (Some(game), Some(analysis)) if job.is_complete || !allow_missing => {
+            Ok(Some(irwin_game(game, analysis)?))
+        }
+        _ if allow_missing => {
+            debug!(
+                "irwin_game_for_job > {} > skipping {}",
+                report_id, job.game_id
+            );
+            Ok(None)
+        }
+        _ => Err(Error::IncompleteReport {
+            report_id: report_id.to_string(),
+            game_id: job.game_id.0,
+        }),
+    }
+}
+
+/// Streams the analysed games of a report one at a time. With `allow_missing`, games
+/// that were purged or aren't fully analysed are left out instead of failing the report.
+pub async fn irwin_games_from_report(
+    db: DbConn,
+    report: &Report,
+    allow_missing: bool,
+) -> Result<impl Stream<Item = Result<IrwinGame>>> {
+    let report_id = report._id.clone();
+    let jobs = Job::find_by_report(db.clone(), report.clone()).await?;
+    Ok(jobs.filter_map(move |job| {
+        let db = db.clone();
+        let report_id = report_id.clone();
+        async move {
+            match job {
+                Ok(job) if job.is_set_aside() => None,
+                Ok(job) => irwin_game_for_job(db, &report_id, job, allow_missing)
+                    .await
+                    .transpose(),
+                Err(err) => Some(Err(err)),
+            }
+        }
+    }))
+}
+
+fn irwin_job(report: &Report, games: Vec<IrwinGame>) -> IrwinJob {
+    IrwinJob {
+        player_id: report.user_id.clone(),
+        origin: report.origin.clone(),
+        games,
+    }
+}
+
+/// Collects every analysed game of a report into a single job; see
+/// `irwin_games_from_report` for `allow_missing`.
+pub async fn irwin_job_from_report(
+    db: DbConn,
+    report: &Report,
+    allow_missing: bool,
+) -> Result<IrwinJob> {
+    let games = irwin_games_from_report(db, report, allow_missing)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(irwin_job(report, games))
+}
+
+/// The client every submission goes through. Build it once and share it, so
+/// connections to irwin are reused.
+pub fn submit_client(config: &IrwinConfig) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.submit_connect_timeout_secs))
+        .timeout(Duration::from_secs(config.submit_timeout_secs))
+        .build()?)
+}
+
+fn submit_error(err: reqwest::Error) -> Error {
+    if err.is_timeout() {
+        Error::IrwinTimeout
+    } else {
+        err.into()
+    }
+}
+
+/// Whether a failed submission says irwin itself is down or struggling: it timed out,
+/// couldn't be reached, or answered with a 5xx. A 4xx is about the report we sent.
+fn is_irwin_outage(err: &Error) -> bool {
+    match err {
+        Error::IrwinTimeout => true,
+        Error::IrwinStreamError(err) => {
+            err.is_connect() || err.status().map_or(false, |s| s.is_server_error())
+        }
+        _ => false,
+    }
+}
+
+pub async fn submit_to_irwin(
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    job: &IrwinJob,
+) -> Result<()> {
+    let url = config
+        .submit_url
+        .as_ref()
+        .ok_or(Error::MissingConfig("irwin.submit_url"))?;
+    let mut request = client
+        .post(url)
+        .header("User-Agent", "lila-deepq")
+        .json(job);
+    if let Some(token) = &config.submit_token {
+        request = request.bearer_auth(token);
+    }
+    request
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(submit_error)?;
     Ok(())
 }
 
-async fn handle_job_acquired(_db: DbConn, job_id: JobId) {
-    let p = "handle_job_acquired >";
-    debug!("{} Fishnet::JobAcquired({})", p, job_id);
+/// Submits a report to irwin, returning the games that were sent. Reports with more
+/// than `submit_chunk_games` games are streamed and sent as several jobs of at most
+/// that many games, so the whole report is never held in memory. A failure part way
+/// through leaves the earlier chunks submitted; they are recorded on the report and
+/// left out when it is submitted again.
+pub async fn submit_report(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    report: &Report,
+    allow_missing: bool,
+) -> Result<Vec<GameId>> {
+    let chunk = match config.submit_chunk_games {
+        Some(chunk) if chunk > 0 && report.games.len() > chunk => chunk,
+        _ => {
+            let mut job = irwin_job_from_report(db, report, allow_missing).await?;
+            job.games.retain(|game| !report.was_submitted(&game.id));
+            if !job.games.is_empty() || report.submitted_games.is_empty() {
+                submit_to_irwin(config, client, &job).await?;
+            }
+            return Ok(job.games.into_iter().map(|game| game.id).collect());
+        }
+    };
+    let mut games = Box::pin(irwin_games_from_report(db.clone(), report, allow_missing).await?);
+    let mut submitted = Vec::new();
+    let mut batch = Vec::with_capacity(chunk);
+    loop {
+        let game = games.next().await.transpose()?;
+        let done = game.is_none();
+        batch.extend(game.filter(|game| !report.was_submitted(&game.id)));
+        if batch.len() == chunk || (done && !batch.is_empty()) {
+            debug!(
+                "submit_report > {} > submitting {} games",
+                report._id,
+                batch.len()
+            );
+            let job = irwin_job(report, std::mem::take(&mut batch));
+            submit_to_irwin(config, client, &job).await?;
+            let sent: Vec<GameId> = job.games.into_iter().map(|game| game.id).collect();
+            record_submitted_games(db.clone(), report._id.clone(), &sent).await?;
+            submitted.extend(sent);
+        }
+        if done {
+            return Ok(submitted);
+        }
+    }
 }
 
-async fn handle_job_aborted(_db: DbConn, job_id: JobId) {
-    let p = "handle_job_aborted >";
-    debug!("{} Fishnet::JobAborted({})", p, job_id);
+/// Builds and submits a report that has already been claimed with
+/// `atomically_update_sent_to_irwin`. On failure the claim is released again so the
+/// report shows up for `resubmit-irwin`.
+async fn submit_claimed_report(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    report: &Report,
+    allow_missing: bool,
+) -> Result<()> {
+    let result = submit_report(db.clone(), config, client, report, allow_missing)
+        .await
+        .map(|_| ());
+    if result.is_err() {
+        unmark_sent_to_irwin(db, report._id.clone()).await?;
+    }
+    result
 }
 
-async fn handle_job_completed(db: DbConn, job_id: JobId) {
-    let p = "handle_job_completed >";
-    match get_job(db.clone(), job_id.clone().into()).await {
+/// Like `submit_claimed_report`, also telling the breaker how irwin took it. Call it
+/// only after `CircuitBreaker::allow`.
+async fn submit_through_breaker(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    breaker: &CircuitBreaker,
+    report: &Report,
+    allow_missing: bool,
+) -> Result<()> {
+    match submit_claimed_report(db.clone(), config, client, report, allow_missing).await {
+        Ok(()) => {
+            breaker.record_success();
+            Ok(())
+        }
+        // NOTE: only irwin itself failing counts; a report we couldn't build or irwin
+        //       refused says nothing about whether irwin is up.
+        Err(err) if is_irwin_outage(&err) => {
+            breaker.record_failure(db.now());
+            Err(err)
+        }
         Err(err) => {
-            error!(
-                "{} Unable find job for {:?}. Error: {:?}",
-                p,
-                job_id.clone(),
-                err
-            );
+            breaker.release_trial();
+            Err(err)
         }
-        Ok(None) => {
-            error!("{} Unable find job for {:?}.", p, job_id.clone());
+    }
+}
+
+/// How many held reports `resubmit_held_reports` looks at each time it runs.
+const HELD_REPORTS_PER_PASS: i64 = 100;
+
+/// Sends complete reports that were held back, e.g. while the breaker was open. Stops
+/// as soon as the breaker stops letting submissions through, so while half-open at most
+/// the one trial submission is made, and none if the fishnet listener already took it.
+/// Returns how many reports were sent.
+pub async fn resubmit_held_reports(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    breaker: &CircuitBreaker,
+) -> Result<usize> {
+    let p = "resubmit_held_reports >";
+    // NOTE: `allow` would take the half-open trial, so only look at the state here.
+    if config.submit_url.is_none() || breaker.state(db.now()) == BreakerState::Open {
+        return Ok(0);
+    }
+    let mut sent = 0;
+    for report in find_held_reports(db.clone(), HELD_REPORTS_PER_PASS).await? {
+        if !breaker.allow(db.now()) {
+            break;
         }
-        Ok(Some(job)) => {
-            if let Some(report_id) = job.report_id {
-                match find_report(db.clone(), report_id.clone()).await {
-                    Err(err) => {
-                        error!(
-                            "{} Unable find report for {:?}. Error: {:?}",
-                            p,
-                            report_id.clone(),
-                            err
-                        );
-                    }
-                    Ok(None) => {
-                        error!("{} Unable find report for {:?}.", p, report_id.clone());
+        mark_report_checked(db.clone(), report._id.clone()).await?;
+        let report = match atomically_update_sent_to_irwin(db.clone(), report._id).await {
+            Ok(Some(report)) => report,
+            Ok(None) => {
+                breaker.release_trial();
+                continue;
+            }
+            Err(err) => {
+                breaker.release_trial();
+                return Err(err);
+            }
+        };
+        // NOTE: held reports may have been completed partially, see `resubmit_reports`.
+        match submit_through_breaker(db.clone(), config, client, breaker, &report, true).await {
+            Ok(()) => {
+                info!("{} Report({}) > sent", p, report._id);
+                sent += 1;
+            }
+            Err(err) => error!("{} Report({}) > unable to submit: {:?}", p, report._id, err),
+        }
+    }
+    Ok(sent)
+}
+
+#[derive(Debug, Default)]
+pub struct ResubmitCounts {
+    pub resubmitted: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// Re-sends completed reports that never made it to irwin. Reports another process
+/// claims in the meantime are skipped rather than sent twice.
+pub async fn resubmit_reports(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    reports: Vec<Report>,
+) -> Result<ResubmitCounts> {
+    let p = "resubmit_reports >";
+    let mut counts = ResubmitCounts::default();
+    for report in reports {
+        match atomically_update_sent_to_irwin(db.clone(), report._id.clone()).await? {
+            None => {
+                debug!("{} Report({}) > already claimed, skipping", p, report._id);
+                counts.skipped += 1;
+            }
+            Some(report) => {
+                // NOTE: a report may have been completed with some games unfinished, and
+                //       those games are still unfinished now.
+                let submitted =
+                    submit_claimed_report(db.clone(), config, client, &report, true).await;
+                match submitted {
+                    Ok(()) => {
+                        info!("{} Report({}) > resubmitted", p, report._id);
+                        counts.resubmitted += 1;
                     }
-                    Ok(Some(report)) => {
-                        debug!("{} Fishnet::JobCompleted({}) > handled", p, job_id);
-                        match update_report_completeness(db.clone(), report).await {
-                            Ok(_) => {}
-                            Err(err) => {
-                                error!(
-                                    "{} Unable to update report completness for report {:?}. Error: {:?}",
-                                    p,
-                                    report_id.clone(),
-                                    err
-                                );
-                            }
-                        }
+                    Err(err) => {
+                        error!("{} Report({}) > unable to submit: {:?}", p, report._id, err);
+                        counts.failed += 1;
                     }
                 }
             }
         }
     }
+    Ok(counts)
+}
+
+async fn handle_job_acquired(_db: DbConn, job_id: JobId) {
+    let p = "handle_job_acquired >";
+    debug!("{} Fishnet::JobAcquired({})", p, job_id);
+}
+
+async fn handle_job_aborted(_db: DbConn, job_id: JobId) {
+    let p = "handle_job_aborted >";
+    debug!("{} Fishnet::JobAborted({})", p, job_id);
+}
+
+async fn handle_job_completed(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    breaker: &CircuitBreaker,
+    job_id: JobId,
+) {
+    let p = "handle_job_completed >";
+    let job = match get_job(db.clone(), job_id.clone())
+        .await
+        .log_missing(format!("{} Unable to find job {}", p, job_id))
+    {
+        Some(job) => job,
+        None => return,
+    };
+    let report_id = match job.report_id {
+        Some(report_id) => report_id,
+        None => return,
+    };
+    let report = match find_report(db.clone(), report_id.clone())
+        .await
+        .log_missing(format!("{} Unable to find report {}", p, report_id))
+    {
+        Some(report) => report,
+        None => return,
+    };
+    debug!("{} Fishnet::JobCompleted({}) > handled", p, job_id);
+    update_report_completeness(db, config, client, breaker, report)
+        .await
+        .log_err(format!("{} Unable to update completeness of report {}", p, report_id));
 }
 
-async fn report_complete_percentage(db: DbConn, report: Report) -> Result<f64> {
+/// How much of the report is complete, or None if there's nothing that could ever be.
+async fn report_complete_percentage(db: DbConn, report: Report) -> Result<Option<f64>> {
     let p = "report_complete_percentage >";
     let mut jobs = Job::find_by_report(db.clone(), report.clone()).await?;
     let mut complete = 0f64;
@@ -217,6 +770,8 @@ async fn report_complete_percentage(db: DbConn, report: Report) -> Result<f64> {
 
     while let Some(job_result) = jobs.next().await {
         let is_complete = match job_result {
+            // NOTE: these will never complete, so they'd hold the report back forever.
+            Ok(job) if job.is_set_aside() => continue,
             Ok(job) => job.is_complete,
             Err(err) => {
                 error!(
@@ -234,20 +789,71 @@ async fn report_complete_percentage(db: DbConn, report: Report) -> Result<f64> {
             incomplete += 1f64;
         }
     }
-    Ok(complete / (complete + incomplete))
+    if complete + incomplete == 0f64 {
+        return Ok(None);
+    }
+    Ok(Some(complete / (complete + incomplete)))
 }
 
-async fn update_report_completeness(db: DbConn, report: Report) -> Result<()> {
+async fn update_report_completeness(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    breaker: &CircuitBreaker,
+    report: Report,
+) -> Result<()> {
     let p = "update_report_completeness";
-    let percentage = report_complete_percentage(db.clone(), report.clone()).await?;
-    if percentage >= 1f64 {
-        let updated_report = atomically_update_sent_to_irwin(db, report._id.clone()).await?;
+    if let Some(resolution) = &report.resolution {
+        info!(
+            "{} > Report({:?}) > already {} by {}",
+            &p, report._id, resolution.action, resolution.by
+        );
+        return Ok(());
+    }
+    let percentage = report_complete_percentage(db.clone(), report.clone())
+        .await?
+        .unwrap_or(0f64);
+    let stale = db.now() - report.date_requested.0 >= config.partial_submit_after();
+    let partial = percentage < 1f64 && percentage >= config.min_completion && stale;
+    if percentage >= 1f64 || partial {
+        if partial {
+            info!(
+                "{} > Report({:?}) > {:.1}% complete and stale, submitting what we have",
+                &p,
+                report._id,
+                percentage * 100f64
+            );
+        }
+        mark_report_complete(db.clone(), report._id.clone()).await?;
+        if config.submit_url.is_none() {
+            info!(
+                "{} > Report({:?}) > complete. No irwin submit url configured, not submitting",
+                &p, report._id
+            );
+            return Ok(());
+        }
+        if !breaker.allow(db.now()) {
+            warn!(
+                "{} > Report({:?}) > complete. Irwin breaker is open, leaving it unsent",
+                &p, report._id
+            );
+            return Ok(());
+        }
+        let updated_report = atomically_update_sent_to_irwin(db.clone(), report._id.clone())
+            .await
+            .map_err(|err| {
+                breaker.release_trial();
+                err
+            })?;
         if let Some(updated_report) = updated_report {
             info!(
                 "{} > Report({:?}) > complete. Submitting to irwin!",
                 &p, updated_report._id
             );
+            submit_through_breaker(db.clone(), config, client, breaker, &updated_report, partial)
+                .await?;
         } else {
+            breaker.release_trial();
             info!(
                 "{} > Report({:?}) > complete. Already submitted to irwin!",
                 &p, report._id
@@ -264,10 +870,136 @@ async fn update_report_completeness(db: DbConn, report: Report) -> Result<()> {
     Ok(())
 }
 
-pub async fn fishnet_listener(db: DbConn, tx: broadcast::Sender<FishnetMsg>) {
+/// Submits the report if it is complete; `update_report_completeness` for callers that
+/// only have its id.
+pub async fn check_report(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    breaker: &CircuitBreaker,
+    report_id: ReportId,
+) -> Result<()> {
+    let report = find_report(db.clone(), report_id)
+        .await?
+        .ok_or(Error::NotFoundError)?;
+    update_report_completeness(db, config, client, breaker, report).await
+}
+
+/// How many reports `submit_stale_reports` looks at each time it runs.
+const STALE_REPORTS_PER_PASS: i64 = 500;
+
+/// Looks again at reports old enough to be submitted partially. Completions only
+/// trigger a check while games are still finishing, so without this a report stuck on
+/// its last few games would never be looked at again. Reports left with no job that
+/// could complete are resolved as abandoned.
+pub async fn submit_stale_reports(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    breaker: &CircuitBreaker,
+) -> Result<()> {
+    let before = db.now() - config.partial_submit_after();
+    for report in find_incomplete_reports(db.clone(), before, STALE_REPORTS_PER_PASS).await? {
+        let report_id = report._id.clone();
+        mark_report_checked(db.clone(), report_id.clone()).await?;
+        // NOTE: e.g. every job expired or was set aside. Nothing would ever submit it.
+        if report_complete_percentage(db.clone(), report.clone()).await?.is_none() {
+            warn!(
+                "submit_stale_reports > Report({:?}) > no jobs left to complete, abandoning",
+                report_id
+            );
+            resolve_report(db.clone(), report_id, ReportAction::Abandoned, "deepq".to_string())
+                .await?;
+            continue;
+        }
+        let updated = update_report_completeness(db.clone(), config, client, breaker, report).await;
+        if let Err(err) = updated {
+            error!(
+                "submit_stale_reports > Report({:?}) > unable to update: {:?}",
+                report_id, err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Where `recheck_reports` got up to, kept in the database so a restart carries on
+/// from there rather than from some fixed window back.
+const RECHECK_WATERMARK: &str = "irwin_recheck";
+
+fn watermarks(db: DbConn) -> Collection {
+    db.database.collection("deepq_watermarks")
+}
+
+async fn get_watermark(db: DbConn, name: &str) -> Result<Option<DateTime<Utc>>> {
+    Ok(watermarks(db)
+        .find_one(doc! {"_id": name}, None)
+        .await?
+        .map(|watermark| watermark.get_datetime("at").map(|at| *at))
+        .transpose()?)
+}
+
+async fn set_watermark(db: DbConn, name: &str, at: DateTime<Utc>) -> Result<()> {
+    watermarks(db)
+        .update_one(
+            doc! {"_id": name},
+            UpdateModifications::Document(doc! {"$set": {"at": at}}),
+            Some(UpdateOptions::builder().upsert(true).build()),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Completions dropped while the listener lagged, or lost with a previous process,
+/// would leave their reports waiting forever. So look again at every unsent report with
+/// a job completed since the last recheck started, or at every unsent report the first
+/// time; ones that were handled after all are left as they are.
+async fn recheck_reports(
+    db: DbConn,
+    config: &IrwinConfig,
+    client: &reqwest::Client,
+    breaker: &CircuitBreaker,
+) -> Result<usize> {
+    let started = db.now();
+    let report_ids = match get_watermark(db.clone(), RECHECK_WATERMARK).await? {
+        Some(since) => reports_with_completed_jobs(db.clone(), since).await?,
+        None => unsent_report_ids(db.clone()).await?,
+    };
+    let mut checked = 0;
+    for report_id in report_ids {
+        let report = match find_report(db.clone(), report_id).await? {
+            Some(report) if !report.sent_to_irwin => report,
+            _ => continue,
+        };
+        let report_id = report._id.clone();
+        let updated = update_report_completeness(db.clone(), config, client, breaker, report).await;
+        if let Err(err) = updated {
+            error!(
+                "recheck_reports > Report({:?}) > unable to update: {:?}",
+                report_id, err
+            );
+        }
+        checked += 1;
+    }
+    set_watermark(db, RECHECK_WATERMARK, started).await?;
+    Ok(checked)
+}
+
+/// Acts on fishnet events. Takes a receiver rather than subscribing itself so the
+/// caller can subscribe before serving; see `FishnetMsg` for what delivery guarantees.
+pub async fn fishnet_listener(
+    db: DbConn,
+    config: IrwinConfig,
+    client: reqwest::Client,
+    breaker: CircuitBreaker,
+    mut rx: broadcast::Receiver<FishnetMsg>,
+) {
     let p = "fishnet_listener >";
     let mut should_stop: bool = false;
-    let mut rx = tx.subscribe();
+    match recheck_reports(db.clone(), &config, &client, &breaker).await {
+        Ok(checked) => info!("{} rechecked {} reports on start", p, checked),
+        Err(err) => error!("{} unable to recheck reports: {:?}", p, err),
+    }
     while !should_stop {
         let db = db.clone();
         let msg = rx.recv().await;
@@ -278,12 +1010,16 @@ pub async fn fishnet_listener(db: DbConn, tx: broadcast::Sender<FishnetMsg>) {
             } else if let FishnetMsg::JobAborted(id) = msg {
                 handle_job_aborted(db.clone(), id.clone()).await;
             } else if let FishnetMsg::JobCompleted(id) = msg {
-                handle_job_completed(db.clone(), id.clone()).await;
+                handle_job_completed(db.clone(), &config, &client, &breaker, id.clone()).await;
             }
         } else if let Err(e) = msg {
             match e {
                 RecvError::Lagged(n) => {
                     warn!("{} unable to keep up. Skip {} messages", p, n);
+                    match recheck_reports(db.clone(), &config, &client, &breaker).await {
+                        Ok(checked) => info!("{} rechecked {} reports after lag", p, checked),
+                        Err(err) => error!("{} unable to recheck reports: {:?}", p, err),
+                    }
                 }
                 RecvError::Closed => {
                     should_stop = true;
@@ -292,3 +1028,162 @@ pub async fn fishnet_listener(db: DbConn, tx: broadcast::Sender<FishnetMsg>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::TimeZone;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::db::{testing::test_db, ManualClock};
+    use crate::fishnet::api::{insert_one_job, set_complete};
+    use crate::fishnet::model::AnalysisType;
+
+    async fn report_with_one_job(db: DbConn) -> (ReportId, JobId) {
+        let game_id = GameId("abcdefgh".to_string());
+        let report = CreateReport {
+            user_id: UserId("someone".to_string()),
+            origin: ReportOrigin::Moderator,
+            origin_detail: None,
+            report_type: ReportType::Irwin,
+            games: vec![game_id.clone()],
+        };
+        let report_id = insert_one_report(db.clone(), report).await.unwrap();
+        let job = CreateJob {
+            game_id,
+            report_id: Some(report_id.clone()),
+            analysis_type: AnalysisType::Deep,
+            precedence: 0,
+            target_plies: None,
+            color: None,
+            analysis_from: None,
+            label: None,
+            callback_url: None,
+            unsupported: None,
+        };
+        let job_id = insert_one_job(db, job).await.unwrap();
+        (report_id, JobId(job_id))
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn recheck_finds_completions_missed_while_lagging() {
+        let clock = Arc::new(ManualClock::new(Utc.ymd(2021, 3, 1).and_hms(12, 0, 0)));
+        let db = test_db("recheck_lag").await.with_clock(clock.clone());
+        // NOTE: no submit url, so a complete report is only marked complete.
+        let config = IrwinConfig::default();
+        let client = submit_client(&config).unwrap();
+        let breaker = CircuitBreaker::new(1, chrono::Duration::minutes(5));
+        let recheck = || recheck_reports(db.clone(), &config, &client, &breaker);
+
+        let (report_id, job_id) = report_with_one_job(db.clone()).await;
+        // The first recheck has no watermark to go on, so it looks at every unsent report.
+        assert_eq!(recheck().await.unwrap(), 1);
+
+        // The job completes but the event is lost, and nothing looks again for hours.
+        clock.advance(chrono::Duration::minutes(1));
+        assert!(set_complete(db.clone(), job_id).await.unwrap());
+        clock.advance(chrono::Duration::hours(6));
+        assert_eq!(recheck().await.unwrap(), 1);
+        let report = find_report(db.clone(), report_id).await.unwrap().unwrap();
+        assert!(report.date_completed.is_some());
+
+        // Nothing has completed since the last recheck started.
+        clock.advance(chrono::Duration::minutes(1));
+        assert_eq!(recheck().await.unwrap(), 0);
+    }
+
+    /// Accepts one request on a local port and answers it with `status` after `delay`.
+    async fn mock_irwin(status: &'static str, delay: Duration) -> IrwinConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 64 * 1024];
+            let _ = socket.read(&mut buf).await;
+            tokio::time::sleep(delay).await;
+            let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status);
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+        IrwinConfig {
+            submit_url: Some(format!("http://{}/", addr)),
+            submit_timeout_secs: 1,
+            ..IrwinConfig::default()
+        }
+    }
+
+    fn empty_job() -> IrwinJob {
+        IrwinJob {
+            player_id: UserId("someone".to_string()),
+            origin: ReportOrigin::Moderator,
+            games: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn submission_times_out_on_a_slow_irwin() {
+        let config = mock_irwin("200 OK", Duration::from_secs(5)).await;
+        let client = submit_client(&config).unwrap();
+        let err = submit_to_irwin(&config, &client, &empty_job()).await.unwrap_err();
+        assert!(matches!(err, Error::IrwinTimeout), "{:?}", err);
+        assert!(is_irwin_outage(&err));
+    }
+
+    #[tokio::test]
+    async fn server_errors_count_against_irwin() {
+        let config = mock_irwin("503 Service Unavailable", Duration::from_secs(0)).await;
+        let client = submit_client(&config).unwrap();
+        let err = submit_to_irwin(&config, &client, &empty_job()).await.unwrap_err();
+        assert!(is_irwin_outage(&err), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn client_errors_dont_count_against_irwin() {
+        let config = mock_irwin("400 Bad Request", Duration::from_secs(0)).await;
+        let client = submit_client(&config).unwrap();
+        let err = submit_to_irwin(&config, &client, &empty_job()).await.unwrap_err();
+        assert!(!is_irwin_outage(&err), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn unreachable_irwin_counts_against_it() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let config = IrwinConfig {
+            submit_url: Some(format!("http://{}/", addr)),
+            ..IrwinConfig::default()
+        };
+        let client = submit_client(&config).unwrap();
+        let err = submit_to_irwin(&config, &client, &empty_job()).await.unwrap_err();
+        assert!(is_irwin_outage(&err), "{:?}", err);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs mongo: set LILA_DEEPQ_TEST_MONGO_URI"]
+    async fn reports_held_while_the_breaker_was_open_are_sent_once_it_lets_through() {
+        let start = Utc.ymd(2021, 3, 1).and_hms(12, 0, 0);
+        let clock = Arc::new(ManualClock::new(start));
+        let db = test_db("held_reports_sent_after_breaker").await.with_clock(clock.clone());
+        let config = mock_irwin("200 OK", Duration::from_secs(0)).await;
+        let client = submit_client(&config).unwrap();
+        let breaker = CircuitBreaker::new(1, chrono::Duration::minutes(5));
+        breaker.record_failure(db.now());
+
+        let (report_id, _) = report_with_one_job(db.clone()).await;
+        mark_report_complete(db.clone(), report_id.clone()).await.unwrap();
+        let sent = resubmit_held_reports(db.clone(), &config, &client, &breaker).await;
+        assert_eq!(sent.unwrap(), 0);
+
+        clock.advance(chrono::Duration::minutes(5));
+        let sent = resubmit_held_reports(db.clone(), &config, &client, &breaker).await;
+        assert_eq!(sent.unwrap(), 1);
+        let report = find_report(db.clone(), report_id).await.unwrap().unwrap();
+        assert!(report.sent_to_irwin);
+        assert_eq!(breaker.state(db.now()), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,   // Submitting as normal.
+    Open,     // Too many failures in a row; not submitting until the cooldown is up.
+    HalfOpen, // Cooldown is up; a single trial submission goes through to test the water.
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BreakerStatus {
+    state: BreakerState,
+    consecutive_failures: u32,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    consecutive_failures: u32,
+    opened_at: Option<DateTime<Utc>>,
+    trial_started_at: Option<DateTime<Utc>>,
+}
+
+/// Stops irwin submissions for `cooldown` after `threshold` of them fail in a row, so a
+/// dead irwin isn't hit once per completed report. Reports skipped while it is open stay
+/// complete but unsent, and `resubmit_held_reports` sends them once it lets submissions
+/// through again.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold: threshold.max(1),
+            cooldown,
+            inner: Arc::new(Mutex::new(Inner::default())),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<Inner> {
+        self.inner.lock().expect("breaker lock poisoned")
+    }
+
+    fn state_of(&self, inner: &Inner, now: DateTime<Utc>) -> BreakerState {
+        match inner.opened_at {
+            None => BreakerState::Closed,
+            Some(opened_at) if now - opened_at >= self.cooldown => BreakerState::HalfOpen,
+            Some(_) => BreakerState::Open,
+        }
+    }
+
+    pub fn state(&self, now: DateTime<Utc>) -> BreakerState {
+        self.state_of(&self.lock(), now)
+    }
+
+    /// Whether a submission may be attempted now. While half-open this hands out the one
+    /// trial submission, so callers must follow a `true` with a submission and report how
+    /// it went, or with `release_trial` if they end up not submitting.
+    // NOTE: the fishnet listener and `resubmit_held_reports` both submit at the same time,
+    //       so the trial is taken under the lock. A trial whose outcome never comes back
+    //       is given up on after another cooldown.
+    pub fn allow(&self, now: DateTime<Utc>) -> bool {
+        let mut inner = self.lock();
+        match self.state_of(&inner, now) {
+            BreakerState::Closed => true,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => match inner.trial_started_at {
+                Some(started_at) if now - started_at < self.cooldown => false,
+                _ => {
+                    inner.trial_started_at = Some(now);
+                    true
+                }
+            },
+        }
+    }
+
+    /// Hands back a half-open trial that didn't say whether irwin is up.
+    pub fn release_trial(&self) {
+        self.lock().trial_started_at = None;
+    }
+
+    pub fn record_success(&self) {
+        *self.lock() = Inner::default();
+    }
+
+    /// Counts a failed submission, opening (or reopening) the breaker at the threshold.
+    pub fn record_failure(&self, now: DateTime<Utc>) {
+        let mut inner = self.lock();
+        inner.consecutive_failures += 1;
+        if inner.consecutive_failures >= self.threshold {
+            inner.opened_at = Some(now);
+            inner.trial_started_at = None;
+        }
+    }
+
+    pub fn status(&self, now: DateTime<Utc>) -> BreakerStatus {
+        let inner = self.lock();
+        BreakerStatus {
+            state: self.state_of(&inner, now),
+            consecutive_failures: inner.consecutive_failures,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn start() -> DateTime<Utc> {
+        Utc.ymd(2021, 3, 1).and_hms(12, 0, 0)
+    }
+
+    #[test]
+    fn opens_after_threshold_failures_in_a_row() {
+        let breaker = CircuitBreaker::new(3, Duration::minutes(5));
+        breaker.record_failure(start());
+        breaker.record_failure(start());
+        assert_eq!(breaker.state(start()), BreakerState::Closed);
+        assert!(breaker.allow(start()));
+        breaker.record_failure(start());
+        assert_eq!(breaker.state(start()), BreakerState::Open);
+        assert!(!breaker.allow(start()));
+    }
+
+    #[test]
+    fn a_success_resets_the_count() {
+        let breaker = CircuitBreaker::new(2, Duration::minutes(5));
+        breaker.record_failure(start());
+        breaker.record_success();
+        breaker.record_failure(start());
+        assert_eq!(breaker.state(start()), BreakerState::Closed);
+    }
+
+    #[test]
+    fn half_opens_once_the_cooldown_is_up() {
+        let breaker = CircuitBreaker::new(1, Duration::minutes(5));
+        breaker.record_failure(start());
+        assert_eq!(breaker.state(start() + Duration::minutes(4)), BreakerState::Open);
+        let later = start() + Duration::minutes(5);
+        assert_eq!(breaker.state(later), BreakerState::HalfOpen);
+        assert!(breaker.allow(later));
+    }
+
+    #[test]
+    fn closes_when_the_half_open_trial_succeeds() {
+        let breaker = CircuitBreaker::new(1, Duration::minutes(5));
+        breaker.record_failure(start());
+        let later = start() + Duration::minutes(5);
+        breaker.record_success();
+        assert_eq!(breaker.state(later), BreakerState::Closed);
+    }
+
+    #[test]
+    fn reopens_when_the_half_open_trial_fails() {
+        let breaker = CircuitBreaker::new(1, Duration::minutes(5));
+        breaker.record_failure(start());
+        let later = start() + Duration::minutes(5);
+        assert_eq!(breaker.state(later), BreakerState::HalfOpen);
+        breaker.record_failure(later);
+        assert_eq!(breaker.state(later), BreakerState::Open);
+        assert_eq!(breaker.state(later + Duration::minutes(5)), BreakerState::HalfOpen);
+    }
+
+    #[test]
+    fn half_open_lets_a_single_trial_through() {
+        let breaker = CircuitBreaker::new(1, Duration::minutes(5));
+        breaker.record_failure(start());
+        let later = start() + Duration::minutes(5);
+        assert!(breaker.allow(later));
+        assert!(!breaker.allow(later));
+        assert!(!breaker.allow(later + Duration::minutes(4)));
+        breaker.release_trial();
+        assert!(breaker.allow(later + Duration::minutes(4)));
+    }
+
+    #[test]
+    fn a_lost_trial_is_given_up_on_after_a_cooldown() {
+        let breaker = CircuitBreaker::new(1, Duration::minutes(5));
+        breaker.record_failure(start());
+        let later = start() + Duration::minutes(5);
+        assert!(breaker.allow(later));
+        assert!(breaker.allow(later + Duration::minutes(5)));
+    }
+
+    #[test]
+    fn a_failed_trial_frees_the_next_one() {
+        let breaker = CircuitBreaker::new(1, Duration::minutes(5));
+        breaker.record_failure(start());
+        let later = start() + Duration::minutes(5);
+        assert!(breaker.allow(later));
+        breaker.record_failure(later);
+        assert!(!breaker.allow(later));
+        assert!(breaker.allow(later + Duration::minutes(5)));
+    }
+}
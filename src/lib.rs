@@ -15,6 +15,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod config;
 pub mod db;
 pub mod deepq;
 pub mod error;
@@ -22,3 +23,4 @@ pub mod fishnet;
 pub mod irwin;
 pub mod http;
 pub mod lichess;
+pub mod tools;
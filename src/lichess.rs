@@ -15,3 +15,117 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+use std::convert::{TryFrom, TryInto};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_with::{serde_as, SpaceSeparator, StringWithSeparator};
+use shakmaty::san::San;
+
+use crate::config::LichessConfig;
+use crate::deepq::api::{initial_position, CreateGame};
+use crate::deepq::model::{GameClock, GameId, UserId};
+use crate::error::{Error, Result};
+use crate::fishnet::handlers::SUPPORTED_VARIANTS;
+use crate::irwin::api::uci_from_position;
+
+#[derive(Deserialize, Debug, Clone)]
+struct ExportedUser {
+    id: UserId,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ExportedPlayer {
+    // NOTE: anonymous players and the AI have no user.
+    #[serde(default)]
+    user: Option<ExportedUser>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ExportedPlayers {
+    white: ExportedPlayer,
+    black: ExportedPlayer,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ExportedClock {
+    initial: i32,
+    increment: i32,
+}
+
+/// The subset of lichess's JSON game export that we store.
+#[serde_as]
+#[derive(Deserialize, Debug, Clone)]
+struct ExportedGame {
+    id: GameId,
+    variant: String,
+    players: ExportedPlayers,
+    #[serde_as(as = "StringWithSeparator::<SpaceSeparator, San>")]
+    moves: Vec<San>,
+    // NOTE: correspondence and unlimited games have no clock.
+    #[serde(default)]
+    clock: Option<ExportedClock>,
+    // NOTE: only exported for games from a position.
+    #[serde(rename = "initialFen", default)]
+    initial_fen: Option<String>,
+}
+
+impl TryFrom<ExportedGame> for CreateGame {
+    type Error = Error;
+
+    fn try_from(game: ExportedGame) -> Result<CreateGame> {
+        if !SUPPORTED_VARIANTS.contains(&game.variant.as_str()) {
+            return Err(Error::UnsupportedVariant(game.variant));
+        }
+        let start = initial_position(&game.id, "initialFen", game.initial_fen.as_deref())?;
+        Ok(CreateGame {
+            pgn: uci_from_position(start, &game.moves)?,
+            game_id: game.id,
+            // NOTE: the export only has clock times, not move times.
+            emts: Vec::new(),
+            black: game.players.black.user.map(|u| u.id),
+            white: game.players.white.user.map(|u| u.id),
+            variant: Some(game.variant),
+            clock: game.clock.map(|clock| GameClock {
+                initial: clock.initial,
+                increment: clock.increment,
+            }),
+            initial_fen: game.initial_fen,
+        })
+    }
+}
+
+/// The client game exports go through, built once so they share connections.
+pub fn client(config: &LichessConfig) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(config.export_connect_timeout_secs))
+        .timeout(Duration::from_secs(config.export_timeout_secs))
+        .build()?)
+}
+
+/// Fetches a game from lichess's game export. Returns None if lichess doesn't know it.
+pub async fn export_game(
+    client: &reqwest::Client,
+    config: &LichessConfig,
+    game_id: &GameId,
+) -> Result<Option<CreateGame>> {
+    let url = format!(
+        "{}/game/export/{}",
+        config.api_url.trim_end_matches('/'),
+        game_id
+    );
+    let mut request = client
+        .get(&url)
+        .query(&[("clocks", "false"), ("evals", "false"), ("opening", "false")])
+        .header("Accept", "application/json")
+        .header("User-Agent", "lila-deepq");
+    if let Some(token) = &config.token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let game: ExportedGame = response.error_for_status()?.json().await?;
+    Ok(Some(game.try_into()?))
+}
@@ -14,6 +14,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
 
+pub mod config;
 pub mod db;
 pub mod deepq;
 pub mod error;
@@ -21,6 +22,7 @@ pub mod fishnet;
 pub mod http;
 pub mod irwin;
 pub mod lichess;
+pub mod tools;
 
 extern crate clap;
 extern crate dotenv;
@@ -30,6 +32,8 @@ extern crate pretty_env_logger;
 extern crate serde_json;
 extern crate serde_with;
 
+use std::env;
+use std::io::Write;
 use std::net::SocketAddr;
 use std::result::Result as StdResult;
 
@@ -46,23 +50,33 @@ enum Command {
     DeepQWebserver(DeepQWebserver),
     IrwinJobListener(IrwinJobListener),
     FishnetNewUser(FishnetNewUser),
+    ResubmitIrwin(ResubmitIrwin),
 }
 
 #[derive(Debug, StructOpt, Clone)]
 struct DatabaseOpts {
     #[structopt(long, env = "LILA_DEEPQ_MONGO_URI")]
-    mongo_uri: String,
+    mongo_uri: Option<String>,
 
     #[structopt(long, env = "LILA_DEEPQ_MONGO_DATABASE")]
-    mongo_database: String,
+    mongo_database: Option<String>,
+
+    /// "majority" (the default), a node count, or a replica set tag.
+    #[structopt(long, env = "LILA_DEEPQ_MONGO_WRITE_CONCERN")]
+    mongo_write_concern: Option<String>,
 }
 
-impl From<DatabaseOpts> for db::ConnectionOpts {
-    fn from(db_opts: DatabaseOpts) -> db::ConnectionOpts {
-        db::ConnectionOpts {
-            mongo_uri: db_opts.mongo_uri,
-            mongo_database: db_opts.mongo_database,
-        }
+impl DatabaseOpts {
+    fn overlay(&self, config: &mut config::DatabaseConfig) {
+        config.mongo_uri = self.mongo_uri.clone().or_else(|| config.mongo_uri.take());
+        config.mongo_database = self
+            .mongo_database
+            .clone()
+            .or_else(|| config.mongo_database.take());
+        config.write_concern = self
+            .mongo_write_concern
+            .clone()
+            .or_else(|| config.write_concern.take());
     }
 }
 
@@ -70,38 +84,258 @@ impl From<DatabaseOpts> for db::ConnectionOpts {
 #[structopt(about = "Runs the main lila-deepq webserver.")]
 struct DeepQWebserver {
     #[structopt(short, long, env = "LILA_DEEPQ_WEBSERVER_HOST")]
-    host: String,
+    host: Option<String>,
 
     #[structopt(short, long, env = "LILA_DEEPQ_WEBSERVER_PORT")]
-    port: u16,
+    port: Option<u16>,
+
+    #[structopt(long, env = "LILA_DEEPQ_CHANNEL_SIZE")]
+    channel_size: Option<usize>,
+
+    #[structopt(long, env = "LILA_DEEPQ_JOB_MAX_AGE_DAYS")]
+    job_max_age_days: Option<i64>,
+
+    /// Apply pending database migrations before serving. Also read from
+    /// LILA_DEEPQ_RUN_MIGRATIONS, see `env_flag`.
+    #[structopt(long)]
+    run_migrations: bool,
+
+    /// PEM certificate chain; serves HTTPS when given along with --tls-key.
+    #[structopt(long, env = "LILA_DEEPQ_TLS_CERT")]
+    tls_cert: Option<String>,
+
+    #[structopt(long, env = "LILA_DEEPQ_TLS_KEY")]
+    tls_key: Option<String>,
+
+    #[structopt(long, env = "LILA_DEEPQ_CORS_ALLOWED_ORIGINS", use_delimiter = true)]
+    cors_allowed_origins: Option<Vec<String>>,
+
+    #[structopt(long, env = "LILA_DEEPQ_CORS_ALLOWED_METHODS", use_delimiter = true)]
+    cors_allowed_methods: Option<Vec<String>>,
 
     #[structopt(flatten)]
     database_opts: DatabaseOpts,
 }
 
-async fn deepq_web(args: &DeepQWebserver) -> StdResult<(), Box<dyn std::error::Error>> {
+impl DeepQWebserver {
+    fn overlay(&self, config: &mut config::Config) -> error::Result<()> {
+        self.database_opts.overlay(&mut config.database);
+        let webserver = &mut config.webserver;
+        if let Some(host) = &self.host {
+            webserver.host = host.clone();
+        }
+        if let Some(port) = self.port {
+            webserver.port = port;
+        }
+        if let Some(channel_size) = self.channel_size {
+            webserver.channel_size = channel_size;
+        }
+        if let Some(job_max_age_days) = self.job_max_age_days {
+            webserver.job_max_age_days = job_max_age_days;
+        }
+        if self.run_migrations || env_flag("LILA_DEEPQ_RUN_MIGRATIONS")? {
+            webserver.run_migrations = true;
+        }
+        webserver.tls_cert = self.tls_cert.clone().or_else(|| webserver.tls_cert.take());
+        webserver.tls_key = self.tls_key.clone().or_else(|| webserver.tls_key.take());
+        if let Some(origins) = &self.cors_allowed_origins {
+            webserver.cors.allowed_origins = origins.clone();
+        }
+        if let Some(methods) = &self.cors_allowed_methods {
+            webserver.cors.allowed_methods = methods.clone();
+        }
+        Ok(())
+    }
+}
+
+/// structopt's `env` on a bare flag only checks that the variable is set, so
+/// `LILA_DEEPQ_RUN_MIGRATIONS=false` would turn it on. Parse these by hand instead.
+fn env_flag(name: &'static str) -> error::Result<bool> {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| error::Error::InvalidConfig { field: name, value }),
+        Err(env::VarError::NotPresent) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn deepq_web(config: &config::Config) -> StdResult<(), Box<dyn std::error::Error>> {
+    let tls = config.webserver.tls_paths()?;
+
     info!("Connecting to database...");
-    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let conn = db::connection(&config.database.connection_opts()?).await?;
+
+    if config.webserver.run_migrations {
+        info!("Running migrations...");
+        let applied = db::migrations::run_pending(conn.clone()).await?;
+        info!("Applied {} migrations", applied);
+    } else {
+        let pending = db::migrations::pending(conn.clone()).await?;
+        if !pending.is_empty() {
+            error!("Pending migrations: {}", pending.join(", "));
+            return Err(error::Error::PendingMigrations(pending.len()).into());
+        }
+    }
 
-    // TODO: should probably make the 16 configurable.
     info!("Starting Fishnet Actor...");
-    let fishnet = fishnet::Actor::new(16);
+    let fishnet = fishnet::Actor::new(config.webserver.channel_size);
     info!("Mounting urls...");
-    let app = fishnet.handlers(conn.clone());
+    let breaker = irwin::breaker::CircuitBreaker::new(
+        config.irwin.breaker_failures,
+        chrono::Duration::seconds(config.irwin.breaker_cooldown_secs),
+    );
+    let irwin_client = irwin::api::submit_client(&config.irwin)?;
+    let callback_client = fishnet::callback::client(&config.fishnet)?;
+    let export_client = lichess::client(&config.lichess)?;
+    let app = fishnet.handlers(
+        conn.clone(),
+        config,
+        breaker.clone(),
+        callback_client,
+        export_client,
+    );
+    let deepq = deepq::handlers::mount(conn.clone(), config, irwin_client.clone());
 
+    if let Err(err) = fishnet::api::ensure_job_search_indexes(conn.clone()).await {
+        error!("Unable to create job search indexes: {:?}", err);
+    }
+    if let Err(err) = deepq::api::ensure_report_game_index(conn.clone()).await {
+        error!("Unable to create report game index: {:?}", err);
+    }
+    if let Err(err) = deepq::api::ensure_report_user_index(conn.clone()).await {
+        error!("Unable to create report user index: {:?}", err);
+    }
+
+    let expiry_conn = conn.clone();
+    let max_age = chrono::Duration::days(config.webserver.job_max_age_days);
+    tokio::spawn(async move {
+        info!("Starting job expiry...");
+        loop {
+            match fishnet::api::expire_old_jobs(expiry_conn.clone(), max_age).await {
+                Ok(expired) => debug!("Expired {} jobs", expired),
+                Err(err) => error!("Unable to expire old jobs: {:?}", err),
+            }
+            sleep(Duration::from_secs(60 * 60)).await;
+        }
+    });
+
+    let sample_conn = conn.clone();
+    let retention = chrono::Duration::days(config.webserver.queue_sample_retention_days);
+    let sample_interval = Duration::from_secs(config.webserver.queue_sample_secs);
+    tokio::spawn(async move {
+        info!("Starting queue sampler...");
+        let ttl = fishnet::api::ensure_queue_sample_ttl(sample_conn.clone(), retention).await;
+        if let Err(err) = ttl {
+            error!("Unable to set queue sample retention: {:?}", err);
+        }
+        loop {
+            if let Err(err) = fishnet::api::record_queue_samples(sample_conn.clone()).await {
+                error!("Unable to sample queue depth: {:?}", err);
+            }
+            sleep(sample_interval).await;
+        }
+    });
+
+    if config.irwin.min_completion < 1.0 {
+        let stale_conn = conn.clone();
+        let stale_config = config.irwin.clone();
+        let stale_client = irwin_client.clone();
+        let stale_breaker = breaker.clone();
+        tokio::spawn(async move {
+            info!("Starting stale report checks...");
+            loop {
+                sleep(Duration::from_secs(10 * 60)).await;
+                let submitted = irwin::api::submit_stale_reports(
+                    stale_conn.clone(),
+                    &stale_config,
+                    &stale_client,
+                    &stale_breaker,
+                )
+                .await;
+                if let Err(err) = submitted {
+                    error!("Unable to check stale reports: {:?}", err);
+                }
+            }
+        });
+    }
+
+    if config.irwin.submit_url.is_some() {
+        let held_conn = conn.clone();
+        let held_config = config.irwin.clone();
+        let held_client = irwin_client.clone();
+        let held_breaker = breaker.clone();
+        tokio::spawn(async move {
+            info!("Starting held report resubmission...");
+            loop {
+                sleep(Duration::from_secs(60)).await;
+                let sent = irwin::api::resubmit_held_reports(
+                    held_conn.clone(),
+                    &held_config,
+                    &held_client,
+                    &held_breaker,
+                )
+                .await;
+                if let Err(err) = sent {
+                    error!("Unable to resubmit held reports: {:?}", err);
+                }
+            }
+        });
+    }
+
+    let irwin_config = config.irwin.clone();
+    let state = fishnet.state.clone();
+    // NOTE: subscribed here, before the server starts, so no completion sent while the
+    //       listener task is still starting up can be missed.
+    let rx = fishnet.tx.subscribe();
     let fishnet_listener = tokio::spawn(async move {
         info!("Starting Irwin Actor...");
-        irwin::api::fishnet_listener(conn.clone(), fishnet.tx.clone()).await;
+        irwin::api::fishnet_listener(conn.clone(), irwin_config, irwin_client, breaker, rx)
+            .await;
     });
 
     info!("Starting server...");
-    let address: SocketAddr =
-        format!("{host}:{port}", host = args.host, port = args.port).parse()?;
-    warp::serve(warp::path("fishnet").and(app))
-        .run(address)
-        .await;
+    let address: SocketAddr = format!(
+        "{host}:{port}",
+        host = config.webserver.host,
+        port = config.webserver.port
+    )
+    .parse()?;
+    let tools = tools::handlers::mount(config);
+    let grace = Duration::from_secs(config.webserver.shutdown_grace_secs);
+    let routes = warp::path("fishnet")
+        .and(app)
+        .or(warp::path("deepq").and(deepq))
+        .or(warp::path("tools").and(tools));
+    let shutdown = async move {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            error!("Unable to listen for the shutdown signal: {:?}", err);
+            futures::future::pending::<()>().await;
+        }
+        info!("Shutting down, accepting analysis for another {:?}...", grace);
+        state.start_draining();
+        sleep(grace).await;
+        info!("Stopping server...");
+    };
+    match tls {
+        Some((cert, key)) => {
+            info!("Serving HTTPS on {}", address);
+            let (_, server) = warp::serve(routes)
+                .tls()
+                .cert_path(cert)
+                .key_path(key)
+                .bind_with_graceful_shutdown(address, shutdown);
+            server.await;
+        }
+        None => {
+            info!("Serving HTTP on {}", address);
+            let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(address, shutdown);
+            server.await;
+        }
+    }
 
-    fishnet_listener.await?;
+    // NOTE: the listener holds its own sender, so it never sees the channel close.
+    fishnet_listener.abort();
 
     Ok(())
 }
@@ -109,30 +343,48 @@ async fn deepq_web(args: &DeepQWebserver) -> StdResult<(), Box<dyn std::error::E
 #[derive(Debug, StructOpt)]
 #[structopt(about = "Listens for irwin jobs from lila")]
 struct IrwinJobListener {
-    #[structopt(
-        short,
-        long,
-        env = "LILA_DEEPQ_IRWIN_STREAM_URL",
-        default_value = "https://lichess.org/api/stream/irwin"
-    )]
-    api_url: String,
+    #[structopt(short, long, env = "LILA_DEEPQ_IRWIN_STREAM_URL")]
+    api_url: Option<String>,
 
     #[structopt(short, long, env = "LILA_DEEPQ_IRWIN_LICHESS_API_KEY")]
-    lichess_api_key: String,
+    lichess_api_key: Option<String>,
 
     #[structopt(flatten)]
     database_opts: DatabaseOpts,
 }
 
+impl IrwinJobListener {
+    fn overlay(&self, config: &mut config::Config) {
+        self.database_opts.overlay(&mut config.database);
+        if let Some(api_url) = &self.api_url {
+            config.irwin.stream_url = api_url.clone();
+        }
+        config.irwin.lichess_api_key = self
+            .lichess_api_key
+            .clone()
+            .or_else(|| config.irwin.lichess_api_key.take());
+    }
+}
+
 async fn deepq_irwin_job_listener(
-    args: &IrwinJobListener,
+    config: &config::Config,
 ) -> StdResult<(), Box<dyn std::error::Error>> {
-    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let conn = db::connection(&config.database.connection_opts()?).await?;
+    let lichess_api_key = config
+        .irwin
+        .lichess_api_key
+        .as_ref()
+        .ok_or(error::Error::MissingConfig("irwin.lichess_api_key"))?;
+    let client = irwin::api::submit_client(&config.irwin)?;
+    let breaker = irwin::breaker::CircuitBreaker::new(
+        config.irwin.breaker_failures,
+        chrono::Duration::seconds(config.irwin.breaker_cooldown_secs),
+    );
 
     info!("Starting up...");
     loop {
         info!("Connecting...");
-        let mut stream = irwin::stream::listener(&args.api_url, &args.lichess_api_key).await?;
+        let mut stream = irwin::stream::listener(&config.irwin.stream_url, lichess_api_key).await?;
 
         info!("Reading stream...");
         while let Some(msg) = stream.next().await {
@@ -145,7 +397,43 @@ async fn deepq_irwin_job_listener(
                         request.user.id.0,
                         request.games.len()
                     );
-                    irwin::api::add_to_queue(conn.clone(), request).await?;
+                    let queued = match irwin::api::add_to_queue(
+                        conn.clone(),
+                        &config.precedence,
+                        &config.origin_analysis,
+                        config.irwin.reuse_analysis_within(),
+                        request,
+                    )
+                    .await
+                    {
+                        Ok(queued) => queued,
+                        Err(error::Error::UnsupportedVariant(variant)) => {
+                            warn!("Skipping report with only {} games", variant);
+                            continue;
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    info!(
+                        "queued report {}: {} games stored, {} unchanged, {} unsupported, {} jobs",
+                        queued.report_id,
+                        queued.games_inserted,
+                        queued.games_skipped,
+                        queued.games_unsupported,
+                        queued.jobs_created
+                    );
+                    if !queued.needs_analysis() {
+                        let checked = irwin::api::check_report(
+                            conn.clone(),
+                            &config.irwin,
+                            &client,
+                            &breaker,
+                            queued.report_id,
+                        )
+                        .await;
+                        if let Err(err) = checked {
+                            error!("Unable to submit a report with nothing to analyse: {:?}", err);
+                        }
+                    }
                 }
                 Err(e) => error!("Error parsing message from lichess:\n{:?}", e),
             }
@@ -174,11 +462,34 @@ struct FishnetNewUser {
     #[structopt(short, long)]
     system_analysis: bool,
 
+    /// Grants every capability.
+    #[structopt(short, long)]
+    admin: bool,
+
+    /// A capability to grant, e.g. manage_queue or purge_games. May be repeated.
+    #[structopt(long = "capability")]
+    capabilities: Vec<fishnet::model::Capability>,
+
+    /// Most incomplete jobs the key may hold at once; unlimited if not given.
+    #[structopt(long)]
+    max_concurrent: Option<i64>,
+
+    /// Most nodes the key may be handed per UTC day; unlimited if not given.
+    #[structopt(long)]
+    daily_node_budget: Option<i64>,
+
+    /// Let the key's workers acquire in parallel rather than one at a time.
+    #[structopt(long)]
+    parallel_acquire: bool,
+
     #[structopt(flatten)]
     database_opts: DatabaseOpts,
 }
 
-async fn fishnet_new_user(args: &FishnetNewUser) -> StdResult<(), Box<dyn std::error::Error>> {
+async fn fishnet_new_user(
+    args: &FishnetNewUser,
+    config: &config::Config,
+) -> StdResult<(), Box<dyn std::error::Error>> {
     let mut perms = Vec::new();
     if args.system_analysis {
         perms.push(fishnet::model::AnalysisType::SystemAnalysis);
@@ -189,33 +500,131 @@ async fn fishnet_new_user(args: &FishnetNewUser) -> StdResult<(), Box<dyn std::e
     if args.deep_analysis {
         perms.push(fishnet::model::AnalysisType::Deep);
     }
+    let capabilities = if args.admin {
+        fishnet::model::ADMIN_CAPABILITIES.to_vec()
+    } else {
+        args.capabilities.clone()
+    };
     let create_user = fishnet::api::CreateApiUser {
         user: Some(args.username.clone().into()),
         name: args.keyname.clone(),
         perms: perms,
+        capabilities,
+        max_concurrent: args.max_concurrent,
+        daily_node_budget: args.daily_node_budget,
+        parallel_acquire: args.parallel_acquire,
     };
 
-    let conn = db::connection(&args.database_opts.clone().into()).await?;
+    let conn = db::connection(&config.database.connection_opts()?).await?;
     let api_user = fishnet::api::create_api_user(conn, create_user).await?;
     info!(
-        "Created key {} for {{user: {:?}, name: {:?}}}",
-        api_user.key.0, api_user.user, api_user.name
+        "Created key {} for {{user: {:?}, name: {:?}, capabilities: {:?}}}",
+        api_user.key.0, api_user.user, api_user.name, api_user.capabilities
+    );
+    Ok(())
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Re-send completed reports that never reached irwin.")]
+struct ResubmitIrwin {
+    /// Only reports completed at or after this RFC 3339 timestamp are considered.
+    #[structopt(long)]
+    since: chrono::DateTime<chrono::Utc>,
+
+    #[structopt(long, env = "LILA_DEEPQ_IRWIN_SUBMIT_URL")]
+    submit_url: Option<String>,
+
+    #[structopt(long, env = "LILA_DEEPQ_IRWIN_SUBMIT_TOKEN")]
+    submit_token: Option<String>,
+
+    #[structopt(flatten)]
+    database_opts: DatabaseOpts,
+}
+
+impl ResubmitIrwin {
+    fn overlay(&self, config: &mut config::Config) {
+        self.database_opts.overlay(&mut config.database);
+        config.irwin.submit_url = self
+            .submit_url
+            .clone()
+            .or_else(|| config.irwin.submit_url.take());
+        config.irwin.submit_token = self
+            .submit_token
+            .clone()
+            .or_else(|| config.irwin.submit_token.take());
+    }
+}
+
+async fn resubmit_irwin(
+    args: &ResubmitIrwin,
+    config: &config::Config,
+) -> StdResult<(), Box<dyn std::error::Error>> {
+    if config.irwin.submit_url.is_none() {
+        return Err(error::Error::MissingConfig("irwin.submit_url").into());
+    }
+    let conn = db::connection(&config.database.connection_opts()?).await?;
+    let reports = deepq::api::find_unsent_reports(conn.clone(), args.since).await?;
+    info!(
+        "Found {} unsent reports since {}",
+        reports.len(),
+        args.since
+    );
+    let client = irwin::api::submit_client(&config.irwin)?;
+    let counts = irwin::api::resubmit_reports(conn, &config.irwin, &client, reports).await?;
+    info!(
+        "Resubmitted {} reports, skipped {}, failed {}",
+        counts.resubmitted, counts.skipped, counts.failed
     );
     Ok(())
 }
 
+/// Pretty output by default; `LILA_DEEPQ_LOG_FORMAT=json` writes one JSON object per
+/// line instead. Either way the level filter comes from `RUST_LOG`.
+fn init_logging() {
+    match env::var("LILA_DEEPQ_LOG_FORMAT").as_deref() {
+        Ok("json") => env_logger::Builder::from_default_env()
+            .format(|buf, record| {
+                let line = serde_json::json!({
+                    "timestamp": chrono::Utc::now().to_rfc3339(),
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            })
+            .init(),
+        _ => pretty_env_logger::init(),
+    }
+}
+
 #[tokio::main]
 async fn main() -> StdResult<(), Box<dyn std::error::Error>> {
-    pretty_env_logger::init();
-
-    debug!("Reading dotenv...");
+    // NOTE: dotenv comes first so the log settings can live in .env too.
     dotenv().ok();
+    init_logging();
+    debug!("Read dotenv");
 
+    // NOTE: arguments first, so --help and usage errors don't need a readable config.
     let command = Command::from_args();
+    let mut config = config::load()?;
     match command {
-        Command::DeepQWebserver(args) => deepq_web(&args).await?,
-        Command::IrwinJobListener(args) => deepq_irwin_job_listener(&args).await?,
-        Command::FishnetNewUser(args) => fishnet_new_user(&args).await?,
+        Command::DeepQWebserver(args) => {
+            args.overlay(&mut config)?;
+            config.validate()?;
+            deepq_web(&config).await?
+        }
+        Command::IrwinJobListener(args) => {
+            args.overlay(&mut config);
+            deepq_irwin_job_listener(&config).await?
+        }
+        Command::FishnetNewUser(args) => {
+            args.database_opts.overlay(&mut config.database);
+            fishnet_new_user(&args, &config).await?
+        }
+        Command::ResubmitIrwin(args) => {
+            args.overlay(&mut config);
+            resubmit_irwin(&args, &config).await?
+        }
     }
 
     Ok(())
@@ -0,0 +1,98 @@
+// Copyright 2021 Lakin Wecker
+//
+// This file is part of lila-deepq.
+//
+// lila-deepq is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// lila-deepq is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with lila-deepq.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::result::Result as StdResult;
+use std::str::FromStr;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use shakmaty::{san::San, uci::Uci};
+use warp::{
+    filters::{method, BoxedFilter},
+    http, path,
+    reply::{self, Reply},
+    Filter, Rejection,
+};
+
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::fishnet::handlers::Variant;
+use crate::http::{json_body, recover};
+use crate::irwin::api::uci_from_san;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PgnToUci {
+    pgn: Vec<String>,
+    // NOTE: only standard is supported for now, so this is only validated.
+    #[serde(default)]
+    variant: Option<Variant>,
+}
+
+#[serde_as]
+#[derive(Serialize, Debug)]
+struct UciMoves {
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    uci: Vec<Uci>,
+}
+
+#[derive(Serialize, Debug)]
+struct IllegalMove {
+    code: u16,
+    message: String,
+    ply: usize,
+    san: String,
+}
+
+fn parse_pgn(pgn: &[String]) -> Result<Vec<San>> {
+    pgn.iter()
+        .enumerate()
+        .map(|(ply, san)| {
+            San::from_str(san).map_err(|_| Error::IllegalMove {
+                ply,
+                san: san.clone(),
+            })
+        })
+        .collect()
+}
+
+async fn pgn_to_uci(request: PgnToUci) -> StdResult<reply::Response, Rejection> {
+    info!("pgn_to_uci > {} plies", request.pgn.len());
+    match parse_pgn(&request.pgn).and_then(|pgn| uci_from_san(&pgn)) {
+        Ok(uci) => Ok(reply::json(&UciMoves { uci }).into_response()),
+        Err(Error::IllegalMove { ply, san }) => {
+            let code = http::StatusCode::BAD_REQUEST;
+            let body = IllegalMove {
+                code: code.as_u16(),
+                message: "illegal_move".into(),
+                ply,
+                san,
+            };
+            Ok(reply::with_status(reply::json(&body), code).into_response())
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub fn mount(config: &Config) -> BoxedFilter<(impl Reply,)> {
+    let pgn_to_uci = path("pgn-to-uci")
+        .and(method::post())
+        .and(json_body(config.webserver.max_body_bytes))
+        .and_then(pgn_to_uci);
+
+    pgn_to_uci.recover(recover).boxed()
+}